@@ -2,9 +2,10 @@ use std::{env, process};
 use actix_web::cookie::Key;
 use dotenv::dotenv;
 
-pub fn get_api_key() -> String {
+// Legacy shared API key, prefer named keys in `API_KEYS_FILE`
+pub fn get_api_key() -> Option<String> {
     dotenv().ok();  // Load environment variables from .env file
-    env::var("API_KEY").expect("API_KEY must be set")
+    env::var("API_KEY").ok().filter(|key| !key.is_empty())
 }
 
 pub fn get_officer_secret_key() -> Key {
@@ -25,4 +26,199 @@ pub fn get_envar(var_name: &str) -> String {
             process::exit(1)
         }
     }
-}
\ No newline at end of file
+}
+pub fn get_rbac_policy_file() -> Option<String> {
+    dotenv().ok();
+    env::var("RBAC_POLICY_FILE").ok().filter(|path| !path.is_empty())
+}
+
+// Explicit opt-out of RBAC, every authenticated caller may then perform every action
+pub fn get_rbac_disabled() -> bool {
+    get_optional_envar("RBAC_DISABLED").is_some_and(|v| v == "true")
+}
+
+// Lifetime of access tokens in seconds
+pub fn get_access_token_ttl() -> i64 {
+    dotenv().ok();
+    env::var("JWT_ACCESS_TOKEN_TTL").ok().and_then(|v| v.parse().ok()).unwrap_or(3600)
+}
+
+// Lifetime of refresh tokens in seconds
+pub fn get_refresh_token_ttl() -> i64 {
+    dotenv().ok();
+    env::var("JWT_REFRESH_TOKEN_TTL").ok().and_then(|v| v.parse().ok()).unwrap_or(604800)
+}
+
+// Lifetime of a login session in seconds, refreshing can not extend it so group membership is read again at the next login
+pub fn get_session_ttl() -> i64 {
+    dotenv().ok();
+    env::var("JWT_SESSION_TTL").ok().and_then(|v| v.parse().ok()).unwrap_or(86400)
+}
+
+// Optional file where revoked token and session ids are persisted across restarts
+pub fn get_revocation_file() -> Option<String> {
+    dotenv().ok();
+    env::var("JWT_REVOCATION_FILE").ok().filter(|path| !path.is_empty())
+}
+
+pub fn get_api_keys_file() -> Option<String> {
+    dotenv().ok();
+    env::var("API_KEYS_FILE").ok().filter(|path| !path.is_empty())
+}
+
+// Legacy allow list of user emails, role bindings in the RBAC policy are preferred
+pub fn get_users() -> Vec<String> {
+    dotenv().ok();
+    env::var("USERS")
+        .map(|users| users.split(',').map(|u| u.trim().to_string()).filter(|u| !u.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+// Also record project access levels at login, in addition to groups
+pub fn get_gitlab_project_access() -> bool {
+    dotenv().ok();
+    env::var("OAUTH2_GITLAB_PROJECT_ACCESS").map(|v| v == "true").unwrap_or(false)
+}
+
+pub fn get_optional_envar(var_name: &str) -> Option<String> {
+    dotenv().ok();
+    env::var(var_name).ok().filter(|value| !value.is_empty())
+}
+
+// Names of the OpenID Connect providers to enable, e.g. "keycloak,dex"
+pub fn get_oidc_providers() -> Vec<String> {
+    get_optional_envar("OIDC_PROVIDERS")
+        .map(|names| names.split(',').map(|n| n.trim().to_lowercase()).filter(|n| !n.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+pub fn get_trusted_issuers_file() -> Option<String> {
+    get_optional_envar("TRUSTED_ISSUERS_FILE")
+}
+
+// Authenticate unknown bearer tokens with a Kubernetes TokenReview
+pub fn get_token_review_enabled() -> bool {
+    get_optional_envar("TOKEN_REVIEW_ENABLED").is_some_and(|v| v == "true")
+}
+
+// Audiences the service account tokens must be issued for, e.g. "officer", required with `TOKEN_REVIEW_ENABLED`
+pub fn get_token_review_audiences() -> Vec<String> {
+    get_optional_envar("TOKEN_REVIEW_AUDIENCES")
+        .map(|audiences| audiences.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+// Comma separated audit sinks: file, stdout, webhook (default stdout)
+pub fn get_audit_sinks() -> Vec<String> {
+    get_optional_envar("AUDIT_SINKS")
+        .unwrap_or_else(|| "stdout".to_string())
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// JSON-lines file of the `file` audit sink
+pub fn get_audit_file() -> Option<String> {
+    get_optional_envar("AUDIT_FILE")
+}
+
+// URL receiving every audit record as a JSON POST with the `webhook` sink
+pub fn get_audit_webhook_url() -> Option<String> {
+    get_optional_envar("AUDIT_WEBHOOK_URL")
+}
+
+// Default time in seconds to wait for a rollout when the caller does not give one
+pub fn get_rollout_timeout() -> u64 {
+    dotenv().ok();
+    env::var("ROLLOUT_TIMEOUT_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(300)
+}
+
+// Restarts of a container of the new revision after which a waited rollout is considered failed
+pub fn get_rollout_max_restarts() -> i32 {
+    dotenv().ok();
+    env::var("ROLLOUT_MAX_RESTARTS").ok().and_then(|v| v.parse().ok()).unwrap_or(3)
+}
+
+// Whether deploys check the image exists in its registry unless the caller says otherwise
+pub fn get_registry_verify() -> bool {
+    get_optional_envar("REGISTRY_VERIFY").is_some_and(|v| v == "true")
+}
+
+// Comma separated registries (host[:port]) reached over plain HTTP, e.g. a local registry
+pub fn get_insecure_registries() -> Vec<String> {
+    get_optional_envar("REGISTRY_INSECURE_HOSTS")
+        .map(|hosts| hosts.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+// Comma separated replica bounds for /scale, `namespace=min:max` with `*` for the other namespaces,
+// e.g. "production=2:20,*=0:10". An empty max means no upper bound, invalid entries stop the startup.
+pub fn get_scale_bounds() -> Vec<String> {
+    get_optional_envar("SCALE_BOUNDS")
+        .map(|bounds| bounds.split(',').map(|b| b.trim().to_string()).filter(|b| !b.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+// Default time in seconds a ready canary is watched before it is promoted
+pub fn get_canary_bake_seconds() -> u64 {
+    get_optional_envar("CANARY_BAKE_SECONDS").and_then(|v| v.parse().ok()).unwrap_or(300)
+}
+
+// YAML file of the Falco response playbooks, reloaded when it changes
+pub fn get_playbooks_file() -> Option<String> {
+    get_optional_envar("PLAYBOOKS_FILE")
+}
+
+// Whether Officer creates and reconciles the quarantine NetworkPolicy before isolating a pod (default true)
+pub fn get_quarantine_policy_managed() -> bool {
+    get_optional_envar("QUARANTINE_POLICY_MANAGED").is_none_or(|v| v != "false")
+}
+
+// Let isolated pods resolve names with the cluster DNS
+pub fn get_quarantine_allow_dns() -> bool {
+    get_optional_envar("QUARANTINE_ALLOW_DNS").is_some_and(|v| v == "true")
+}
+
+// Comma separated egress allowed from isolated pods, `cidr[:port]`, e.g. a forensics collector "10.0.5.10/32:443"
+pub fn get_quarantine_egress_allow() -> Vec<String> {
+    get_optional_envar("QUARANTINE_EGRESS_ALLOW")
+        .map(|entries| entries.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+// Capture a forensic bundle of every pod a playbook responds to, unless the request says otherwise
+pub fn get_forensics_capture() -> bool {
+    get_optional_envar("FORENSICS_CAPTURE").is_some_and(|v| v == "true")
+}
+
+// Directory, usually a mounted volume, forensic bundles are written to
+pub fn get_forensics_dir() -> Option<String> {
+    get_optional_envar("FORENSICS_DIR")
+}
+
+// S3-compatible endpoint forensic bundles are uploaded to instead, e.g. "https://s3.eu-west-1.amazonaws.com" or a MinIO URL
+pub fn get_forensics_s3_endpoint() -> Option<String> {
+    get_optional_envar("FORENSICS_S3_ENDPOINT")
+}
+
+pub fn get_forensics_s3_bucket() -> Option<String> {
+    get_optional_envar("FORENSICS_S3_BUCKET")
+}
+
+pub fn get_forensics_s3_region() -> String {
+    get_optional_envar("FORENSICS_S3_REGION").unwrap_or_else(|| "us-east-1".to_string())
+}
+
+pub fn get_forensics_s3_access_key() -> Option<String> {
+    get_optional_envar("FORENSICS_S3_ACCESS_KEY")
+}
+
+pub fn get_forensics_s3_secret_key() -> Option<String> {
+    get_optional_envar("FORENSICS_S3_SECRET_KEY")
+}
+
+// Lines of each container log kept in a forensic bundle
+pub fn get_forensics_log_lines() -> i64 {
+    get_optional_envar("FORENSICS_LOG_LINES").and_then(|v| v.parse().ok()).unwrap_or(10000)
+}
@@ -0,0 +1,35 @@
+use actix_web::{error::{ErrorBadRequest, ErrorInternalServerError}, Error};
+use chrono::{DateTime, Utc};
+use paperclip::actix::{api_v2_operation, web::{Json, Query}};
+use crate::{
+    model::{audit::{AuditQuery, AuditRecord}, auth::{ApiKeyHeader, AuthJwtHeader}},
+    util::audit::{query, AuditFilter}
+};
+
+fn parse_time(value: &Option<String>) -> Result<Option<DateTime<Utc>>, Error> {
+    match value {
+        Some(value) => DateTime::parse_from_rfc3339(value)
+            .map(|t| Some(t.with_timezone(&Utc)))
+            .map_err(|e| ErrorBadRequest(format!("Invalid time {}: {}", value, e))),
+        None => Ok(None),
+    }
+}
+
+#[api_v2_operation(tags("Audit"))]
+/// Query audit log
+///
+/// List the recorded mutating actions, filtered by user, namespace and time range
+pub async fn get_audit(_: ApiKeyHeader,  _: AuthJwtHeader, query_params: Query<AuditQuery>) -> Result<Json<Vec<AuditRecord>>, Error> {
+    let filter = AuditFilter {
+        actor: query_params.actor.clone(),
+        namespace: query_params.namespace.clone(),
+        target: query_params.target.clone(),
+        since: parse_time(&query_params.since)?,
+        until: parse_time(&query_params.until)?,
+        limit: query_params.limit.unwrap_or(100),
+    };
+    match query(filter).await {
+        Ok(records) => Ok(Json(records)),
+        Err(e) => Err(ErrorInternalServerError(e)),
+    }
+}
@@ -0,0 +1,145 @@
+use actix_session::Session;
+use actix_web::{error::ErrorBadRequest, web, Error, HttpResponse, Responder};
+use log::{error, info};
+use paperclip::actix::{api_v2_operation, web::{Json, ReqData}};
+use serde::Deserialize;
+use serde_json::json;
+use crate::{
+    config::get_users,
+    model::{
+        auth::{ApiKeyHeader, AuthJwtHeader, AuthMethod, Principal, RefreshTokenPayload, RequestContext, RevokeTokenPayload, TokenResponse},
+        error::AuthError,
+        kubernetes::SuccessResponse,
+        rbac::Action,
+    },
+    provider::{get_provider, LoginState},
+    util::{jwt::{create_token, refresh_token, revoke_session, validate_any_token, validate_token}, rbac::{authorize, has_any_role}}
+};
+
+#[allow(unused)]
+#[derive(Deserialize)]
+pub struct OAuthQuery {
+    pub code: String,
+    pub state: String,
+}
+
+// Session key of the pending login
+const LOGIN_STATE: &str = "login_state";
+
+/// Redirect to the login page of an identity provider, e.g. /auth/gitlab/login
+pub async fn login(session: Session, provider: web::Path<String>) -> impl Responder {
+    login_with(session, &provider).await
+}
+
+/// Callback of an identity provider, exchanges the code for an Officer token pair
+pub async fn callback(session: Session, provider: web::Path<String>, query: web::Query<OAuthQuery>) -> impl Responder {
+    callback_with(session, &provider, query.into_inner()).await
+}
+
+pub async fn login_with(session: Session, name: &str) -> HttpResponse {
+    let provider = match get_provider(name) {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().json(json!({"error": format!("Unknown identity provider {}", name)})),
+    };
+    match provider.authorize_url().await {
+        Ok((auth_url, state)) => {
+            // Store CSRF token, PKCE verifier and nonce in session
+            session.insert(LOGIN_STATE, state).unwrap();
+            // Redirect user to the provider's authorization URL
+            HttpResponse::Found().append_header(("LOCATION", auth_url.to_string())).finish()
+        }
+        Err(e) => {
+            error!("Login with {} failed: {}", name, e);
+            HttpResponse::InternalServerError().json(json!({"error": "Identity provider unavailable"}))
+        }
+    }
+}
+
+pub async fn callback_with(session: Session, name: &str, query: OAuthQuery) -> HttpResponse {
+    let state = match session.get::<LoginState>(LOGIN_STATE) {
+        Ok(Some(state)) => state,
+        _ => return HttpResponse::BadRequest().body("Invalid CSRF token"),
+    };
+    if state.provider != name || query.state != state.csrf_token {
+        return HttpResponse::BadRequest().body("Invalid CSRF token");
+    }
+    session.remove(LOGIN_STATE);
+    let provider = match get_provider(name) {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().json(json!({"error": format!("Unknown identity provider {}", name)})),
+    };
+
+    let user = match provider.authenticate(query.code, &state).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("Login with {} failed: {}", name, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Failed to get access token"}))
+        }
+    };
+    let principal = Principal {
+        subject: user.subject,
+        membership: user.membership,
+        auth_method: AuthMethod::Jwt,
+        scope: None,
+    };
+    // Users listed in USERS are still admitted, everyone else needs a role binding
+    if get_users().contains(&principal.subject) || has_any_role(&principal) {
+        match create_token(&principal.subject, &principal.membership) {
+            Ok(tokens) => HttpResponse::Ok().json(tokens),
+            Err(_) => HttpResponse::InternalServerError().json(json!({"error": "Invalid credential!"}))
+        }
+    } else {
+        info!("Login refused for {}, no role granted", principal.subject);
+        HttpResponse::Forbidden().json(json!({"error": "Invalid credential!"}))
+    }
+}
+
+#[api_v2_operation(tags("Auth"))]
+/// Refresh token
+///
+/// Exchange a refresh token for a new access token and refresh token, a refresh token can only be used once
+///
+/// Refreshing does not extend the login session, after `JWT_SESSION_TTL` (default one day) the user logs in again
+pub async fn refresh(payload: Json<RefreshTokenPayload>) -> Result<Json<TokenResponse>, Error> {
+    match refresh_token(&payload.refresh_token) {
+        Ok(tokens) => Ok(Json(tokens)),
+        Err(e) => Err(AuthError::from(e).into()),
+    }
+}
+
+#[api_v2_operation(tags("Auth"))]
+/// Revoke token
+///
+/// Revoke every token issued for the login session of the given token.
+/// Revoking a token of another user requires the `revoke-token` action
+pub async fn revoke(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<RevokeTokenPayload>) -> Result<Json<SuccessResponse>, Error> {
+    let claims = match validate_any_token(&payload.token) {
+        Ok(token) => token.claims,
+        Err(_) => return Err(ErrorBadRequest("Invalid token")),
+    };
+    let principal = &ctx.principal;
+    if claims.sub != principal.subject {
+        authorize(principal, Action::RevokeToken, None).map_err(AuthError::InsufficientPermission)?;
+    }
+    revoke_session(&claims);
+    info!("Session of {} revoked by {}", claims.sub, principal.subject);
+    Ok(Json(SuccessResponse { status: "Token revoked".to_string() }))
+}
+
+#[api_v2_operation(tags("Auth"))]
+/// Logout
+///
+/// Revoke the access token used for this request and its refresh token
+pub async fn logout(_: ApiKeyHeader,  auth_jwt_header: AuthJwtHeader) -> Result<Json<SuccessResponse>, Error> {
+    let token = match auth_jwt_header.0.strip_prefix("Bearer ") {
+        Some(token) => token,
+        None => return Err(ErrorBadRequest("Only JWT sessions can be logged out")),
+    };
+    match validate_token(token) {
+        Ok(token) => {
+            revoke_session(&token.claims);
+            Ok(Json(SuccessResponse { status: "Logged out".to_string() }))
+        },
+        Err(e) => Err(AuthError::from(e).into()),
+    }
+}
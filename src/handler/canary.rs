@@ -0,0 +1,371 @@
+use std::time::Duration;
+use actix_web::{error::{ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorNotFound}, http::StatusCode, Error};
+use k8s_openapi::api::apps::v1::Deployment;
+use kube::{api::{DeleteParams, ListParams, PostParams}, runtime::events::EventType, Api, Client, Resource};
+use log::{error, info, warn};
+use paperclip::actix::{api_v2_operation, web::{Json, Query, ReqData}};
+use serde_json::json;
+use uuid::Uuid;
+use crate::{
+    config::{get_canary_bake_seconds, get_registry_verify, get_rollout_max_restarts, get_rollout_timeout},
+    handler::kubernetes::{change_annotations, error_with_body, find_container, plan_image_change, set_images, verify_image, ImageChange, ImageTarget},
+    model::{
+        auth::{ApiKeyHeader, AuthJwtHeader, AuthMethod, Membership, Principal, RequestContext},
+        kubernetes::{
+            CanaryActionPayload, CanaryOptions, CanaryQuery, CanaryStatus, DeployServicePayload, DeployServiceResponse, SuccessResponse,
+            WorkloadKind
+        },
+        rbac::Action},
+    util::{audit::AuditEntry, canary::{
+        bake, build_canary, canary_image, canary_name, canary_phase, canary_status, list_canaries, mark_ready, BakeOutcome,
+        CanarySpec, ABORTED, BAKING, CANARY_OF_LABEL, MAX_BAKE_SECONDS, PROMOTED, READY, STARTING
+    }, events::{publish_event, publish_event_on}, workload::WorkloadApi}
+};
+
+// Canary replicas from the options, at least one
+fn canary_replicas(options: &CanaryOptions, primary_replicas: i32) -> Result<i32, String> {
+    match (options.replicas, options.percentage) {
+        (Some(_), Some(_)) => Err("Give either canary replicas or a percentage".to_string()),
+        (Some(replicas), None) if replicas < 1 => Err("Canary replicas must be at least 1".to_string()),
+        (Some(replicas), None) => Ok(replicas),
+        (None, Some(percentage)) if percentage == 0 || percentage > 100 => {
+            Err("Canary percentage must be between 1 and 100".to_string())
+        }
+        (None, Some(percentage)) => Ok(((primary_replicas * percentage as i32 + 99) / 100).max(1)),
+        (None, None) => Ok(1),
+    }
+}
+
+// Start a staged deploy: create the canary Deployment, then watch it in the background,
+// or with `wait` until it is promoted or aborted
+pub async fn start_canary(
+    ctx: &RequestContext,
+    payload: &DeployServicePayload,
+    options: &CanaryOptions,
+    audit: &mut AuditEntry,
+) -> Result<Json<DeployServiceResponse>, Error> {
+    let namespace = &payload.namespace;
+    let name = &payload.service_deployment;
+    if payload.kind != WorkloadKind::Deployment {
+        return Err(ErrorBadRequest(format!("Canary deploys are only supported for Deployments, not {}", payload.kind)));
+    }
+    if payload.dry_run {
+        return Err(ErrorBadRequest("dry_run is not supported for canary deploys"));
+    }
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    let workload = WorkloadApi::new(client.clone(), namespace, WorkloadKind::Deployment);
+    let current = workload.api.get(name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get Deployment failed: {}", e)))?;
+    let target = ImageTarget {
+        repository: payload.repository.as_deref(),
+        tag: payload.version.as_deref(),
+        digest: payload.digest.as_deref(),
+    };
+    let change = plan_image_change(&current, &payload.container_name, &target).map_err(ErrorBadRequest)?;
+    let resolved_digest = if payload.verify_image.unwrap_or_else(get_registry_verify) {
+        Some(verify_image(client.clone(), namespace, &current, &change.image).await?)
+    } else {
+        None
+    };
+    let primary: Deployment = current.try_parse()
+        .map_err(|e| ErrorInternalServerError(format!("Deployment {} is invalid: {}", name, e)))?;
+    let primary_replicas = primary.spec.as_ref().and_then(|spec| spec.replicas).unwrap_or(1);
+    let replicas = canary_replicas(options, primary_replicas).map_err(ErrorBadRequest)?;
+    let bake_seconds = options.bake_seconds.unwrap_or_else(get_canary_bake_seconds);
+    if bake_seconds > MAX_BAKE_SECONDS {
+        return Err(ErrorBadRequest(format!("Canary bake time can not exceed {} seconds", MAX_BAKE_SECONDS)));
+    }
+    let spec = CanarySpec {
+        container: change.container.clone(),
+        image: change.image.clone(),
+        previous_image: change.previous.clone(),
+        replicas,
+        bake_seconds,
+        auto_promote: options.auto_promote.unwrap_or(true),
+        triggered_by: audit.actor().to_string(),
+    };
+    let canary = build_canary(&primary, &spec).map_err(ErrorBadRequest)?;
+    audit.before(json!({ "container": change.container, "image": change.previous }));
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    match deployments.create(&PostParams::default(), &canary).await {
+        Ok(_) => {},
+        Err(kube::Error::Api(e)) if e.code == 409 => {
+            return Err(ErrorConflict(format!("A canary of {} is already in flight", name)));
+        },
+        Err(e) => return Err(ErrorInternalServerError(format!("Could not create canary: {}", e))),
+    }
+    audit.after(json!({ "canary": canary_name(name), "container": change.container, "image": change.image, "replicas": replicas }));
+    let note = format!("Canary started with {}={} on {} replicas by {}", change.container, change.image, replicas, audit.actor());
+    publish_event(client.clone(), &primary, EventType::Normal, "CanaryStarted", "Deploy", note).await;
+
+    let timeout = Duration::from_secs(payload.timeout_seconds.unwrap_or_else(get_rollout_timeout));
+    let max_restarts = payload.max_restarts.unwrap_or_else(get_rollout_max_restarts);
+    if !payload.wait {
+        let (watch_client, ctx, watched_namespace, watched_name) = (client.clone(), ctx.clone(), namespace.clone(), name.clone());
+        actix_web::rt::spawn(async move {
+            if let Err(e) = drive_canary(watch_client, &ctx, &watched_namespace, &watched_name, timeout, max_restarts).await {
+                error!("Canary of {}/{} could not be completed: {}", watched_namespace, watched_name, e);
+            }
+        });
+        let canary = canary_status(client, namespace, &canary).await.ok();
+        let status = format!("Canary {} started", canary_name(name));
+        return Ok(Json(DeployServiceResponse { status, rollout: None, rolled_back_to: None, resolved_digest, changes: None, canary }));
+    }
+
+    let outcome = drive_canary(client, ctx, namespace, name, timeout, max_restarts).await
+        .map_err(ErrorInternalServerError)?;
+    let mut response = DeployServiceResponse {
+        status: String::new(),
+        rollout: None,
+        rolled_back_to: None,
+        resolved_digest,
+        changes: None,
+        canary: outcome.clone(),
+    };
+    match outcome {
+        Some(canary) if canary.phase == ABORTED => {
+            response.status = format!("Canary aborted: {}", canary.message);
+            Err(error_with_body(StatusCode::INTERNAL_SERVER_ERROR, response.status.clone(), &response))
+        },
+        Some(canary) => {
+            response.status = format!("Canary {}: {}", canary.phase, canary.message);
+            Ok(Json(response))
+        },
+        None => {
+            response.status = format!("Canary of {} was promoted or aborted by another request", name);
+            Ok(Json(response))
+        },
+    }
+}
+
+// Watch the canary until its bake time passed, then promote it (or leave it ready for a manual promotion),
+// abort it when it fails. Returns the final state, none when the canary was promoted or aborted meanwhile.
+// A canary that can not be watched is aborted, nobody would otherwise stop it from taking live traffic.
+async fn drive_canary(
+    client: Client,
+    ctx: &RequestContext,
+    namespace: &str,
+    name: &str,
+    timeout: Duration,
+    max_restarts: i32,
+) -> Result<Option<CanaryStatus>, String> {
+    let e = match watch_canary(client.clone(), ctx, namespace, name, timeout, max_restarts).await {
+        Ok(outcome) => return Ok(outcome),
+        Err(e) => e,
+    };
+    let reason = format!("Canary could not be watched: {}", e);
+    let request = json!({ "canary": canary_name(name), "promote": false, "reason": reason });
+    let mut audit = AuditEntry::new(ctx, Action::DeployService, namespace, format!("Deployment/{}", name), request);
+    let result = abort(client, namespace, name, &reason, &mut audit).await;
+    audit.finish(&result).await;
+    match result {
+        Ok(_) => Err(reason),
+        Err(abort_error) => Err(format!("{}, and it could not be aborted: {}", reason, abort_error)),
+    }
+}
+
+async fn watch_canary(
+    client: Client,
+    ctx: &RequestContext,
+    namespace: &str,
+    name: &str,
+    timeout: Duration,
+    max_restarts: i32,
+) -> Result<Option<CanaryStatus>, String> {
+    let (mut status, promoted) = match bake(client.clone(), namespace, name, timeout, max_restarts).await? {
+        BakeOutcome::Passed(status) => (status, true),
+        BakeOutcome::Failed(status) => (status, false),
+        BakeOutcome::Gone => return Ok(None),
+    };
+    if promoted && !status.auto_promote {
+        mark_ready(client, namespace, name).await?;
+        status.phase = READY.to_string();
+        status.message = "Bake time passed, waiting for /canary/promote".to_string();
+        return Ok(Some(status));
+    }
+    let request = json!({ "canary": canary_name(name), "promote": promoted, "reason": status.message });
+    let mut audit = AuditEntry::new(ctx, Action::DeployService, namespace, format!("Deployment/{}", name), request);
+    let result = if promoted {
+        promote(client, namespace, name, &status.message, &mut audit).await
+    } else {
+        abort(client, namespace, name, &status.message, &mut audit).await
+    };
+    audit.finish(&result).await;
+    result.map_err(|e| e.to_string())?;
+    status.phase = if promoted { PROMOTED } else { ABORTED }.to_string();
+    Ok(Some(status))
+}
+
+// Watch again the canaries left starting or baking by a previous Officer process, must be called once at startup
+pub async fn resume_canaries() {
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Canaries in flight are not resumed, Kubernetes connection failed: {}", e);
+            return;
+        },
+    };
+    let deployments: Api<Deployment> = Api::all(client.clone());
+    let canaries = match deployments.list(&ListParams::default().labels(CANARY_OF_LABEL)).await {
+        Ok(canaries) => canaries,
+        Err(e) => {
+            error!("Canaries in flight are not resumed, they could not be listed: {}", e);
+            return;
+        },
+    };
+    let ctx = RequestContext {
+        principal: Principal {
+            subject: "officer".to_string(),
+            membership: Membership::default(),
+            auth_method: AuthMethod::System,
+            scope: None,
+        },
+        correlation_id: Uuid::new_v4().to_string(),
+        source_ip: None,
+    };
+    let timeout = Duration::from_secs(get_rollout_timeout());
+    let max_restarts = get_rollout_max_restarts();
+    for canary in canaries {
+        let phase = canary_phase(&canary);
+        if phase != STARTING && phase != BAKING {
+            continue;
+        }
+        let namespace = canary.metadata.namespace.clone().unwrap_or_default();
+        let name = canary.metadata.labels.as_ref().and_then(|labels| labels.get(CANARY_OF_LABEL).cloned()).unwrap_or_default();
+        info!("Resuming canary of {}/{} in phase {}", namespace, name, phase);
+        let (client, ctx) = (client.clone(), ctx.clone());
+        actix_web::rt::spawn(async move {
+            if let Err(e) = drive_canary(client, &ctx, &namespace, &name, timeout, max_restarts).await {
+                error!("Canary of {}/{} could not be completed: {}", namespace, name, e);
+            }
+        });
+    }
+}
+
+async fn get_canary(deployments: &Api<Deployment>, name: &str) -> Result<Deployment, Error> {
+    deployments.get_opt(&canary_name(name)).await
+        .map_err(|e| ErrorInternalServerError(format!("Get canary failed: {}", e)))?
+        .ok_or_else(|| ErrorNotFound(format!("Deployment {} has no canary in flight", name)))
+}
+
+// Deploy the image of the canary to the deployment and remove the canary
+async fn promote(client: Client, namespace: &str, name: &str, reason: &str, audit: &mut AuditEntry) -> Result<Json<SuccessResponse>, Error> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let canary = get_canary(&deployments, name).await?;
+    let (container, image) = canary_image(&canary);
+    let workload = WorkloadApi::new(client.clone(), namespace, WorkloadKind::Deployment);
+    let current = workload.api.get(name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get Deployment failed: {}", e)))?;
+    let (previous, init) = find_container(&current, &container).map_err(ErrorBadRequest)?;
+    audit.before(json!({ "container": container, "image": previous }));
+    let change = ImageChange { container: container.clone(), init, previous, image: image.clone() };
+    let cause = format!("canary promoted by {}: {}={}", audit.actor(), container, image);
+    let annotations = change_annotations(audit, cause);
+    let patched = set_images(&workload, &current, std::slice::from_ref(&change), false, annotations, false).await
+        .map_err(|e| ErrorInternalServerError(format!("Could not patch Deployment: {}", e)))?;
+    audit.after(json!({ "container": container, "image": image }));
+    deployments.delete(&canary_name(name), &DeleteParams::default()).await
+        .map_err(|e| ErrorInternalServerError(format!("Deployment promoted, but its canary could not be deleted: {}", e)))?;
+    let note = format!("Canary promoted by {}, container {} changed from {} to {}: {}", audit.actor(), container, change.previous, image, reason);
+    publish_event_on(client, patched.object_ref(&workload.resource), EventType::Normal, "CanaryPromoted", "Promote", note).await;
+    Ok(Json(SuccessResponse { status: format!("Canary of {} promoted, {} deployed", name, image) }))
+}
+
+// Remove the canary, the deployment keeps running its image
+async fn abort(client: Client, namespace: &str, name: &str, reason: &str, audit: &mut AuditEntry) -> Result<Json<SuccessResponse>, Error> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let canary = get_canary(&deployments, name).await?;
+    let (container, image) = canary_image(&canary);
+    audit.before(json!({ "canary": canary_name(name), "container": container, "image": image }));
+    deployments.delete(&canary_name(name), &DeleteParams::default()).await
+        .map_err(|e| ErrorInternalServerError(format!("Could not delete canary: {}", e)))?;
+    audit.after(json!({ "canary": null }));
+    if let Ok(primary) = deployments.get(name).await {
+        let note = format!("Canary with {}={} aborted by {}: {}", container, image, audit.actor(), reason);
+        publish_event(client, &primary, EventType::Warning, "CanaryAborted", "Abort", note).await;
+    }
+    Ok(Json(SuccessResponse { status: format!("Canary of {} aborted: {}", name, reason) }))
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Canaries
+///
+/// List the canaries of staged deploys in flight in a namespace
+pub async fn get_canaries(_: ApiKeyHeader,  _: AuthJwtHeader, query: Query<CanaryQuery>) -> Result<Json<Vec<CanaryStatus>>, Error> {
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    list_canaries(client, &query.namespace).await
+        .map(Json)
+        .map_err(ErrorInternalServerError)
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Promote canary
+///
+/// Deploy the image of the canary to its deployment now and remove the canary
+pub async fn promote_canary(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<CanaryActionPayload>) -> Result<Json<SuccessResponse>, Error> {
+    let target = format!("Deployment/{}", payload.service_deployment);
+    let mut audit = AuditEntry::new(&ctx, Action::DeployService, &payload.namespace, target, json!(&*payload));
+    let reason = payload.reason.clone().unwrap_or_else(|| "promoted manually".to_string());
+    let result = match Client::try_default().await {
+        Ok(client) => promote(client, &payload.namespace, &payload.service_deployment, &reason, &mut audit).await,
+        Err(e) => Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    audit.finish(&result).await;
+    result
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Abort canary
+///
+/// Remove the canary, its deployment keeps running the previous image
+pub async fn abort_canary(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<CanaryActionPayload>) -> Result<Json<SuccessResponse>, Error> {
+    let target = format!("Deployment/{}", payload.service_deployment);
+    let mut audit = AuditEntry::new(&ctx, Action::DeployService, &payload.namespace, target, json!(&*payload));
+    let reason = payload.reason.clone().unwrap_or_else(|| "aborted manually".to_string());
+    let result = match Client::try_default().await {
+        Ok(client) => abort(client, &payload.namespace, &payload.service_deployment, &reason, &mut audit).await,
+        Err(e) => Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    audit.finish(&result).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(replicas: Option<i32>, percentage: Option<u32>) -> CanaryOptions {
+        CanaryOptions { replicas, percentage, bake_seconds: None, auto_promote: None }
+    }
+
+    #[test]
+    fn one_canary_replica_by_default() {
+        assert_eq!(canary_replicas(&options(None, None), 10), Ok(1));
+        assert_eq!(canary_replicas(&options(Some(3), None), 10), Ok(3));
+    }
+
+    #[test]
+    fn percentage_is_rounded_up() {
+        assert_eq!(canary_replicas(&options(None, Some(25)), 10), Ok(3));
+        assert_eq!(canary_replicas(&options(None, Some(20)), 10), Ok(2));
+        assert_eq!(canary_replicas(&options(None, Some(1)), 3), Ok(1));
+        assert_eq!(canary_replicas(&options(None, Some(100)), 4), Ok(4));
+        // A deployment scaled to zero still gets one canary
+        assert_eq!(canary_replicas(&options(None, Some(50)), 0), Ok(1));
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        assert!(canary_replicas(&options(Some(1), Some(10)), 10).is_err());
+        assert!(canary_replicas(&options(Some(0), None), 10).is_err());
+        assert!(canary_replicas(&options(Some(-1), None), 10).is_err());
+        assert!(canary_replicas(&options(None, Some(0)), 10).is_err());
+        assert!(canary_replicas(&options(None, Some(101)), 10).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use actix_web::{error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound}, http::header::{ContentDisposition, DispositionParam, DispositionType}, Error, HttpResponse};
+use paperclip::actix::{api_v2_operation, web::{Json, Path, Query}};
+use crate::{
+    model::{auth::{ApiKeyHeader, AuthJwtHeader}, forensics::{ForensicBundle, ForensicsQuery}},
+    util::forensics::{list_bundles, read_bundle, valid_name},
+};
+
+#[api_v2_operation(tags("Kubernetes Security"))]
+/// Forensic bundles
+///
+/// List the forensic bundles captured when pods were isolated, newest first
+pub async fn get_forensics(_: ApiKeyHeader,  _: AuthJwtHeader, query: Query<ForensicsQuery>) -> Result<Json<Vec<ForensicBundle>>, Error> {
+    let namespace = query.namespace.as_deref();
+    if namespace.is_some_and(|namespace| !valid_name(namespace)) {
+        return Err(ErrorBadRequest("Invalid namespace"));
+    }
+    list_bundles(namespace).await
+        .map(Json)
+        .map_err(ErrorInternalServerError)
+}
+
+#[api_v2_operation(tags("Kubernetes Security"))]
+/// Download forensic bundle
+///
+/// Download a forensic bundle as a gzipped tar archive
+pub async fn download_forensics(_: ApiKeyHeader,  _: AuthJwtHeader, path: Path<(String, String)>) -> Result<HttpResponse, Error> {
+    let (namespace, id) = path.into_inner();
+    let content = read_bundle(&namespace, &id).await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound(format!("Forensic bundle {} does not exist in namespace {}", id, namespace)))?;
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!("{}-{}.tar.gz", namespace, id))],
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/gzip")
+        .insert_header(disposition)
+        .body(content))
+}
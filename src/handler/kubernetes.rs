@@ -1,18 +1,40 @@
-use actix_web::{error::ErrorInternalServerError, Error};
-use chrono::{DateTime, Utc};
-use kube::{api::{ListParams, Patch, PatchParams}, Api, Client};
+use std::{collections::BTreeMap, time::Duration};
+use actix_web::{error::{ErrorBadGateway, ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, InternalError}, http::StatusCode, Error, HttpResponse};
+use chrono::Utc;
+use futures::future::join_all;
+use log::warn;
+use kube::{api::{DynamicObject, ListParams, Patch, PatchParams, PostParams}, runtime::events::EventType, Api, Client, Resource};
 use k8s_openapi::api::{apps::v1::Deployment, core::v1::Pod};
-use paperclip::actix::{api_v2_operation, web::{Json, Query}};
+use paperclip::actix::{api_v2_operation, web::{Json, Path, Query, ReqData}};
+use serde::Serialize;
 use serde_json::{json, Value};
 use crate::{
+    handler::{canary::start_canary, playbook::respond},
     model::{
-        auth::{ApiKeyHeader, AuthJwtHeader},
+        auth::{ApiKeyHeader, AuthJwtHeader, RequestContext},
+        error::AuthError,
+        falco::FalcoEvent,
+        playbook::PlaybookResponse,
         kubernetes::{
-        DeployServicePayload, GetPodQuery, PodInfo, RestartServicePayload, SuccessResponse, UnisolatePodPayload
-    }},
-    util::time_helper
+        BatchDeployPayload, BatchDeployResponse, BatchDeployResult, ChangeResponse, ContainerImage, DeployServicePayload, DeploymentRevision, DeployServiceResponse, GetPodQuery, IsolatePodQuery, PodInfo, RestartServicePayload, RollbackDeploymentPayload, RolloutStatus,
+        RolloutStatusQuery, ScaleOperation, ScalePayload, ScaleResponse, SuccessResponse, UnisolatePodPayload, WorkloadKind
+    },
+        rbac::Action},
+    config::{get_registry_verify, get_rollout_max_restarts, get_rollout_timeout},
+    util::{audit::{records_by_id, AuditEntry}, diff::object_diff, events::{publish_event, publish_event_on}, image_ref::{retarget, ImageRef}, rbac::authorize,
+        registry::{pull_credentials, resolve_digest, RegistryError}, rollout::{
+        container_images, replica_sets, revision, wait_for_rollout, workload_rollout_status, AUDIT_ID_ANNOTATION, CHANGE_CAUSE_ANNOTATION, COMPLETE,
+        POD_TEMPLATE_HASH_LABEL, TIMEOUT
+    }, scale::{find_autoscaler, is_suspended, resume_autoscaler, scale_bounds, suspend_autoscaler, PREVIOUS_REPLICAS_ANNOTATION},
+        quarantine::{detached_labels, plan_reattachment, Reattachment, DETACHED_ANNOTATION, ISOLATE_LABEL}, time_helper, workload::{pod_template, WorkloadApi}}
 };
 
+const RESTARTED_AT_ANNOTATION: &str = "kubectl.Kubernetes.io/restartedAt";
+
+fn pod_labels(pod: &Pod) -> Value {
+    json!(pod.metadata.labels.clone().unwrap_or_default())
+}
+
 #[api_v2_operation(tags("Kubernetes"))]
 /// Get pods in a namespace 
 ///
@@ -29,7 +51,7 @@ pub async fn get_pod(_: ApiKeyHeader,  _: AuthJwtHeader, query: Query<GetPodQuer
     let namespace = &query.namespace;
 
     // Create an API handle for Pod resources
-    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+    let pods: Api<Pod> = Api::namespaced(client, namespace);
 
     // List pods with default parameters
     match pods.list(&ListParams::default()).await {
@@ -41,7 +63,7 @@ pub async fn get_pod(_: ApiKeyHeader,  _: AuthJwtHeader, query: Query<GetPodQuer
                 
                 // Convert Kubernetes Time to chrono DateTime
                 let creation_time = match p.metadata.creation_timestamp {
-                    Some(ref ts) => DateTime::<Utc>::from(ts.0.clone()),
+                    Some(ref ts) => ts.0,
                     None => now, // Fallback if creation_timestamp is None
                 };
                 
@@ -65,8 +87,19 @@ pub async fn get_pod(_: ApiKeyHeader,  _: AuthJwtHeader, query: Query<GetPodQuer
 #[api_v2_operation(tags("Kubernetes"))]
 /// Restart Kubernetes Deployment
 ///
-/// This api will restart a deployment on a specific namespace
-pub async fn restart_service_deployment(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<RestartServicePayload>) -> Result<Json<SuccessResponse>, Error> {
+/// This api will restart a deployment on a specific namespace. With `kind` it restarts a StatefulSet, DaemonSet
+/// or Argo Rollouts rollout instead, a rollout is restarted through `spec.restartAt`
+///
+/// With `dry_run` the patch is only computed by the API server and the changes of the deployment are returned
+pub async fn restart_service_deployment(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<RestartServicePayload>) -> Result<Json<ChangeResponse>, Error> {
+    let target = format!("{}/{}", payload.kind, payload.service_deployment);
+    let mut audit = AuditEntry::new(&ctx, Action::RestartServiceDeployment, &payload.namespace, target, json!(&*payload));
+    let result = restart(&payload, &mut audit).await;
+    audit.finish(&result).await;
+    result
+}
+
+async fn restart(payload: &RestartServicePayload, audit: &mut AuditEntry) -> Result<Json<ChangeResponse>, Error> {
     // Get `namespace` and `pod name`
     let namespace = &payload.namespace;
 
@@ -78,40 +111,112 @@ pub async fn restart_service_deployment(_: ApiKeyHeader,  _: AuthJwtHeader, payl
         Ok(c) => c,
         Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
     };
-    // Create an API handle for Pod resources
-    let deployment: Api<Deployment> = Api::namespaced(client, &namespace);
-    let patch = json!({
-        "spec": {
+    let workload = WorkloadApi::new(client.clone(), namespace, payload.kind);
+    let current = workload.api.get(service_deployment).await
+        .map_err(|e| ErrorInternalServerError(format!("Get {} failed: {}", payload.kind, e)))?;
+    audit.before(json!({ "restartedAt": restarted_at(&current) }));
+    let cause = format!("restart-service-deployment by {}", audit.actor());
+    let now = Utc::now().to_rfc3339();
+    // Argo Rollouts restarts the pods of a rollout on `restartAt`, a template change would start a new rollout
+    let spec = if payload.kind == WorkloadKind::Rollout {
+        json!({ "restartAt": now })
+    } else {
+        json!({
             "template": {
                 "metadata": {
                     "annotations": {
-                        "kubectl.Kubernetes.io/restartedAt": Utc::now().to_rfc3339(),
+                        RESTARTED_AT_ANNOTATION: now,
                     }
                 }
             }
-        }
+        })
+    };
+    let patch = json!({
+        "metadata": {
+            "annotations": change_annotations(audit, cause)
+        },
+        "spec": spec
     });
-    // Apply the patch to the pod
-    let pp = PatchParams::apply("restart-deployment");
-    match deployment.patch(service_deployment, &pp, &Patch::Merge(&patch)).await {
-        Ok(_) => Ok(Json(SuccessResponse { status: format!("Deployment {} restarted", service_deployment) })),
-        Err(e) => Err(ErrorInternalServerError(format!("Could not patch pod: {}", e)))
+    // Apply the patch to the workload
+    let pp = patch_params("restart-deployment", payload.dry_run);
+    match workload.api.patch(service_deployment, &pp, &Patch::Merge(&patch)).await {
+        Ok(patched) => {
+            audit.after(json!({ "restartedAt": restarted_at(&patched) }));
+            if payload.dry_run {
+                let status = format!("{} {} would be restarted", payload.kind, service_deployment);
+                return Ok(Json(ChangeResponse { status, changes: Some(object_diff(&current, &patched)) }));
+            }
+            let note = format!("Rollout restart requested by {}", audit.actor());
+            let reference = patched.object_ref(&workload.resource);
+            publish_event_on(client, reference, EventType::Normal, "RolloutRestarted", "Restart", note).await;
+            Ok(Json(ChangeResponse { status: format!("{} {} restarted", payload.kind, service_deployment), changes: None }))
+        },
+        Err(e) => Err(ErrorInternalServerError(format!("Could not patch {}: {}", payload.kind, e)))
     }
+}
 
-   
+fn restarted_at(workload: &DynamicObject) -> Option<String> {
+    let spec = workload.data.get("spec")?;
+    spec.pointer("/template/metadata/annotations")
+        .and_then(|annotations| annotations.get(RESTARTED_AT_ANNOTATION))
+        .or_else(|| spec.get("restartAt"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+// Field manager of a patch, with a server-side dry run that validates and computes it without persisting it
+pub(crate) fn patch_params(manager: &str, dry_run: bool) -> PatchParams {
+    let pp = PatchParams::apply(manager);
+    if dry_run { pp.dry_run() } else { pp }
 }
 
 #[api_v2_operation(tags("Kubernetes"))]
 /// Kubernetes Deployment
 ///
 /// This api will help you to deploy service in kubernetes
-pub async fn deploy_service(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<DeployServicePayload>) -> Result<Json<SuccessResponse>, Error> {
+///
+/// `kind` selects a StatefulSet, DaemonSet or Argo Rollouts rollout instead of a Deployment, see /rollout-status
+/// for when the rollout of each kind is complete
+///
+/// The container image gets the new `version` tag and/or is pinned to `digest`. `repository` also moves it to
+/// another registry or repository, which needs the `change-image-repository` action.
+/// With `verify_image` the image is looked up in its registry first, using the imagePullSecrets of the deployment,
+/// and an image that does not exist is rejected.
+///
+/// With `wait` the response is sent once the rollout is finished, a failed rollout is answered with 500
+/// and a rollout still in progress after `timeout_seconds` with 504. Unless `auto_rollback` is false,
+/// the previous image is put back when the rollout fails, times out or a new container restarts more than `max_restarts` times
+///
+/// With `dry_run` the patch is only computed by the API server and the changes of the deployment are returned
+///
+/// With `canary` the new image first runs in a `<service_deployment>-canary` Deployment next to the stable pods.
+/// Once ready the canary is watched for its bake time, then promoted, or aborted when its pods restart or stop being
+/// ready. Without `wait` this happens in the background, see /canaries, /canary/promote and /canary/abort.
+/// Canary pods are labelled `track: canary`, the selector of the deployment must not match them
+pub async fn deploy_service(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<DeployServicePayload>) -> Result<Json<DeployServiceResponse>, Error> {
+    if payload.repository.is_some() {
+        authorize_repository_change(&ctx, &payload.namespace)?;
+    }
+    let target = format!("{}/{}", payload.kind, payload.service_deployment);
+    let mut audit = AuditEntry::new(&ctx, Action::DeployService, &payload.namespace, target, json!(&*payload));
+    let result = match &payload.canary {
+        Some(canary) => start_canary(&ctx, &payload, canary, &mut audit).await,
+        None => deploy(&payload, &mut audit).await,
+    };
+    audit.finish(&result).await;
+    result
+}
+
+async fn deploy(payload: &DeployServicePayload, audit: &mut AuditEntry) -> Result<Json<DeployServiceResponse>, Error> {
     // Get `namespace` and `pod name`
     let namespace = &payload.namespace;
     let service_deployment = &payload.service_deployment;
     let container_name = &payload.container_name;
-    let image_tag = &payload.version;
-
+    let target = ImageTarget {
+        repository: payload.repository.as_deref(),
+        tag: payload.version.as_deref(),
+        digest: payload.digest.as_deref(),
+    };
     // Interact with k8s
     // Initialize the Kubernetes client
     let client = match Client::try_default().await {
@@ -119,108 +224,699 @@ pub async fn deploy_service(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<De
         Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
     };
 
-    let deployment: Api<Deployment> = Api::namespaced(client, &namespace);
-    let current_deployment = match deployment.get(service_deployment).await {
+    let workload = WorkloadApi::new(client.clone(), namespace, payload.kind);
+    let current_deployment = match workload.api.get(service_deployment).await {
         Ok(c) => Ok(c),
-        Err(e) => Err(ErrorInternalServerError(format!("Get deployment failed: {}", e))),
+        Err(e) => Err(ErrorInternalServerError(format!("Get {} failed: {}", payload.kind, e))),
     }?;
-    // Find the container by name and print its image
-    if let Some(container) = current_deployment.spec.unwrap().template.spec.unwrap().containers.iter().find(|c| c.name.as_str() == container_name) {
-        // Print the image for the found container or a default message if no image is specified
-        let image_name = container.image.as_deref().unwrap_or("No image specified");
-        let parts: Vec<&str> = image_name.split(':').collect();
-        let normalize_image_name = parts[0];
-        let full_image = format!("{}:{}", normalize_image_name, image_tag);
-        let patch = json!({
-            "spec": {
-                "template": {
-                    "spec": {
-                        "containers": [
-                            {
-                                "name": container_name,
-                                "image": full_image
-                            }
-                        ]
+    // Find the container by name, init containers included
+    let change = plan_image_change(&current_deployment, container_name, &target).map_err(ErrorBadRequest)?;
+    let image_name = change.previous.as_str();
+    let full_image = change.image.as_str();
+    audit.before(json!({ "container": container_name, "image": image_name }));
+    let resolved_digest = if payload.verify_image.unwrap_or_else(get_registry_verify) {
+        Some(verify_image(client.clone(), namespace, &current_deployment, full_image).await?)
+    } else {
+        None
+    };
+    let cause = format!("deploy-service by {}: {}={}", audit.actor(), container_name, full_image);
+    let annotations = change_annotations(audit, cause);
+    match set_images(&workload, &current_deployment, std::slice::from_ref(&change), false, annotations, payload.dry_run).await {
+        Ok(patched) => {
+            audit.after(json!({ "container": container_name, "image": full_image, "digest": resolved_digest }));
+            if payload.dry_run {
+                let status = format!("Container {} would be changed from {} to {}", container_name, image_name, full_image);
+                let changes = Some(object_diff(&current_deployment, &patched));
+                return Ok(Json(DeployServiceResponse { status, rollout: None, rolled_back_to: None, resolved_digest, changes, canary: None }));
+            }
+            let note = format!("Container {} image changed from {} to {} by {}", container_name, image_name, full_image, audit.actor());
+            publish_event_on(client.clone(), patched.object_ref(&workload.resource), EventType::Normal, "ImageUpdated", "Deploy", note).await;
+            if !payload.wait {
+                let status = format!("Service {} deployed!", service_deployment);
+                return Ok(Json(DeployServiceResponse { status, rollout: None, rolled_back_to: None, resolved_digest, changes: None, canary: None }));
+            }
+            let timeout = Duration::from_secs(payload.timeout_seconds.unwrap_or_else(get_rollout_timeout));
+            let max_restarts = payload.max_restarts.unwrap_or_else(get_rollout_max_restarts);
+            let status = wait_for_rollout(client.clone(), namespace, payload.kind, service_deployment, timeout, Some(max_restarts)).await
+                .map_err(ErrorInternalServerError)?;
+            if status.state == COMPLETE {
+                let message = status.message.clone();
+                return Ok(Json(DeployServiceResponse {
+                    status: message,
+                    rollout: Some(status),
+                    rolled_back_to: None,
+                    resolved_digest,
+                    changes: None,
+                    canary: None,
+                }));
+            }
+            let mut response = DeployServiceResponse {
+                status: format!("Rollout failed: {}", status.message),
+                rollout: Some(status.clone()),
+                rolled_back_to: None,
+                resolved_digest,
+                changes: None,
+                canary: None,
+            };
+            if payload.auto_rollback.unwrap_or(true) {
+                // Put the image that was running before the deploy back
+                let cause = format!("rollback by {} after failed rollout: {}={}", audit.actor(), container_name, image_name);
+                let annotations = change_annotations(audit, cause);
+                match set_images(&workload, &current_deployment, std::slice::from_ref(&change), true, annotations, false).await {
+                    Ok(reverted) => {
+                        audit.after(json!({ "container": container_name, "image": image_name, "rolledBackFrom": full_image }));
+                        let note = format!("Rollout of {} failed ({}), container {} rolled back to {}", full_image, status.message, container_name, image_name);
+                        publish_event_on(client, reverted.object_ref(&workload.resource), EventType::Warning, "RolledBack", "Rollback", note).await;
+                        response.status = format!("Rollout failed, rolled back to {}: {}", image_name, status.message);
+                        response.rolled_back_to = Some(image_name.to_string());
+                    },
+                    Err(e) => {
+                        response.status = format!("Rollout failed and rollback to {} failed ({}): {}", image_name, e, status.message);
                     }
                 }
             }
+            Err(rollout_failure(&status, response.status.clone(), &response))
+        },
+        Err(e) => Err(ErrorInternalServerError(format!("Could not patch {}: {}", payload.kind, e)))
+    }
+}
+
+/// New image of one container, with the image to put back on rollback
+pub(crate) struct ImageChange {
+    pub(crate) container: String,
+    pub(crate) init: bool,
+    pub(crate) previous: String,
+    pub(crate) image: String,
+}
+
+// Resolve `image` in its registry with the pull secrets of the workload, unknown images are a bad request
+pub(crate) async fn verify_image(client: Client, namespace: &str, workload: &DynamicObject, image: &str) -> Result<String, Error> {
+    let image = ImageRef::parse(image).map_err(ErrorBadRequest)?;
+    let template = pod_template(workload).map_err(ErrorBadRequest)?;
+    let credentials = pull_credentials(client, namespace, &template).await;
+    match resolve_digest(&image, &credentials).await {
+        Ok(digest) => Ok(digest),
+        Err(RegistryError::NotFound(e)) | Err(RegistryError::Unauthorized(e)) => Err(ErrorBadRequest(e)),
+        Err(RegistryError::Unavailable(e)) => Err(ErrorBadGateway(e)),
+    }
+}
+
+/// Requested image of a container: a tag and/or a digest, and possibly another repository
+pub(crate) struct ImageTarget<'a> {
+    pub(crate) repository: Option<&'a str>,
+    pub(crate) tag: Option<&'a str>,
+    pub(crate) digest: Option<&'a str>,
+}
+
+// Moving an image to another repository is a separate permission, a deployer can only change versions
+fn authorize_repository_change(ctx: &RequestContext, namespace: &str) -> Result<(), Error> {
+    authorize(&ctx.principal, Action::ChangeImageRepository, Some(namespace))
+        .map_err(|reason| AuthError::InsufficientPermission(reason).into())
+}
+
+// Change of `container` (or init container) of the workload to the requested image
+pub(crate) fn plan_image_change(workload: &DynamicObject, container: &str, target: &ImageTarget) -> Result<ImageChange, String> {
+    let (previous, init) = find_container(workload, container)?;
+    let image = retarget(&previous, target.repository, target.tag, target.digest)?;
+    Ok(ImageChange {
+        container: container.to_string(),
+        init,
+        image: image.to_string(),
+        previous,
+    })
+}
+
+// Current image of `container` in the workload, and whether it is an init container
+pub(crate) fn find_container(workload: &DynamicObject, container: &str) -> Result<(String, bool), String> {
+    let name = workload.metadata.name.as_deref().unwrap_or_default();
+    let kind = workload.types.as_ref().map(|types| types.kind.as_str()).unwrap_or("Workload");
+    let template = pod_template(workload)?;
+    let found = template.spec.as_ref()
+        .and_then(|spec| {
+            let containers = spec.containers.iter().map(|c| (c, false));
+            let init_containers = spec.init_containers.iter().flatten().map(|c| (c, true));
+            containers.chain(init_containers).find(|(c, _)| c.name == container)
         });
-        // Apply the patch to the pod
-        let pp = PatchParams::apply("deploy-service");
-        match deployment.patch(service_deployment, &pp, &Patch::Merge(&patch)).await {
-            Ok(_) => Ok(Json(SuccessResponse { status: format!("Service {} deployed!", service_deployment) })),
-            Err(e) => Err(ErrorInternalServerError(format!("Could not patch pod: {}", e)))
+    let (found, init) = found.ok_or_else(|| format!("{} {} has no container {}", kind, name, container))?;
+    Ok((found.image.clone().unwrap_or_default(), init))
+}
+
+// Annotations recording why and through which audit record the next revision was created
+pub(crate) fn change_annotations(audit: &AuditEntry, cause: String) -> BTreeMap<String, String> {
+    BTreeMap::from([
+        (CHANGE_CAUSE_ANNOTATION.to_string(), cause),
+        (AUDIT_ID_ANNOTATION.to_string(), audit.id().to_string()),
+    ])
+}
+
+// Set the new images of the containers of `current`, or the previous ones with `revert`.
+// The strategic merge keeps the other fields of the containers. A JSON merge, the only patch CRDs accept,
+// replaces whole lists, so the containers of `current` are sent with their new images.
+pub(crate) async fn set_images(
+    workload: &WorkloadApi,
+    current: &DynamicObject,
+    changes: &[ImageChange],
+    revert: bool,
+    annotations: BTreeMap<String, String>,
+    dry_run: bool,
+) -> Result<DynamicObject, kube::Error> {
+    let image = |change: &ImageChange| if revert { change.previous.clone() } else { change.image.clone() };
+    let current_spec = current.data.pointer("/spec/template/spec");
+    let mut pod_spec = serde_json::Map::new();
+    for (key, init) in [("containers", false), ("initContainers", true)] {
+        let changed: Vec<&ImageChange> = changes.iter().filter(|change| change.init == init).collect();
+        if changed.is_empty() {
+            continue;
+        }
+        let list: Vec<Value> = if workload.strategic_merge() {
+            changed.iter().map(|change| json!({ "name": change.container, "image": image(change) })).collect()
+        } else {
+            let containers = current_spec.and_then(|spec| spec.get(key)).and_then(Value::as_array).cloned().unwrap_or_default();
+            containers.into_iter().map(|mut container| {
+                let name = container.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+                if let Some(change) = changed.iter().find(|change| change.container == name) {
+                    container["image"] = json!(image(change));
+                }
+                container
+            }).collect()
+        };
+        pod_spec.insert(key.to_string(), Value::Array(list));
+    }
+    let patch = json!({
+        "metadata": {
+            "annotations": annotations
+        },
+        "spec": {
+            "template": {
+                "spec": pod_spec
+            }
         }
+    });
+    let pp = patch_params("deploy-service", dry_run);
+    let name = current.metadata.name.as_deref().unwrap_or_default();
+    if workload.strategic_merge() {
+        workload.api.patch(name, &pp, &Patch::Strategic(&patch)).await
     } else {
-        return Err(ErrorInternalServerError(format!("Failed to deploy")))
+        workload.api.patch(name, &pp, &Patch::Merge(&patch)).await
     }
 }
 
-#[api_v2_operation(tags("Kubernetes Security"))]
-/// Isolate pod
+// Error response with a JSON body, so the caller still gets the details of a failed operation
+pub(crate) fn error_with_body<T: Serialize>(code: StatusCode, message: String, body: &T) -> Error {
+    InternalError::from_response(message, HttpResponse::build(code).json(body)).into()
+}
+
+// Error carrying the rollout outcome, 504 when the rollout was still progressing
+fn rollout_failure<T: Serialize>(status: &RolloutStatus, message: String, body: &T) -> Error {
+    let code = if status.state == TIMEOUT { StatusCode::GATEWAY_TIMEOUT } else { StatusCode::INTERNAL_SERVER_ERROR };
+    error_with_body(code, message, body)
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Batch deployment
 ///
-/// Isolating pod network connection, both Ingress and Egress
-/// 
-/// Requirement: Network policy that deny Ingress and Eggress with label selector isolate: "true" 
-/// 
-/// Example usage: Use this endpoint to isolate pod when threat is detected on a pod
-pub async fn isolate_pod(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<Value>) -> Result<Json<SuccessResponse>, Error> {
-    // Get the JSON payload
-    let json_payload = payload.into_inner();
-    // Extract values from the `output_fields` object
-    let output_fields = json_payload.get("output_fields").and_then(Value::as_object);
+/// Deploy new versions of containers (init containers included) of several deployments of a namespace as one release.
+/// Nothing is changed when a deployment or container does not exist, and every deployment is put back to its previous
+/// images when one of them can not be patched or, with `wait` (the default), fails to roll out
+pub async fn deploy_batch(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<BatchDeployPayload>) -> Result<Json<BatchDeployResponse>, Error> {
+    let containers = || payload.deployments.iter().flat_map(|d| d.containers.iter());
+    if containers().any(|c| c.repository.is_some()) {
+        authorize_repository_change(&ctx, &payload.namespace)?;
+    }
+    let names: Vec<&str> = payload.deployments.iter().map(|d| d.service_deployment.as_str()).collect();
+    let target = format!("Deployment/{}", names.join(","));
+    let mut audit = AuditEntry::new(&ctx, Action::DeployService, &payload.namespace, target, json!(&*payload));
+    audit.targets(names.iter().map(|name| format!("Deployment/{}", name)).collect());
+    let result = batch_deploy(&payload, &mut audit).await;
+    audit.finish(&result).await;
+    result
+}
 
-    // Get `namespace` and `pod name`
-    let namespace = output_fields
-        .and_then(|fields| fields.get("k8s.ns.name"))
-        .and_then(Value::as_str)
-        .unwrap_or("Unknown");
+/// Deployment of a batch with the changes of its containers
+struct PlannedDeployment {
+    name: String,
+    current: DynamicObject,
+    changes: Vec<ImageChange>,
+}
 
-    let pod_name = output_fields
-        .and_then(|fields| fields.get("k8s.pod.name"))
-        .and_then(Value::as_str)
-        .unwrap_or("Unknown");
-
-    let falco_rule = json_payload.get("rule").and_then(Value::as_str).unwrap_or("Unknown");
-    let implemented_falco_rules = vec!["network_scan_process_in_container"];
-    
-    if implemented_falco_rules.contains(&falco_rule) {
-        // Interact with k8s
-        // Initialize the Kubernetes client
-        let client = match Client::try_default().await {
-            Ok(c) => c,
-            Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
-        };
-        // Create an API handle for Pod resources
-        let pods: Api<Pod> = Api::namespaced(client, &namespace);
-        let patch = json!({
-            "metadata": {
-                "labels": {
-                    "isolate": "true"
+fn describe_changes(changes: &[ImageChange]) -> String {
+    changes.iter().map(|c| format!("{}={}", c.container, c.image)).collect::<Vec<_>>().join(", ")
+}
+
+// Image of every changed container, before or after the batch
+fn batch_images(planned: &[PlannedDeployment], previous: bool) -> Value {
+    let images: BTreeMap<&str, BTreeMap<&str, &str>> = planned.iter().map(|deployment| {
+        let containers = deployment.changes.iter()
+            .map(|c| (c.container.as_str(), if previous { c.previous.as_str() } else { c.image.as_str() }))
+            .collect();
+        (deployment.name.as_str(), containers)
+    }).collect();
+    json!(images)
+}
+
+async fn batch_deploy(payload: &BatchDeployPayload, audit: &mut AuditEntry) -> Result<Json<BatchDeployResponse>, Error> {
+    let namespace = &payload.namespace;
+    if payload.deployments.is_empty() {
+        return Err(ErrorBadRequest("No deployment to deploy"));
+    }
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    let deployments = WorkloadApi::new(client.clone(), namespace, WorkloadKind::Deployment);
+
+    // Every deployment and container is checked before the first change
+    let mut planned: Vec<PlannedDeployment> = Vec::new();
+    for item in &payload.deployments {
+        let name = &item.service_deployment;
+        if planned.iter().any(|p| &p.name == name) {
+            return Err(ErrorBadRequest(format!("Deployment {} is listed twice", name)));
+        }
+        if item.containers.is_empty() {
+            return Err(ErrorBadRequest(format!("No container to deploy in {}", name)));
+        }
+        let current = deployments.api.get(name).await
+            .map_err(|e| ErrorInternalServerError(format!("Get deployment {} failed: {}", name, e)))?;
+        let changes = item.containers.iter()
+            .map(|c| {
+                let target = ImageTarget {
+                    repository: c.repository.as_deref(),
+                    tag: c.version.as_deref(),
+                    digest: c.digest.as_deref(),
+                };
+                plan_image_change(&current, &c.container_name, &target)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ErrorBadRequest)?;
+        planned.push(PlannedDeployment { name: name.clone(), current, changes });
+    }
+    audit.before(batch_images(&planned, true));
+
+    let mut response = BatchDeployResponse {
+        status: String::new(),
+        deployments: planned.iter().map(|deployment| BatchDeployResult {
+            service_deployment: deployment.name.clone(),
+            containers: deployment.changes.iter()
+                .map(|c| ContainerImage { container: c.container.clone(), image: c.image.clone(), tag: image_tag(&c.image) })
+                .collect(),
+            rollout: None,
+        }).collect(),
+        rolled_back: false,
+    };
+
+    for (index, deployment) in planned.iter().enumerate() {
+        let cause = format!("deploy-service by {}: {}", audit.actor(), describe_changes(&deployment.changes));
+        let annotations = change_annotations(audit, cause);
+        match set_images(&deployments, &deployment.current, &deployment.changes, false, annotations, false).await {
+            Ok(patched) => {
+                let note = format!("Images changed to {} by {}", describe_changes(&deployment.changes), audit.actor());
+                let reference = patched.object_ref(&deployments.resource);
+                publish_event_on(client.clone(), reference, EventType::Normal, "ImageUpdated", "Deploy", note).await;
+            },
+            Err(e) => {
+                let reason = format!("Could not patch deployment {}: {}", deployment.name, e);
+                response.status = revert_batch(client, &deployments, &planned[..index], audit, &reason).await;
+                response.rolled_back = true;
+                return Err(error_with_body(StatusCode::INTERNAL_SERVER_ERROR, response.status.clone(), &response));
+            }
+        }
+    }
+    audit.after(batch_images(&planned, false));
+    if !payload.wait.unwrap_or(true) {
+        response.status = format!("{} deployments deployed", planned.len());
+        return Ok(Json(response));
+    }
+
+    let timeout = Duration::from_secs(payload.timeout_seconds.unwrap_or_else(get_rollout_timeout));
+    let max_restarts = payload.max_restarts.unwrap_or_else(get_rollout_max_restarts);
+    let statuses = join_all(planned.iter().map(|deployment| {
+        wait_for_rollout(client.clone(), namespace, WorkloadKind::Deployment, &deployment.name, timeout, Some(max_restarts))
+    })).await;
+    let mut failures = Vec::new();
+    let mut timed_out = true;
+    for (result, status) in response.deployments.iter_mut().zip(statuses) {
+        match status {
+            Ok(status) => {
+                if status.state != COMPLETE {
+                    failures.push(format!("{}: {}", result.service_deployment, status.message));
+                    timed_out &= status.state == TIMEOUT;
                 }
+                result.rollout = Some(status);
+            },
+            Err(e) => {
+                failures.push(format!("{}: {}", result.service_deployment, e));
+                timed_out = false;
             }
-        });
-        // Apply the patch to the pod
-        let pp = PatchParams::apply("add-label-isolate");
-        match pods.patch(pod_name, &pp, &Patch::Merge(&patch)).await {
-            Ok(_) => Ok(Json(SuccessResponse { status: "Pod isolated succesfully".to_string() })),
-            Err(e) => Err(ErrorInternalServerError(format!("Could not patch pod: {}", e)))
         }
+    }
+    if failures.is_empty() {
+        response.status = format!("{} deployments rolled out", planned.len());
+        return Ok(Json(response));
+    }
+    let reason = format!("Rollout failed ({})", failures.join("; "));
+    response.status = revert_batch(client, &deployments, &planned, audit, &reason).await;
+    response.rolled_back = true;
+    let code = if timed_out { StatusCode::GATEWAY_TIMEOUT } else { StatusCode::INTERNAL_SERVER_ERROR };
+    Err(error_with_body(code, response.status.clone(), &response))
+}
+
+// Put the previous images back on the deployments already patched, returns the outcome to report
+async fn revert_batch(client: Client, deployments: &WorkloadApi, patched: &[PlannedDeployment], audit: &mut AuditEntry, reason: &str) -> String {
+    let mut errors = Vec::new();
+    for deployment in patched {
+        let cause = format!("rollback by {} after failed batch deploy", audit.actor());
+        let annotations = change_annotations(audit, cause);
+        match set_images(deployments, &deployment.current, &deployment.changes, true, annotations, false).await {
+            Ok(reverted) => {
+                let note = format!("Batch deploy failed, previous images put back: {}", reason);
+                let reference = reverted.object_ref(&deployments.resource);
+                publish_event_on(client.clone(), reference, EventType::Warning, "RolledBack", "Rollback", note).await;
+            },
+            Err(e) => errors.push(format!("{}: {}", deployment.name, e)),
+        }
+    }
+    audit.after(json!({ "rolledBack": batch_images(patched, true), "errors": errors }));
+    if errors.is_empty() {
+        format!("{}, every deployment was rolled back", reason)
     } else {
-        Ok(Json(SuccessResponse { status: "Skipped, no action taken".to_string() }))
+        format!("{}, rollback failed for {}", reason, errors.join("; "))
     }
-   
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Rollout status
+///
+/// Report the progress of a rollout with the pods of the new revision that fail to start. Deployments, StatefulSets and
+/// DaemonSets follow the rules of `kubectl rollout status`, an Argo Rollout is complete once its phase is `Healthy`
+/// and failed once it is `Degraded`.
+/// With `wait` the response is sent once the rollout is finished, a failed rollout is answered with 500
+/// and a rollout still in progress after `timeout_seconds` with 504
+pub async fn get_rollout_status(_: ApiKeyHeader,  _: AuthJwtHeader, query: Query<RolloutStatusQuery>) -> Result<Json<RolloutStatus>, Error> {
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    if !query.wait.unwrap_or(false) {
+        return workload_rollout_status(client, &query.namespace, query.kind, &query.service_deployment).await
+            .map(Json)
+            .map_err(ErrorInternalServerError);
+    }
+    let timeout = Duration::from_secs(query.timeout_seconds.unwrap_or_else(get_rollout_timeout));
+    let status = wait_for_rollout(client, &query.namespace, query.kind, &query.service_deployment, timeout, None).await
+        .map_err(ErrorInternalServerError)?;
+    if status.state == COMPLETE {
+        Ok(Json(status))
+    } else {
+        Err(rollout_failure(&status, status.message.clone(), &status))
+    }
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Rollback Kubernetes Deployment
+///
+/// Return a deployment to the pod template of one of its ReplicaSet revisions, by default the one before the current revision
+pub async fn rollback_deployment(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<RollbackDeploymentPayload>) -> Result<Json<SuccessResponse>, Error> {
+    let target = format!("Deployment/{}", payload.service_deployment);
+    let mut audit = AuditEntry::new(&ctx, Action::RollbackDeployment, &payload.namespace, target, json!(&*payload));
+    let result = rollback(&payload, &mut audit).await;
+    audit.finish(&result).await;
+    result
+}
+
+async fn rollback(payload: &RollbackDeploymentPayload, audit: &mut AuditEntry) -> Result<Json<SuccessResponse>, Error> {
+    let namespace = &payload.namespace;
+    let service_deployment = &payload.service_deployment;
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let mut current = deployments.get(service_deployment).await
+        .map_err(|e| ErrorInternalServerError(format!("Get deployment failed: {}", e)))?;
+    let current_revision = revision(&current.metadata);
+    let replica_sets = replica_sets(client.clone(), namespace, &current).await
+        .map_err(ErrorInternalServerError)?;
+    let target = match payload.revision {
+        Some(wanted) => replica_sets.iter().find(|rs| revision(&rs.metadata) == Some(wanted)),
+        None => replica_sets.iter().rev().find(|rs| revision(&rs.metadata) < current_revision),
+    };
+    let (target_revision, mut template) = match target.and_then(|rs| Some((revision(&rs.metadata)?, rs.spec.as_ref()?.template.clone()?))) {
+        Some(found) => found,
+        None => return Err(ErrorNotFound(format!("No revision to roll deployment {} back to", service_deployment))),
+    };
+    if Some(target_revision) == current_revision {
+        return Ok(Json(SuccessResponse { status: format!("Deployment {} already at revision {}", service_deployment, target_revision) }));
+    }
+    // The hash label is added by the ReplicaSet controller, the Deployment template never has it
+    if let Some(labels) = template.metadata.as_mut().and_then(|metadata| metadata.labels.as_mut()) {
+        labels.remove(POD_TEMPLATE_HASH_LABEL);
+    }
+    let spec = current.spec.as_mut().ok_or_else(|| ErrorInternalServerError("Deployment has no spec"))?;
+    audit.before(json!({ "revision": current_revision, "images": container_images(&spec.template) }));
+    let images = container_images(&template);
+    spec.template = template;
+    let cause = format!("rollback-deployment by {}: revision {}", audit.actor(), target_revision);
+    current.metadata.annotations.get_or_insert_with(Default::default).extend(change_annotations(audit, cause));
+    match deployments.replace(service_deployment, &PostParams::default(), &current).await {
+        Ok(replaced) => {
+            audit.after(json!({ "rolledBackTo": target_revision, "images": images }));
+            let note = format!("Rolled back to revision {} by {}", target_revision, audit.actor());
+            publish_event(client, &replaced, EventType::Normal, "RolledBack", "Rollback", note).await;
+            Ok(Json(SuccessResponse { status: format!("Deployment {} rolled back to revision {}", service_deployment, target_revision) }))
+        },
+        Err(e) => Err(ErrorInternalServerError(format!("Could not roll back deployment: {}", e)))
+    }
+}
+
+fn image_tag(image: &str) -> Option<String> {
+    ImageRef::parse(image).ok().and_then(|image| image.tag)
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Deployment history
+///
+/// List the ReplicaSet revisions of a deployment, newest first, with their images, change cause
+/// and the Officer user who triggered them
+pub async fn get_deployment_history(_: ApiKeyHeader,  _: AuthJwtHeader, path: Path<(String, String)>) -> Result<Json<Vec<DeploymentRevision>>, Error> {
+    let (namespace, name) = path.into_inner();
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), &namespace);
+    let deployment = deployments.get(&name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get deployment failed: {}", e)))?;
+    let current_revision = revision(&deployment.metadata);
+    let replica_sets = replica_sets(client, &namespace, &deployment).await
+        .map_err(ErrorInternalServerError)?;
+
+    let ids = replica_sets.iter()
+        .filter_map(|rs| rs.metadata.annotations.as_ref()?.get(AUDIT_ID_ANNOTATION).cloned())
+        .collect();
+    let records = records_by_id(ids).await
+        .map_err(ErrorInternalServerError)?;
+
+    let history = replica_sets.iter().rev().filter_map(|rs| {
+        let revision = revision(&rs.metadata)?;
+        let annotations = rs.metadata.annotations.clone().unwrap_or_default();
+        let audit_id = annotations.get(AUDIT_ID_ANNOTATION).cloned();
+        let record = audit_id.as_ref().and_then(|id| records.get(id));
+        let containers = rs.spec.as_ref()
+            .and_then(|spec| spec.template.as_ref())
+            .map(container_images)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(container, image)| ContainerImage { container, tag: image_tag(&image), image })
+            .collect();
+        Some(DeploymentRevision {
+            revision,
+            replica_set: rs.metadata.name.clone().unwrap_or_default(),
+            current: Some(revision) == current_revision,
+            replicas: rs.status.as_ref().map(|status| status.replicas).unwrap_or_default(),
+            created: rs.metadata.creation_timestamp.as_ref().map(|t| t.0.to_rfc3339()),
+            change_cause: annotations.get(CHANGE_CAUSE_ANNOTATION).cloned(),
+            containers,
+            action: record.map(|r| r.action.clone()),
+            triggered_by: record.map(|r| r.actor.clone()),
+            audit_id,
+        })
+    }).collect();
+    Ok(Json(history))
+}
+
+#[api_v2_operation(tags("Kubernetes"))]
+/// Scale workload
+///
+/// Scale a Deployment or StatefulSet through its scale subresource, within the replica bounds of the namespace
+/// from `SCALE_BOUNDS`.
+///
+/// `zero` scales to zero whatever the minimum, for incident containment, and remembers the current replicas that
+/// `restore` scales back to. With `suspend_autoscaler` the HorizontalPodAutoscaler of the workload is pinned to
+/// `replicas` until `restore`, an autoscaler stops on its own while its target has zero replicas
+pub async fn scale(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<ScalePayload>) -> Result<Json<ScaleResponse>, Error> {
+    let target = format!("{}/{}", payload.kind, payload.service_deployment);
+    let mut audit = AuditEntry::new(&ctx, Action::Scale, &payload.namespace, target, json!(&*payload));
+    let result = scale_workload(&payload, &mut audit).await;
+    audit.finish(&result).await;
+    result
+}
+
+pub(crate) async fn scale_workload(payload: &ScalePayload, audit: &mut AuditEntry) -> Result<Json<ScaleResponse>, Error> {
+    let namespace = &payload.namespace;
+    let name = &payload.service_deployment;
+    let kind = payload.kind;
+    if !matches!(kind, WorkloadKind::Deployment | WorkloadKind::StatefulSet) {
+        return Err(ErrorBadRequest(format!("Only Deployments and StatefulSets can be scaled, not {}", kind)));
+    }
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    let workload = WorkloadApi::new(client.clone(), namespace, kind);
+    let current = workload.api.get(name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get {} failed: {}", kind, e)))?;
+    let previous_replicas = workload.api.get_scale(name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get scale of {} failed: {}", name, e)))?
+        .spec.and_then(|spec| spec.replicas).unwrap_or_default();
+    let remembered: Option<i32> = current.metadata.annotations.as_ref()
+        .and_then(|annotations| annotations.get(PREVIOUS_REPLICAS_ANNOTATION))
+        .and_then(|replicas| replicas.parse().ok());
+    let autoscaler = find_autoscaler(client.clone(), namespace, kind, name).await
+        .map_err(ErrorInternalServerError)?;
+    let autoscaler_name = autoscaler.as_ref().and_then(|hpa| hpa.metadata.name.clone());
+    audit.before(json!({ "replicas": previous_replicas, "rememberedReplicas": remembered, "autoscaler": autoscaler_name }));
+
+    let replicas = match payload.operation {
+        ScaleOperation::Set => {
+            let replicas = payload.replicas.ok_or_else(|| ErrorBadRequest("replicas is required to scale"))?;
+            scale_bounds(namespace)
+                .check(replicas)
+                .map_err(|e| ErrorBadRequest(format!("Can not scale {} in {}: {}", name, namespace, e)))?;
+            replicas
+        },
+        ScaleOperation::Zero => 0,
+        ScaleOperation::Restore => remembered
+            .ok_or_else(|| ErrorBadRequest(format!("{} {} has no remembered replicas to restore", kind, name)))?,
+    };
+    let suspend = payload.operation == ScaleOperation::Set && payload.suspend_autoscaler;
+    if suspend && autoscaler.is_none() {
+        return Err(ErrorBadRequest(format!("{} {} has no autoscaler to suspend", kind, name)));
+    }
+    if suspend && replicas < 1 {
+        return Err(ErrorBadRequest("An autoscaler can not be pinned to zero replicas, use the zero operation"));
+    }
+
+    // The replicas from before the first intervention are kept until restore
+    let intervening = payload.operation == ScaleOperation::Zero || suspend;
+    let remembering = intervening && remembered.is_none();
+    if remembering {
+        set_annotation(&workload, name, PREVIOUS_REPLICAS_ANNOTATION, json!(previous_replicas.to_string())).await?;
+    }
+    let scaled = async {
+        if let (true, Some(hpa)) = (suspend, autoscaler.as_ref()) {
+            suspend_autoscaler(client.clone(), namespace, hpa, replicas).await.map_err(ErrorInternalServerError)?;
+        }
+        let patch = json!({ "spec": { "replicas": replicas } });
+        workload.api.patch_scale(name, &PatchParams::apply("scale"), &Patch::Merge(&patch)).await
+            .map_err(|e| ErrorInternalServerError(format!("Could not scale {}: {}", name, e)))?;
+        Ok::<(), Error>(())
+    }.await;
+    // A failed scale must not leave replicas behind for a later restore
+    if let Err(e) = scaled {
+        if remembering {
+            if let Err(cleanup) = set_annotation(&workload, name, PREVIOUS_REPLICAS_ANNOTATION, Value::Null).await {
+                warn!("Could not remove {} of {} after a failed scale: {}", PREVIOUS_REPLICAS_ANNOTATION, name, cleanup);
+            }
+        }
+        return Err(e);
+    }
+    let mut autoscaler_suspended = suspend || autoscaler.as_ref().is_some_and(is_suspended);
+    if payload.operation == ScaleOperation::Restore {
+        if let Some(hpa) = autoscaler.as_ref().filter(|hpa| is_suspended(hpa)) {
+            resume_autoscaler(client.clone(), namespace, hpa).await.map_err(ErrorInternalServerError)?;
+        }
+        autoscaler_suspended = false;
+        set_annotation(&workload, name, PREVIOUS_REPLICAS_ANNOTATION, Value::Null).await?;
+    }
+    audit.after(json!({ "replicas": replicas, "autoscaler": autoscaler_name, "autoscalerSuspended": autoscaler_suspended }));
+
+    let note = format!("Scaled from {} to {} replicas by {}", previous_replicas, replicas, audit.actor());
+    let type_ = if payload.operation == ScaleOperation::Zero { EventType::Warning } else { EventType::Normal };
+    publish_event_on(client, current.object_ref(&workload.resource), type_, "Scaled", "Scale", note).await;
+    Ok(Json(ScaleResponse {
+        status: format!("{} {} scaled from {} to {} replicas", kind, name, previous_replicas, replicas),
+        previous_replicas,
+        replicas,
+        autoscaler: autoscaler_name,
+        autoscaler_suspended,
+    }))
+}
+
+// Set or, with null, remove an annotation of a workload
+async fn set_annotation(workload: &WorkloadApi, name: &str, key: &str, value: Value) -> Result<(), Error> {
+    let patch = json!({ "metadata": { "annotations": { key: value } } });
+    workload.api.patch(name, &PatchParams::apply("scale"), &Patch::Merge(&patch)).await
+        .map_err(|e| ErrorInternalServerError(format!("Could not annotate {}: {}", name, e)))?;
+    Ok(())
+}
+
+#[api_v2_operation(tags("Kubernetes Security"))]
+/// Isolate pod
+///
+/// Respond to a Falco alert with the first matching playbook from `PLAYBOOKS_FILE`, see `/playbooks`.
+/// Without playbook file, pods alerted by `network_scan_process_in_container` have their network isolated,
+/// both Ingress and Egress
+///
+/// Isolation creates or reconciles the `officer-quarantine` NetworkPolicy of the namespace, which selects
+/// `isolate: "true"` and only allows the egress of `QUARANTINE_EGRESS_ALLOW` and, with `QUARANTINE_ALLOW_DNS`, the cluster DNS.
+/// The response reports whether the policy exists and selects the pod
+/// 
+/// Example usage: Use this endpoint to isolate pod when threat is detected on a pod
+///
+/// With the `dry_run` query parameter the actions are only computed by the API server and the changes of the pod are returned
+///
+/// With `detach`, or an `isolate_network` action with `detach: true`, the labels used by the selectors of the pod's
+/// ReplicaSet or DaemonSet and of the Services selecting it are removed. The controller releases the pod and starts
+/// a clean replacement, the pod stays for investigation without receiving Service traffic. `unisolate-pod` puts
+/// the labels back
+///
+/// With `forensics` the pod spec and status, container logs, Events, owners and the Falco event are archived first,
+/// see `/forensics`
+///
+/// Accepts the JSON of Falco's HTTP output and of Falcosidekick's webhook output. Events without the namespace and pod
+/// in their output fields are rejected with 422
+pub async fn isolate_pod(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, query: Query<IsolatePodQuery>, payload: Json<FalcoEvent>) -> Result<Json<PlaybookResponse>, Error> {
+    let namespace = payload.namespace().unwrap_or_default();
+    let target = format!("Pod/{}", payload.pod_name().unwrap_or_default());
+    let mut audit = AuditEntry::new(&ctx, Action::IsolatePod, &namespace, target, json!(&*payload));
+    let result = respond(&ctx, &payload, &query, &mut audit).await;
+    audit.finish(&result).await;
+    result
 }
 
 #[api_v2_operation(tags("Kubernetes Security"))]
 /// Remove pod Isolation
 ///
 /// Allowing Ingress and Egress network connection
-/// 
-/// Requirement: Network policy that deny Ingress and Eggress with label selector isolate: "true" 
+///
+/// The `isolate` label is removed, the quarantine NetworkPolicy stays in place for other isolated pods
+///
+/// A detached pod gets back the labels kept in its `officer/detached-labels` annotation, except the ones that make a
+/// ReplicaSet or DaemonSet select it again: the controller would adopt it and delete a surplus pod, which may be the
+/// clean replacement. Those stay in the annotation and are only put back with `restore_controller_labels`
 /// 
 /// Example usage: Use this endpoint to isolate pod when threat is detected 
-pub async fn unisolate_pod(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<UnisolatePodPayload>) -> Result<Json<SuccessResponse>, Error> {
+///
+/// With `dry_run` the patch is only computed by the API server and the changes of the pod are returned
+pub async fn unisolate_pod(_: ApiKeyHeader,  _: AuthJwtHeader, ctx: ReqData<RequestContext>, payload: Json<UnisolatePodPayload>) -> Result<Json<ChangeResponse>, Error> {
+    let target = format!("Pod/{}", payload.pod_name);
+    let mut audit = AuditEntry::new(&ctx, Action::UnisolatePod, &payload.namespace, target, json!(&*payload));
+    let result = unisolate(&payload, &mut audit).await;
+    audit.finish(&result).await;
+    result
+}
+
+async fn unisolate(payload: &UnisolatePodPayload, audit: &mut AuditEntry) -> Result<Json<ChangeResponse>, Error> {
     let namespace = &payload.namespace;
     let pod_name = &payload.pod_name;
     // Interact with k8s
@@ -230,18 +926,54 @@ pub async fn unisolate_pod(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<Uni
         Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
     };
     // Create an API handle for Pod resources
-    let pods: Api<Pod> = Api::namespaced(client, namespace);
-    let patch = json!({
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let current = pods.get(pod_name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get pod failed: {}", e)))?;
+    audit.before(json!({ "labels": pod_labels(&current) }));
+    let mut patch = json!({
         "metadata": {
             "labels": {
-                "isolate": null
+                ISOLATE_LABEL: null
             }
         }
     });
+    // A detached pod gets back the labels it lost, the controller ones only when asked for
+    let mut restored = String::new();
+    if let Some(labels) = detached_labels(&current).map_err(ErrorInternalServerError)? {
+        let plan = match payload.restore_controller_labels {
+            true => Reattachment { labels, held_back: BTreeMap::new(), controllers: Vec::new() },
+            false => plan_reattachment(client.clone(), namespace, &current, labels).await
+                .map_err(ErrorInternalServerError)?,
+        };
+        for (key, value) in &plan.labels {
+            patch["metadata"]["labels"][key] = json!(value);
+        }
+        let held_back = match plan.held_back.is_empty() {
+            true => Value::Null,
+            false => json!(json!(plan.held_back).to_string()),
+        };
+        patch["metadata"]["annotations"] = json!({ DETACHED_ANNOTATION: held_back });
+        if !plan.labels.is_empty() {
+            restored = format!(", labels {} restored", plan.labels.keys().cloned().collect::<Vec<_>>().join(", "));
+        }
+        if !plan.held_back.is_empty() {
+            restored.push_str(&format!(", labels {} kept off so that {} does not adopt it",
+                plan.held_back.keys().cloned().collect::<Vec<_>>().join(", "), plan.controllers.join(", ")));
+        }
+    }
      // Apply the patch to the pod
-     let pp = PatchParams::apply("add-label-isolate");
+     let pp = patch_params("add-label-isolate", payload.dry_run);
      match pods.patch(pod_name, &pp, &Patch::Merge(&patch)).await {
-         Ok(_) => Ok(Json(SuccessResponse { status: "Pod is being freed".to_string() })),
+         Ok(pod) => {
+             audit.after(json!({ "labels": pod_labels(&pod) }));
+             if payload.dry_run {
+                 let status = format!("Pod {} would be freed{}", pod_name, restored);
+                 return Ok(Json(ChangeResponse { status, changes: Some(object_diff(&current, &pod)) }));
+             }
+             let note = format!("Network isolation removed by {}{}", audit.actor(), restored);
+             publish_event(client, &pod, EventType::Normal, "NetworkIsolationRemoved", "Unisolate", note).await;
+             Ok(Json(ChangeResponse { status: format!("Pod is being freed{}", restored), changes: None }))
+         },
          Err(e) => Err(ErrorInternalServerError(format!("Could not patch pod: {}", e)))
      }
 }
@@ -1,2 +1,7 @@
 pub mod kubernetes;
-pub mod gitlab_oauth2;
\ No newline at end of file
+pub mod canary;
+pub mod playbook;
+pub mod forensics;
+pub mod gitlab_oauth2;
+pub mod auth;
+pub mod audit;
\ No newline at end of file
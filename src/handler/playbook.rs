@@ -0,0 +1,307 @@
+use actix_web::{error::{ErrorInternalServerError, ErrorUnprocessableEntity}, http::StatusCode, Error};
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::{api::{DeleteParams, Patch}, runtime::events::EventType, Api, Client};
+use paperclip::actix::{api_v2_operation, web::Json};
+use log::error;
+use serde_json::{json, Value};
+use crate::{
+    config::get_forensics_capture,
+    handler::kubernetes::{error_with_body, patch_params, scale_workload},
+    model::{
+        auth::{ApiKeyHeader, AuthJwtHeader, RequestContext},
+        error::AuthError,
+        falco::FalcoEvent,
+        kubernetes::{DetachedPod, IsolatePodQuery, ScaleOperation, ScalePayload},
+        playbook::{
+            ActionOutcome, Playbook, PlaybookAction, PlaybookActionKind, PlaybookResponse, PlaybooksResponse,
+            ValidatePlaybooksPayload, ValidatePlaybooksResponse
+        },
+        rbac::Action},
+    util::{audit::AuditEntry, rbac::authorize, diff::object_diff, events::publish_event, forensics::capture_bundle, http::post_json,
+        quarantine::{ensure_quarantine_policy, plan_detachment, selects, DETACHED_ANNOTATION, ISOLATE_LABEL, QUARANTINE_POLICY},
+        playbook::{find_playbook, parse_playbooks, playbooks, playbooks_source}, workload::pod_owner}
+};
+
+// Field manager of the pod and node patches of playbooks
+const FIELD_MANAGER: &str = "falco-playbook";
+
+/// Response of a playbook to one Falco alert
+struct Run<'a> {
+    ctx: &'a RequestContext,
+    playbook: &'a Playbook,
+    event: &'a FalcoEvent,
+    namespace: &'a str,
+    pod_name: &'a str,
+    actor: String,
+    dry_run: bool,
+    detach: Option<bool>,
+}
+
+// Run the playbook matching the Falco event against the pod of the event
+pub(crate) async fn respond(ctx: &RequestContext, event: &FalcoEvent, query: &IsolatePodQuery, audit: &mut AuditEntry) -> Result<Json<PlaybookResponse>, Error> {
+    let dry_run = query.dry_run.unwrap_or(false);
+    let (namespace, pod_name) = event.kubernetes_context().map_err(ErrorUnprocessableEntity)?;
+    let (namespace, pod_name) = (namespace.as_str(), pod_name.as_str());
+    let playbook = match find_playbook(&playbooks(), event) {
+        Some(playbook) => playbook,
+        None => {
+            let changes = dry_run.then(Vec::new);
+            let status = format!("Skipped, no playbook responds to Falco rule {}", event.rule);
+            let response = PlaybookResponse {
+                status,
+                playbook: None,
+                actions: Vec::new(),
+                quarantine: None,
+                detached: None,
+                forensics: None,
+                forensics_error: None,
+                changes,
+            };
+            return Ok(Json(response));
+        },
+    };
+    // The caller picks the rule, so the playbook only runs what the caller could do directly
+    for action in &playbook.actions {
+        if let Some((permission, scope)) = action_permission(action.action, namespace) {
+            authorize(&ctx.principal, permission, scope).map_err(AuthError::InsufficientPermission)?;
+        }
+    }
+    let client = match Client::try_default().await {
+        Ok(c) => c,
+        Err(e) => return Err(ErrorInternalServerError(format!("Kubernetes connection failed: {}", e))),
+    };
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let pod = pods.get(pod_name).await
+        .map_err(|e| ErrorInternalServerError(format!("Get pod failed: {}", e)))?;
+    audit.before(json!({
+        "labels": pod.metadata.labels,
+        "annotations": pod.metadata.annotations,
+        "node": pod.spec.as_ref().and_then(|spec| spec.node_name.clone()),
+    }));
+
+    let run = Run {
+        ctx,
+        playbook: &playbook,
+        event,
+        namespace,
+        pod_name,
+        actor: audit.actor().to_string(),
+        dry_run,
+        detach: query.detach,
+    };
+    let mut response = PlaybookResponse {
+        status: String::new(),
+        playbook: Some(playbook.name.clone()),
+        actions: Vec::new(),
+        quarantine: None,
+        detached: None,
+        forensics: None,
+        forensics_error: None,
+        changes: dry_run.then(Vec::new),
+    };
+    // Captured first, before an action like delete_pod destroys the evidence
+    if !dry_run && query.forensics.unwrap_or(playbook.forensics || get_forensics_capture()) {
+        match capture_bundle(client.clone(), &pod, event, audit.actor()).await {
+            Ok(bundle) => response.forensics = Some(bundle),
+            Err(e) => {
+                error!("Forensic bundle of {}/{} could not be captured: {}", namespace, pod_name, e);
+                response.forensics_error = Some(e);
+            },
+        }
+    }
+    for action in &playbook.actions {
+        match run_action(client.clone(), &run, &pod, action, &mut response).await {
+            Ok(status) => response.actions.push(ActionOutcome { action: action.action, status }),
+            Err(e) => {
+                response.actions.push(ActionOutcome { action: action.action, status: format!("Failed: {}", e) });
+                response.status = format!("Playbook {} failed at {}: {}", playbook.name, action.action, e);
+                audit.after(json!({ "playbook": playbook.name, "actions": response.actions, "forensics": response.forensics }));
+                return Err(error_with_body(StatusCode::INTERNAL_SERVER_ERROR, response.status.clone(), &response));
+            },
+        }
+    }
+    audit.after(json!({ "playbook": playbook.name, "actions": response.actions, "forensics": response.forensics }));
+    let actions: Vec<&str> = playbook.actions.iter().map(|action| action.action.as_str()).collect();
+    if dry_run {
+        response.status = format!("Playbook {} would run {}", playbook.name, actions.join(", "));
+        return Ok(Json(response));
+    }
+    let note = format!("Playbook {} ran {} by {} after Falco rule {}", playbook.name, actions.join(", "), audit.actor(), run.event.rule);
+    publish_event(client, &pod, EventType::Warning, "PlaybookRan", "Respond", note).await;
+    response.status = format!("Playbook {} ran {}", playbook.name, actions.join(", "));
+    Ok(Json(response))
+}
+
+// Permission an action needs beyond `isolate-pod`, cordoning is checked cluster-wide since nodes are shared
+fn action_permission(kind: PlaybookActionKind, namespace: &str) -> Option<(Action, Option<&str>)> {
+    match kind {
+        PlaybookActionKind::DeletePod => Some((Action::DeletePod, Some(namespace))),
+        PlaybookActionKind::ScaleToZero => Some((Action::Scale, Some(namespace))),
+        PlaybookActionKind::CordonNode => Some((Action::CordonNode, None)),
+        _ => None,
+    }
+}
+
+// Run one action, the result describes what was done
+async fn run_action(client: Client, run: &Run<'_>, pod: &Pod, action: &PlaybookAction, response: &mut PlaybookResponse) -> Result<String, String> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), run.namespace);
+    let (pod_name, dry_run, actor) = (run.pod_name, run.dry_run, &run.actor);
+    match action.action {
+        PlaybookActionKind::IsolateNetwork => isolate_network(client, run, pod, action, response).await,
+        PlaybookActionKind::Label | PlaybookActionKind::Annotate => {
+            let patch = match action.action {
+                PlaybookActionKind::Label => json!({ "metadata": { "labels": action.labels } }),
+                _ => json!({ "metadata": { "annotations": action.annotations } }),
+            };
+            let patched = pods.patch(pod_name, &patch_params(FIELD_MANAGER, dry_run), &Patch::Merge(&patch)).await
+                .map_err(|e| format!("Could not patch pod: {}", e))?;
+            if let Some(changes) = response.changes.as_mut() {
+                changes.extend(object_diff(pod, &patched));
+                return Ok(format!("Pod {} would be patched", pod_name));
+            }
+            Ok(format!("Pod {} patched", pod_name))
+        },
+        PlaybookActionKind::CordonNode => {
+            let node_name = pod.spec.as_ref().and_then(|spec| spec.node_name.clone())
+                .ok_or_else(|| format!("Pod {} is not scheduled on a node", pod_name))?;
+            let nodes: Api<Node> = Api::all(client.clone());
+            let patch = json!({ "spec": { "unschedulable": true } });
+            let node = nodes.patch(&node_name, &patch_params(FIELD_MANAGER, dry_run), &Patch::Merge(&patch)).await
+                .map_err(|e| format!("Could not cordon node {}: {}", node_name, e))?;
+            if dry_run {
+                return Ok(format!("Node {} would be cordoned", node_name));
+            }
+            let note = format!("Cordoned by {} after Falco rule {} on pod {}/{}", actor, run.event.rule, run.namespace, pod_name);
+            publish_event(client, &node, EventType::Warning, "Cordoned", "Cordon", note).await;
+            Ok(format!("Node {} cordoned", node_name))
+        },
+        PlaybookActionKind::DeletePod => {
+            let params = DeleteParams { dry_run, grace_period_seconds: action.grace_period_seconds, ..Default::default() };
+            pods.delete(pod_name, &params).await
+                .map_err(|e| format!("Could not delete pod: {}", e))?;
+            if dry_run {
+                return Ok(format!("Pod {} would be deleted", pod_name));
+            }
+            Ok(format!("Pod {} deleted", pod_name))
+        },
+        PlaybookActionKind::ScaleToZero => {
+            let (kind, name) = pod_owner(client, run.namespace, pod).await?
+                .ok_or_else(|| format!("Pod {} is not managed by a workload that can be scaled", pod_name))?;
+            if dry_run {
+                return Ok(format!("{} {} would be scaled to zero", kind, name));
+            }
+            let payload = ScalePayload {
+                namespace: run.namespace.to_string(),
+                service_deployment: name.clone(),
+                kind,
+                operation: ScaleOperation::Zero,
+                replicas: None,
+                suspend_autoscaler: false,
+            };
+            // Audited on its own so `/scale` finds what to restore
+            let mut scale_audit = AuditEntry::new(run.ctx, Action::Scale, run.namespace, format!("{}/{}", kind, name), json!(payload));
+            let result = scale_workload(&payload, &mut scale_audit).await;
+            scale_audit.finish(&result).await;
+            let scaled = result.map_err(|e| e.to_string())?;
+            Ok(scaled.status.clone())
+        },
+        PlaybookActionKind::Notify => {
+            let url = action.url.as_deref().unwrap_or_default();
+            if dry_run {
+                return Ok(format!("{} would be notified", url));
+            }
+            let notification = json!({
+                "playbook": run.playbook.name,
+                "rule": run.event.rule,
+                "priority": run.event.priority,
+                "output": run.event.output,
+                "namespace": run.namespace,
+                "pod": pod_name,
+                "actor": actor,
+                "actions": response.actions,
+            });
+            post_json(url, &notification).await?;
+            Ok(format!("{} notified", url))
+        },
+    }
+}
+
+// What a detached pod was taken out of, for statuses and Events
+fn detachment_note(detached: &DetachedPod) -> String {
+    let from: Vec<&str> = detached.controller.iter().chain(&detached.services).map(String::as_str).collect();
+    let mut note = match from.is_empty() {
+        true => "no controller or Service selects it".to_string(),
+        false => format!("detached from {}", from.join(", ")),
+    };
+    if let Some(kept_by) = &detached.kept_by {
+        note.push_str(&format!(", {} keeps it", kept_by));
+    }
+    note
+}
+
+// Label the pod once the quarantine NetworkPolicy of its namespace is in place.
+// Fails when the policy does not select the labelled pod, the pod would not be isolated.
+async fn isolate_network(client: Client, run: &Run<'_>, pod: &Pod, action: &PlaybookAction, response: &mut PlaybookResponse) -> Result<String, String> {
+    let (policy, mut quarantine) = ensure_quarantine_policy(client.clone(), run.namespace, run.dry_run).await?;
+    let detached = match run.detach.unwrap_or(action.detach) {
+        true => Some(plan_detachment(client.clone(), run.namespace, pod).await?),
+        false => None,
+    };
+    let pods: Api<Pod> = Api::namespaced(client.clone(), run.namespace);
+    let mut patch = json!({ "metadata": { "labels": { ISOLATE_LABEL: "true" } } });
+    // The labels leave the selectors in the same patch that isolates the pod, the originals are kept for unisolate
+    if let Some(detached) = detached.as_ref().filter(|detached| !detached.labels.is_empty()) {
+        for key in detached.labels.keys() {
+            patch["metadata"]["labels"][key] = Value::Null;
+        }
+        patch["metadata"]["annotations"] = json!({ DETACHED_ANNOTATION: json!(detached.labels).to_string() });
+    }
+    let patched = pods.patch(run.pod_name, &patch_params(FIELD_MANAGER, run.dry_run), &Patch::Merge(&patch)).await
+        .map_err(|e| format!("Could not patch pod: {}", e))?;
+    let labels = patched.metadata.labels.clone().unwrap_or_default();
+    quarantine.selects_pod = policy.as_ref()
+        .and_then(|policy| policy.spec.as_ref())
+        .is_some_and(|spec| selects(&spec.pod_selector, &labels));
+    let (state, selected) = (quarantine.state.clone(), quarantine.selects_pod);
+    response.quarantine = Some(quarantine);
+    let detachment = detached.as_ref().map(|detached| format!(", {}", detachment_note(detached))).unwrap_or_default();
+    response.detached = detached;
+    if !selected {
+        return Err(format!("Pod {} is labelled but not isolated, NetworkPolicy {} is {} or does not select it", run.pod_name, QUARANTINE_POLICY, state));
+    }
+    if let Some(changes) = response.changes.as_mut() {
+        changes.extend(object_diff(pod, &patched));
+        return Ok(format!("Pod {} would be isolated, NetworkPolicy {} would be {}{}", run.pod_name, QUARANTINE_POLICY, state, detachment));
+    }
+    let note = format!("Network isolated by {} after Falco rule {}{}", run.actor, run.event.rule, detachment);
+    publish_event(client, &patched, EventType::Warning, "NetworkIsolated", "Isolate", note).await;
+    Ok(format!("Pod {} isolated, NetworkPolicy {} {}{}", run.pod_name, QUARANTINE_POLICY, state, detachment))
+}
+
+#[api_v2_operation(tags("Kubernetes Security"))]
+/// Falco playbooks
+///
+/// List the playbooks `isolate-pod` responds to Falco alerts with, from `PLAYBOOKS_FILE` or the built-in
+/// network scan playbook. The file is reloaded when it changes, an invalid change is reported in `reload_error`
+/// while the previous playbooks stay in use
+pub async fn get_playbooks(_: ApiKeyHeader,  _: AuthJwtHeader) -> Result<Json<PlaybooksResponse>, Error> {
+    let (file, reload_error) = playbooks_source();
+    Ok(Json(PlaybooksResponse { file, reload_error, playbooks: playbooks().to_vec() }))
+}
+
+#[api_v2_operation(tags("Kubernetes Security"))]
+/// Validate Falco playbooks
+///
+/// Check a playbook file before deploying it, or the loaded playbooks when `content` is not given.
+/// With `alert` the playbook that would respond to that Falco event is returned, nothing is run
+pub async fn validate_playbooks(_: ApiKeyHeader,  _: AuthJwtHeader, payload: Json<ValidatePlaybooksPayload>) -> Result<Json<ValidatePlaybooksResponse>, Error> {
+    let candidates = match &payload.content {
+        Some(content) => match parse_playbooks(content) {
+            Ok(playbooks) => playbooks,
+            Err(e) => return Ok(Json(ValidatePlaybooksResponse { valid: false, error: Some(e), matched: None })),
+        },
+        None => playbooks().to_vec(),
+    };
+    let matched = payload.alert.as_ref().and_then(|event| find_playbook(&candidates, event));
+    Ok(Json(ValidatePlaybooksResponse { valid: true, error: None, matched }))
+}
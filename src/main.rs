@@ -2,7 +2,6 @@ use actix_session::{SessionMiddleware, storage::CookieSessionStore};
 use actix_web::{middleware::{from_fn, Logger}, web as actweb, App, HttpResponse, HttpServer, Responder};
 use paperclip::{actix::{web::{self}, OpenApiExt}, v2::models::{DefaultApiRaw, Info}};
 use middleware::auth::auth_middleware;
-use env_logger;
 use dotenv::dotenv;
 use config::{get_envar, get_officer_secret_key};
 
@@ -11,6 +10,7 @@ mod handler;
 mod config;
 mod model;
 mod util;
+mod provider;
 
 async fn healthz() -> impl Responder {
     HttpResponse::Ok().body("ok")
@@ -22,30 +22,40 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
     let required_vars = [
-        "API_KEY",
         "RUST_LOG",
-        "USERS",
-        "OAUTH2_GITLAB_URL",
-        "OAUTH2_GITLAB_CLIENT_ID",
-        "OAUTH2_GITLAB_CLIENT_SECRET",
-        "OAUTH2_REDIRECT_URL",
         "OFFICER_SECRET_KEY"
         ];
     // Check each required environment variable
     for &var in required_vars.iter() {
         let _value = get_envar(var);
     }
+    let initialized = util::rbac::init_policy()
+        .and_then(|_| util::api_key::init_key_store())
+        .and_then(|_| provider::init_providers())
+        .and_then(|_| util::trusted_issuer::init_trusted_issuers())
+        .and_then(|_| util::token_review::init_token_review())
+        .and_then(|_| util::audit::init_audit())
+        .and_then(|_| util::scale::init_scale_bounds())
+        .and_then(|_| util::playbook::init_playbooks())
+        .and_then(|_| util::forensics::init_forensics());
+    if let Err(e) = initialized {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
+    }
+    actix_web::rt::spawn(handler::canary::resume_canaries());
     // end of initialize
     HttpServer::new(move || {
         // Setup header swagger
         let mut spec = DefaultApiRaw::default();
         const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
         let app_version = format!("v{}", PKG_VERSION);
+        let sign_in_links: Vec<String> = provider::provider_names().iter()
+            .map(|name| format!("<a href=\"/auth/{}/login\" target=\"_blank\">Sign in with {}</a>", name, name))
+            .collect();
         spec.info = Info {
-            version: app_version.into(),
+            version: app_version,
             title: "Officer".into(),
-            description: "<b>At your service, Sir!</b> <br><br>\
-            <a href=\"/gitlab/auth\" target=\"_blank\">Sign in with GitLab</a>".to_string().into(),
+            description: format!("<b>At your service, Sir!</b> <br><br>{}", sign_in_links.join("<br>")).into(),
             ..Default::default()
         };
         // End of setup header swagger
@@ -60,11 +70,8 @@ async fn main() -> std::io::Result<()> {
         )
         .route("/gitlab/auth", actweb::get().to(handler::gitlab_oauth2::oauth_login))
         .route("/gitlab/callback", actweb::get().to(handler::gitlab_oauth2::oauth_callback))
-        .service(
-            web::resource("/isolate-pod")
-                .wrap(from_fn(auth_middleware))
-                .route(web::post().to(handler::kubernetes::isolate_pod))
-        )
+        .route("/auth/{provider}/login", actweb::get().to(handler::auth::login))
+        .route("/auth/{provider}/callback", actweb::get().to(handler::auth::callback))
         // Record services and routes from this line.
         .wrap_api_with_spec(spec)
         .wrap(Logger::default())
@@ -79,11 +86,95 @@ async fn main() -> std::io::Result<()> {
                 .wrap(from_fn(auth_middleware))
                 .route(web::get().to(handler::kubernetes::get_pod))
         )
+        .service(
+            web::resource("/deploy-batch")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::kubernetes::deploy_batch))
+        )
+        .service(
+            web::resource("/rollout-status")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::kubernetes::get_rollout_status))
+        )
+        .service(
+            web::resource("/rollback-deployment")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::kubernetes::rollback_deployment))
+        )
+        .service(
+            web::resource("/deployments/{namespace}/{name}/history")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::kubernetes::get_deployment_history))
+        )
+        .service(
+            web::resource("/canaries")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::canary::get_canaries))
+        )
+        .service(
+            web::resource("/canary/promote")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::canary::promote_canary))
+        )
+        .service(
+            web::resource("/canary/abort")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::canary::abort_canary))
+        )
+        .service(
+            web::resource("/scale")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::kubernetes::scale))
+        )
+        .service(
+            web::resource("/playbooks")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::playbook::get_playbooks))
+        )
+        .service(
+            web::resource("/playbooks/validate")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::playbook::validate_playbooks))
+        )
+        .service(
+            web::resource("/isolate-pod")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::kubernetes::isolate_pod))
+        )
+        .service(
+            web::resource("/forensics")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::forensics::get_forensics))
+        )
+        .service(
+            web::resource("/forensics/{namespace}/{id}")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::forensics::download_forensics))
+        )
         .service(
             web::resource("/unisolate-pod")
                 .wrap(from_fn(auth_middleware))
                 .route(web::post().to(handler::kubernetes::unisolate_pod))
         )
+        .service(
+            web::resource("/audit")
+                .wrap(from_fn(auth_middleware))
+                .route(web::get().to(handler::audit::get_audit))
+        )
+        .service(
+            web::resource("/auth/refresh")
+                .route(web::post().to(handler::auth::refresh))
+        )
+        .service(
+            web::resource("/auth/revoke")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::auth::revoke))
+        )
+        .service(
+            web::resource("/auth/logout")
+                .wrap(from_fn(auth_middleware))
+                .route(web::post().to(handler::auth::logout))
+        )
         .service(
             web::resource("/restart-service-deployment")
                 .wrap(from_fn(auth_middleware))
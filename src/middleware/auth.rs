@@ -1,49 +1,150 @@
+use std::{collections::HashMap, pin::Pin};
 use actix_web::{
-    body::MessageBody, dev::{ServiceRequest, ServiceResponse}, Error
+    body::MessageBody, dev::{ServiceRequest, ServiceResponse}, error::PayloadError, http::header::{HeaderName, HeaderValue}, web::{Bytes, Query}, Error, HttpMessage
 };
-use log::info;
+use futures::{stream, Stream};
+use log::{info, warn};
+use serde_json::Value;
+use uuid::Uuid;
 // use actix_web_lab::middleware::Next;
-use crate::{config::get_api_key, model::auth::{ApiKeyHeader, AuthJwtHeader}, util::jwt::validate_token};
+use crate::{
+    config::get_token_review_enabled,
+    model::{auth::{ApiKeyHeader, AuthJwtHeader, AuthMethod, Principal, RequestContext}, error::AuthError, falco::FalcoEvent, rbac::Action},
+    util::{api_key::verify_api_key, audit::{record_denial, Denial}, jwt::validate_token, rbac::authorize, token_review::review_token, trusted_issuer::{is_external_token, is_trusted_issuer, validate_external_token}}
+};
 
 use actix_web::middleware::Next;
 
+const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
 pub async fn auth_middleware(
     api_key_header: ApiKeyHeader,
     auth_jwt_header: AuthJwtHeader,
-    req: ServiceRequest,
+    mut req: ServiceRequest,
     next: Next<impl MessageBody>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
     // pre-processing
-    let api_key_env = get_api_key();
-    let api_key = api_key_header.0.as_str();
-    if api_key.is_empty() {
-        let jwt = auth_jwt_header.0.as_str();
-        let res = next.call(req).await?;
-
-        // Check if the header starts with "Bearer " and extract the token
-        let token = if jwt.starts_with("Bearer ") {
-            &jwt["Bearer ".len()..]
-        } else {
-            return Err(actix_web::error::ErrorUnauthorized("Invalid Token!")); // Handle the error case
-        };
-        match validate_token(token) {
-            Ok(token) => {
-                info!("User: {}", token.claims.sub);
-                Ok(res)
-            },
-            Err(_) => Err(actix_web::error::ErrorUnauthorized("Invalid API key")),
+    let correlation_id = correlation_id(&req);
+    let source_ip = req.connection_info().realip_remote_addr().map(str::to_string);
+    let principal = match authenticate(&api_key_header, &auth_jwt_header).await {
+        Ok(principal) => principal,
+        Err(e) => {
+            info!("Unauthenticated request to {}: {}", req.path(), e);
+            record_denial(Denial {
+                principal: None,
+                correlation_id: &correlation_id,
+                source_ip,
+                action: Action::from_path(req.path()),
+                namespace: None,
+                path: req.path(),
+                error: e.to_string(),
+            }).await;
+            return Err(e.into());
         }
-    } else {
-        // Check API key
-        if api_key != api_key_env {
-            return Err(actix_web::error::ErrorUnauthorized("Invalid API key")); // Handle the error case
+    };
+    info!("User: {} ({:?})", principal.subject, principal.auth_method);
+
+    // Authorization has to be done before the handler touches the cluster
+    if let Some(action) = Action::from_path(req.path()) {
+        let namespace = target_namespace(&mut req, action).await?;
+        if let Err(reason) = authorize(&principal, action, namespace.as_deref()) {
+            warn!("Forbidden: {}", reason);
+            record_denial(Denial {
+                principal: Some(&principal),
+                correlation_id: &correlation_id,
+                source_ip,
+                action: Some(action),
+                namespace,
+                path: req.path(),
+                error: reason.clone(),
+            }).await;
+            return Err(AuthError::InsufficientPermission(reason).into());
         }
-        // invoke the wrapped middleware or service
-        let res = next.call(req).await?;
+    }
+    req.extensions_mut().insert(RequestContext { principal, correlation_id: correlation_id.clone(), source_ip });
 
-        // post-processing
+    // invoke the wrapped middleware or service
+    let mut res = next.call(req).await?;
 
-        Ok(res)
+    // post-processing
+    if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+        res.headers_mut().insert(HeaderName::from_static(CORRELATION_ID_HEADER), value);
     }
-    
-}
\ No newline at end of file
+    Ok(res)
+}
+
+// Reuse the caller's correlation id when it looks sane, otherwise generate one
+fn correlation_id(req: &ServiceRequest) -> String {
+    req.headers().get(CORRELATION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+async fn authenticate(api_key_header: &ApiKeyHeader, auth_jwt_header: &AuthJwtHeader) -> Result<Principal, AuthError> {
+    let api_key = api_key_header.0.as_str();
+    if !api_key.is_empty() {
+        // Check API key
+        return verify_api_key(api_key).ok_or_else(|| AuthError::UnknownKey("Unknown API key".to_string()));
+    }
+    let jwt = auth_jwt_header.0.as_str();
+    if jwt.is_empty() {
+        return Err(AuthError::MissingCredentials);
+    }
+    // Check if the header starts with "Bearer " and extract the token
+    let token = jwt.strip_prefix("Bearer ")
+        .ok_or_else(|| AuthError::InvalidToken("Authorization header must use the Bearer scheme".to_string()))?;
+    if is_external_token(token) {
+        return if is_trusted_issuer(token) {
+            validate_external_token(token).await
+        } else if get_token_review_enabled() {
+            review_token(token).await
+        } else {
+            Err(AuthError::InvalidToken("Token issuer is not trusted".to_string()))
+        };
+    }
+    let token = validate_token(token)?;
+    Ok(Principal {
+        subject: token.claims.sub,
+        membership: token.claims.membership,
+        auth_method: AuthMethod::Jwt,
+        scope: None,
+    })
+}
+
+// Find the namespace targeted by the request, either in the path, the query string or the JSON body.
+// A Falco event is authorized against the namespace the playbook acts on, never against a top-level key.
+// A namespace in both the query string and the body must be the same, the handler only reads one of them.
+// The body is buffered and put back so the handler can still read it.
+async fn target_namespace(req: &mut ServiceRequest, action: Action) -> Result<Option<String>, AuthError> {
+    if let Some(namespace) = req.match_info().get("namespace") {
+        return Ok(Some(namespace.to_string()));
+    }
+    let body = buffer_body(req).await?;
+    if action == Action::IsolatePod {
+        return Ok(serde_json::from_slice::<FalcoEvent>(&body).ok().and_then(|event| event.namespace()));
+    }
+    let query = Query::<HashMap<String, String>>::from_query(req.query_string()).ok()
+        .and_then(|query| query.get("namespace").cloned());
+    let payload = serde_json::from_slice::<Value>(&body).ok().and_then(|payload| {
+        payload.get("namespace").and_then(Value::as_str).map(str::to_string)
+    });
+    match (query, payload) {
+        (Some(query), Some(payload)) if query != payload => Err(AuthError::BadRequest(format!(
+            "Namespace {} of the query string does not match namespace {} of the body", query, payload
+        ))),
+        (query, payload) => Ok(query.or(payload)),
+    }
+}
+
+// Read the whole request body and put it back for the handler
+async fn buffer_body(req: &mut ServiceRequest) -> Result<Bytes, AuthError> {
+    let body = req.extract::<Bytes>().await
+        .map_err(|e| AuthError::BadRequest(format!("Could not read the request body: {}", e)))?;
+    let replay = body.clone();
+    let payload: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(stream::once(async move { Ok(replay) }));
+    req.set_payload(payload.into());
+    Ok(body)
+}
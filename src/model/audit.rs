@@ -0,0 +1,49 @@
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// One mutating action, as written to the audit sinks
+///
+/// Records form a hash chain: `hash` covers the record and `prev_hash`,
+/// so a removed or edited line breaks the chain of every following record.
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone, Debug)]
+pub struct AuditRecord {
+    pub id: String,
+    /// RFC 3339 timestamp
+    pub time: String,
+    pub correlation_id: String,
+    pub actor: String,
+    pub auth_method: String,
+    pub source_ip: Option<String>,
+    pub action: String,
+    pub namespace: String,
+    /// Kind and name of the object, e.g. "Deployment/api"
+    pub target: String,
+    /// Every object of a change to several objects, e.g. a batch deployment
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    pub request: Value,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    /// "success" or "failure", "denied" for requests refused by authentication or authorization
+    pub result: String,
+    pub response: Option<Value>,
+    pub error: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct AuditQuery {
+    /// Caller identity
+    pub actor: Option<String>,
+    pub namespace: Option<String>,
+    /// Kind and name of the object, e.g. "Deployment/api", also matches changes to several objects including it
+    pub target: Option<String>,
+    /// Only records at or after this RFC 3339 time
+    pub since: Option<String>,
+    /// Only records at or before this RFC 3339 time
+    pub until: Option<String>,
+    /// Maximum number of records, the most recent are returned (default 100)
+    pub limit: Option<usize>,
+}
@@ -1,6 +1,10 @@
+use std::collections::BTreeMap;
 use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
 use futures::future::{ready, Ready};
-use paperclip::actix::Apiv2Security;
+use paperclip::actix::{Apiv2Schema, Apiv2Security};
+use serde::{Deserialize, Serialize};
+
+use super::rbac::Scope;
 // Swagger Auth
 #[derive(Apiv2Security)]
 #[openapi(
@@ -51,4 +55,136 @@ impl FromRequest for AuthJwtHeader {
         // If the header is not present or not valid, return an error
         ready(Ok(AuthJwtHeader("".to_owned())))
     }
-}
\ No newline at end of file
+}
+/// How the caller proved its identity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+    /// Token signed by a trusted external issuer (GitLab CI id_token, projected service account token, ...)
+    ExternalJwt,
+    /// Kubernetes service account token checked with a TokenReview
+    ServiceAccount,
+    /// Officer itself, e.g. resuming the canaries in flight at startup
+    System,
+}
+
+/// Groups of a user as reported by the identity provider
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Membership {
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// GitLab access level (10 guest to 50 owner) per group or project path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub access_levels: BTreeMap<String, u8>,
+}
+
+impl AuthMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthMethod::ApiKey => "api-key",
+            AuthMethod::Jwt => "jwt",
+            AuthMethod::ExternalJwt => "external-jwt",
+            AuthMethod::ServiceAccount => "service-account",
+            AuthMethod::System => "system",
+        }
+    }
+}
+
+/// Authenticated caller
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub subject: String,
+    pub membership: Membership,
+    pub auth_method: AuthMethod,
+    /// Limits of the credential, replaces the RBAC bindings when set
+    pub scope: Option<Scope>,
+}
+
+/// Caller and origin of a request, stored in the request extensions by `auth_middleware`
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub principal: Principal,
+    /// Taken from the `X-Correlation-Id` header or generated, echoed in the response
+    pub correlation_id: String,
+    pub source_ip: Option<String>,
+}
+
+/// Entry of the API key store in `API_KEYS_FILE`
+///
+/// Example:
+/// ```yaml
+/// - name: falco-sidekick
+///   sha256: 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+///   endpoints: ["isolate-pod"]
+/// - name: jenkins-prod
+///   sha256: 60303ae22b998861bce3b28f33eec1be758a213c86c93c076dbe9f558c11c752
+///   endpoints: ["deploy-service", "restart-service-deployment", "get-pod"]
+///   namespaces: ["production"]
+/// ```
+#[derive(Deserialize, Debug)]
+pub struct ApiKeyEntry {
+    /// Identity of the caller in logs and RBAC
+    pub name: String,
+    /// Hex encoded SHA-256 of the key, e.g. `echo -n "$KEY" | sha256sum`
+    pub sha256: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema, Debug)]
+pub struct TokenResponse {
+    /// Access token, send it as "Authorization: Bearer <token>"
+    pub token: String,
+    /// Single use token to get a new token pair from /auth/refresh
+    pub refresh_token: String,
+    /// Lifetime of the access token in seconds
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct RefreshTokenPayload {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct RevokeTokenPayload {
+    /// Access or refresh token, every token of its login session gets revoked
+    pub token: String,
+}
+
+/// Issuer whose tokens are accepted as bearer tokens, entry of `TRUSTED_ISSUERS_FILE`
+///
+/// Example:
+/// ```yaml
+/// - name: gitlab-ci
+///   issuer: https://gitlab.example.com
+///   audiences: ["https://officer.example.com"]
+///   groups_claims: ["namespace_path", "project_path"]
+/// - name: cluster
+///   issuer: https://kubernetes.default.svc.cluster.local
+///   jwks_uri: https://kubernetes.default.svc/openid/v1/jwks
+///   audiences: ["officer"]
+/// ```
+#[derive(Deserialize, Debug)]
+pub struct TrustedIssuerConfig {
+    /// Prefix of the identity and groups of callers, bindings refer to them as `<name>:<value>` so that
+    /// they can not be mistaken for GitLab groups or users of another issuer
+    pub name: String,
+    /// Expected `iss` claim
+    pub issuer: String,
+    /// Key set location, discovered from `<issuer>/.well-known/openid-configuration` when omitted
+    pub jwks_uri: Option<String>,
+    /// Accepted `aud` values, at least one is required so tokens minted for other services are refused
+    pub audiences: Vec<String>,
+    /// Claim used as the caller identity
+    #[serde(default = "default_subject_claim")]
+    pub subject_claim: String,
+    /// Claims whose values become groups of the caller
+    #[serde(default)]
+    pub groups_claims: Vec<String>,
+}
+
+fn default_subject_claim() -> String {
+    "sub".to_string()
+}
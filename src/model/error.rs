@@ -0,0 +1,144 @@
+use std::fmt;
+use actix_web::{http::{header::{self, HeaderValue}, StatusCode}, HttpResponse, ResponseError};
+use jsonwebtoken::errors::ErrorKind;
+use serde::Serialize;
+
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Problem document (RFC 7807) returned when a request is not authenticated, not allowed or can not be authorized
+#[derive(Serialize, Debug)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub title: String,
+    pub status: u16,
+    pub code: String,
+    pub detail: String,
+}
+
+/// Why the caller was rejected by the auth middleware
+#[derive(Debug)]
+pub enum AuthError {
+    MissingCredentials,
+    ExpiredToken,
+    BadSignature,
+    // API key or token signing key that is not known
+    UnknownKey(String),
+    // Malformed, revoked or otherwise unacceptable token
+    InvalidToken(String),
+    InsufficientPermission(String),
+    // Token issuer, its key set or the TokenReview API could not be reached
+    Unavailable(String),
+    // Request whose target namespace can not be determined, e.g. a body that can not be read
+    BadRequest(String),
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingCredentials => "missing-credentials",
+            AuthError::ExpiredToken => "expired-token",
+            AuthError::BadSignature => "bad-signature",
+            AuthError::UnknownKey(_) => "unknown-key",
+            AuthError::InvalidToken(_) => "invalid-token",
+            AuthError::InsufficientPermission(_) => "insufficient-permission",
+            AuthError::Unavailable(_) => "issuer-unavailable",
+            AuthError::BadRequest(_) => "invalid-request",
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let status = self.status_code();
+        Problem {
+            type_: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            code: self.code().to_string(),
+            detail: self.to_string(),
+        }
+    }
+
+    // Challenge sent with 401 responses (RFC 6750)
+    fn challenge(&self) -> String {
+        match self {
+            AuthError::MissingCredentials => "Bearer realm=\"officer\"".to_string(),
+            other => format!(
+                "Bearer realm=\"officer\", error=\"invalid_token\", error_description=\"{}\"",
+                other.code()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "No API key or bearer token was provided"),
+            AuthError::ExpiredToken => write!(f, "Token has expired"),
+            AuthError::BadSignature => write!(f, "Token signature is invalid"),
+            AuthError::UnknownKey(detail) => write!(f, "{}", detail),
+            AuthError::InvalidToken(detail) => write!(f, "{}", detail),
+            AuthError::InsufficientPermission(detail) => write!(f, "{}", detail),
+            AuthError::Unavailable(detail) => write!(f, "{}", detail),
+            AuthError::BadRequest(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AuthError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ExpiredSignature => AuthError::ExpiredToken,
+            ErrorKind::InvalidSignature => AuthError::BadSignature,
+            ErrorKind::InvalidToken => AuthError::InvalidToken("Token is malformed or revoked".to_string()),
+            _ => AuthError::InvalidToken(format!("Invalid token: {}", e)),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::InsufficientPermission(_) => StatusCode::FORBIDDEN,
+            AuthError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        response.content_type(PROBLEM_CONTENT_TYPE);
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            if let Ok(challenge) = HeaderValue::from_str(&self.challenge()) {
+                response.insert_header((header::WWW_AUTHENTICATE, challenge));
+            }
+        }
+        response.json(self.problem())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unavailable_issuer_is_not_a_challenge() {
+        let response = AuthError::Unavailable("down".to_string()).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get(header::WWW_AUTHENTICATE).is_none());
+        let response = AuthError::UnknownKey("kid".to_string()).error_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn bad_request_is_a_problem() {
+        let error = AuthError::BadRequest("namespaces differ".to_string());
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_CONTENT_TYPE);
+        assert!(response.headers().get(header::WWW_AUTHENTICATE).is_none());
+        let problem = error.problem();
+        assert_eq!((problem.status, problem.code.as_str(), problem.detail.as_str()), (400, "invalid-request", "namespaces differ"));
+    }
+}
@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+// Output fields holding the namespace and pod of an event: Falco before 0.37, the k8smeta plugin,
+// then the labels the container runtime sets on every container of a pod
+const NAMESPACE_FIELDS: [&str; 3] = ["k8s.ns.name", "k8smeta.ns.name", "container.label.io.kubernetes.pod.namespace"];
+const POD_FIELDS: [&str; 3] = ["k8s.pod.name", "k8smeta.pod.name", "container.label.io.kubernetes.pod.name"];
+
+/// Alert sent by Falco's HTTP output (with `json_output`) or by Falcosidekick's webhook output
+#[derive(Serialize, Deserialize, Apiv2Schema, Debug, Clone)]
+pub struct FalcoEvent {
+    /// Id Falcosidekick gives to every event
+    pub uuid: Option<String>,
+    pub rule: String,
+    pub priority: FalcoPriority,
+    /// Time of the event, RFC 3339
+    pub time: Option<String>,
+    /// Event source, e.g. `syscall` or `k8s_audit`
+    pub source: Option<String>,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub tags: Vec<String>,
+    /// Host Falco runs on
+    pub hostname: Option<String>,
+    pub output: Option<String>,
+    /// Fields of the rule output, e.g. `k8s.ns.name` and `k8s.pod.name`
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub output_fields: BTreeMap<String, Value>,
+}
+
+// Falcosidekick sends `null` for an empty list or map
+fn null_as_empty<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl FalcoEvent {
+    /// String value of an output field, other values as their JSON text
+    pub fn field(&self, name: &str) -> Option<String> {
+        match self.output_fields.get(name)? {
+            Value::Null => None,
+            Value::String(value) => Some(value.clone()),
+            value => Some(value.to_string()),
+        }
+    }
+
+    fn first_field(&self, names: &[&str]) -> Option<String> {
+        names.iter().find_map(|name| self.field(name).filter(|value| !value.is_empty() && value != "<NA>"))
+    }
+
+    pub fn namespace(&self) -> Option<String> {
+        self.first_field(&NAMESPACE_FIELDS)
+    }
+
+    pub fn pod_name(&self) -> Option<String> {
+        self.first_field(&POD_FIELDS)
+    }
+
+    /// Namespace and pod of the event, the error explains what is missing
+    pub fn kubernetes_context(&self) -> Result<(String, String), String> {
+        match (self.namespace(), self.pod_name()) {
+            (Some(namespace), Some(pod)) => Ok((namespace, pod)),
+            _ => Err(format!(
+                "Falco event of rule {} has no Kubernetes context, one of the output fields {} and {} is required",
+                self.rule,
+                NAMESPACE_FIELDS.join(", "),
+                POD_FIELDS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Falco priorities, from the least to the most severe
+#[derive(Serialize, Deserialize, Apiv2Schema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase", try_from = "String")]
+pub enum FalcoPriority {
+    Debug,
+    Informational,
+    Notice,
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl FalcoPriority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FalcoPriority::Debug => "debug",
+            FalcoPriority::Informational => "informational",
+            FalcoPriority::Notice => "notice",
+            FalcoPriority::Warning => "warning",
+            FalcoPriority::Error => "error",
+            FalcoPriority::Critical => "critical",
+            FalcoPriority::Alert => "alert",
+            FalcoPriority::Emergency => "emergency",
+        }
+    }
+}
+
+// Falco writes priorities capitalized, Falcosidekick and playbook files in lowercase
+impl FromStr for FalcoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "debug" => Ok(FalcoPriority::Debug),
+            "informational" | "info" => Ok(FalcoPriority::Informational),
+            "notice" => Ok(FalcoPriority::Notice),
+            "warning" => Ok(FalcoPriority::Warning),
+            "error" => Ok(FalcoPriority::Error),
+            "critical" => Ok(FalcoPriority::Critical),
+            "alert" => Ok(FalcoPriority::Alert),
+            "emergency" => Ok(FalcoPriority::Emergency),
+            _ => Err(format!("Unknown Falco priority: {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FalcoPriority {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for FalcoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
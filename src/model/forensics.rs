@@ -0,0 +1,23 @@
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+/// Forensic bundle of a pod, a gzipped tar archive with `summary.json`, `event.json`, `pod.json`,
+/// `events.json` and `logs/<container>[.previous].log`
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone)]
+pub struct ForensicBundle {
+    /// `<pod>-<capture time>-<random suffix>`, download it from `/forensics/{namespace}/{id}`
+    pub id: String,
+    pub namespace: String,
+    /// Capture time, RFC 3339
+    pub created: Option<String>,
+    /// Size of the archive in bytes
+    pub size: u64,
+    /// File or object URL of the archive
+    pub location: String,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct ForensicsQuery {
+    /// Only the bundles of this namespace, every namespace requires access to all of them
+    pub namespace: Option<String>,
+}
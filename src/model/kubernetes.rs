@@ -1,16 +1,78 @@
-
+use std::{collections::BTreeMap, fmt};
 use paperclip::actix::Apiv2Schema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Serialize, Deserialize, Apiv2Schema)]
 pub struct SuccessResponse {
     pub status: String,
 }
 
+/// Outcome of a change, with the fields it would set when it was a dry run
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ChangeResponse {
+    pub status: String,
+    /// Computed changes of the object, only for `dry_run`
+    pub changes: Option<Vec<FieldChange>>,
+}
+
+/// One field of an object changed by an action, e.g. `spec.template.spec.containers[name=api].image`
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct FieldChange {
+    pub path: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Quarantine NetworkPolicy of the namespace of an isolated pod
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone)]
+pub struct QuarantinePolicyStatus {
+    pub name: String,
+    /// `created`, `reconciled` or `unchanged`, `unmanaged` or `missing` when `QUARANTINE_POLICY_MANAGED` is false
+    pub state: String,
+    pub exists: bool,
+    /// Whether the policy selects the pod once it is labelled, the pod is only isolated then
+    pub selects_pod: bool,
+    /// Egress isolated pods still have
+    pub egress_allowed: Vec<String>,
+}
+
+/// Selectors an isolated pod was taken out of so that its controller replaces it
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone, Default)]
+pub struct DetachedPod {
+    /// Labels removed from the pod with their values, kept in the `officer/detached-labels` annotation
+    /// for `unisolate-pod` to put back
+    pub labels: BTreeMap<String, String>,
+    /// ReplicaSet or DaemonSet that releases the pod and starts a replacement
+    pub controller: Option<String>,
+    /// Controller that keeps the pod, a StatefulSet or Job can not replace a pod that still exists
+    pub kept_by: Option<String>,
+    /// Services the pod no longer receives traffic from
+    pub services: Vec<String>,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct IsolatePodQuery {
+    /// Compute the change with a server-side dry run, nothing is persisted
+    pub dry_run: Option<bool>,
+    /// Capture a forensic bundle of the pod before the playbook runs, by default when the playbook or
+    /// `FORENSICS_CAPTURE` asks for it
+    pub forensics: Option<bool>,
+    /// Relabel isolated pods out of the selectors of their ReplicaSet and Services, by default when the
+    /// `isolate_network` action asks for it
+    pub detach: Option<bool>,
+}
+
 #[derive(Serialize, Deserialize, Apiv2Schema)]
 pub struct UnisolatePodPayload {
     pub namespace: String,
     pub pod_name: String,
+    /// Also put back the labels a ReplicaSet or DaemonSet selects, it adopts the pod again and deletes a surplus pod
+    #[serde(default)]
+    pub restore_controller_labels: bool,
+    /// Compute the change with a server-side dry run, nothing is persisted
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Serialize, Deserialize, Apiv2Schema)]
@@ -25,16 +87,304 @@ pub struct GetPodQuery {
     pub namespace: String
 }
 
-#[derive(Deserialize, Apiv2Schema)]
+/// Kind of workload changed by deploy and restart, `Rollout` is an Argo Rollouts rollout
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WorkloadKind {
+    #[default]
+    Deployment,
+    StatefulSet,
+    DaemonSet,
+    Rollout,
+}
+
+impl WorkloadKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkloadKind::Deployment => "Deployment",
+            WorkloadKind::StatefulSet => "StatefulSet",
+            WorkloadKind::DaemonSet => "DaemonSet",
+            WorkloadKind::Rollout => "Rollout",
+        }
+    }
+}
+
+impl fmt::Display for WorkloadKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
 pub struct RestartServicePayload {
     pub namespace: String,
-    pub service_deployment: String
+    pub service_deployment: String,
+    /// Kind of the workload, defaults to Deployment
+    #[serde(default)]
+    pub kind: WorkloadKind,
+    /// Compute the change with a server-side dry run, nothing is persisted
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
-#[derive(Deserialize, Apiv2Schema)]
+#[derive(Serialize, Deserialize, Apiv2Schema)]
 pub struct DeployServicePayload {
     pub namespace: String,
     pub service_deployment: String,
+    /// Kind of the workload, defaults to Deployment
+    #[serde(default)]
+    pub kind: WorkloadKind,
+    pub container_name: String,
+    /// New image tag
+    pub version: Option<String>,
+    /// Image digest to pin, e.g. "sha256:..."
+    pub digest: Option<String>,
+    /// New registry and repository, requires the `change-image-repository` action
+    pub repository: Option<String>,
+    /// Wait for the rollout to finish before responding
+    #[serde(default)]
+    pub wait: bool,
+    /// How long to wait for the rollout, defaults to `ROLLOUT_TIMEOUT_SECONDS`
+    pub timeout_seconds: Option<u64>,
+    /// Container restarts after which the rollout is failed, defaults to `ROLLOUT_MAX_RESTARTS`
+    pub max_restarts: Option<i32>,
+    /// Put the previous image back when a waited rollout fails or times out, enabled by default
+    pub auto_rollback: Option<bool>,
+    /// Check the image exists in its registry before deploying, defaults to `REGISTRY_VERIFY`
+    pub verify_image: Option<bool>,
+    /// Compute the change with a server-side dry run, nothing is persisted and `wait` is ignored
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Deploy to a `<service_deployment>-canary` Deployment first, only for Deployments
+    pub canary: Option<CanaryOptions>,
+}
+
+/// Staged deploy: the new image runs in a canary Deployment next to the stable pods for the bake time,
+/// then is promoted to the deployment, or aborted when canary pods restart or stop being ready
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct CanaryOptions {
+    /// Canary replicas, 1 unless `percentage` is given
+    pub replicas: Option<i32>,
+    /// Canary replicas as a percentage of the deployment replicas, rounded up
+    pub percentage: Option<u32>,
+    /// How long the ready canary is watched, defaults to `CANARY_BAKE_SECONDS`, at most a week
+    pub bake_seconds: Option<u64>,
+    /// Promote once the bake time passed, enabled by default, otherwise the canary waits for /canary/promote
+    pub auto_promote: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct DeployServiceResponse {
+    pub status: String,
+    /// Outcome of the rollout, only when `wait` was requested
+    pub rollout: Option<RolloutStatus>,
+    /// Image put back after a failed rollout
+    pub rolled_back_to: Option<String>,
+    /// Digest of the image in the registry, when it was verified
+    pub resolved_digest: Option<String>,
+    /// Computed changes of the deployment, only for `dry_run`
+    pub changes: Option<Vec<FieldChange>>,
+    /// State of the canary of a staged deploy
+    pub canary: Option<CanaryStatus>,
+}
+
+/// ReplicaSet revision of a Deployment, as listed by /deployments/{namespace}/{name}/history
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct DeploymentRevision {
+    pub revision: i64,
+    pub replica_set: String,
+    /// Whether this is the revision the Deployment currently runs
+    pub current: bool,
+    pub replicas: i32,
+    /// RFC 3339 creation time of the ReplicaSet
+    pub created: Option<String>,
+    /// `kubernetes.io/change-cause` annotation
+    pub change_cause: Option<String>,
+    pub containers: Vec<ContainerImage>,
+    /// Officer action that created the revision, from the audit log
+    pub action: Option<String>,
+    pub triggered_by: Option<String>,
+    pub audit_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ContainerImage {
+    pub container: String,
+    pub image: String,
+    pub tag: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct RollbackDeploymentPayload {
+    pub namespace: String,
+    pub service_deployment: String,
+    /// ReplicaSet revision to return to, defaults to the revision before the current one
+    pub revision: Option<i64>,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct RolloutStatusQuery {
+    pub namespace: String,
+    pub service_deployment: String,
+    /// Kind of the workload, defaults to Deployment
+    #[serde(default)]
+    pub kind: WorkloadKind,
+    /// Wait for the rollout to finish instead of returning the current state
+    pub wait: Option<bool>,
+    pub timeout_seconds: Option<u64>,
+}
+
+/// Progress of a workload rollout, `state` is one of `progressing`, `complete`, `failed` or `timeout`
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone)]
+pub struct RolloutStatus {
+    pub namespace: String,
+    pub service_deployment: String,
+    pub state: String,
+    pub message: String,
+    pub generation: Option<i64>,
+    pub observed_generation: Option<i64>,
+    pub replicas: i32,
+    pub updated_replicas: i32,
+    pub ready_replicas: i32,
+    pub available_replicas: i32,
+    pub conditions: Vec<RolloutCondition>,
+    /// Pods of the new revision that are not starting, with the reason reported by Kubernetes
+    pub failing_pods: Vec<FailingPod>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone)]
+pub struct RolloutCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone)]
+pub struct FailingPod {
+    pub name: String,
+    pub container: Option<String>,
+    pub reason: String,
+    pub message: Option<String>,
+    pub restart_count: i32,
+}
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ContainerVersion {
+    /// Name of a container or init container
     pub container_name: String,
-    pub version: String
-}
\ No newline at end of file
+    /// New image tag
+    pub version: Option<String>,
+    /// Image digest to pin, e.g. "sha256:..."
+    pub digest: Option<String>,
+    /// New registry and repository, requires the `change-image-repository` action
+    pub repository: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct BatchDeployItem {
+    pub service_deployment: String,
+    pub containers: Vec<ContainerVersion>,
+}
+
+/// Deployments released together, every change is rolled back if one of them fails
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct BatchDeployPayload {
+    pub namespace: String,
+    pub deployments: Vec<BatchDeployItem>,
+    /// Wait for every rollout before responding, enabled by default
+    pub wait: Option<bool>,
+    /// How long to wait for the rollouts, defaults to `ROLLOUT_TIMEOUT_SECONDS`
+    pub timeout_seconds: Option<u64>,
+    /// Container restarts after which a rollout is failed, defaults to `ROLLOUT_MAX_RESTARTS`
+    pub max_restarts: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct BatchDeployResult {
+    pub service_deployment: String,
+    pub containers: Vec<ContainerImage>,
+    pub rollout: Option<RolloutStatus>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct BatchDeployResponse {
+    pub status: String,
+    pub deployments: Vec<BatchDeployResult>,
+    /// Whether the deployments were put back to their previous images
+    pub rolled_back: bool,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleOperation {
+    /// Scale to `replicas`
+    #[default]
+    Set,
+    /// Scale to zero and remember the current replicas
+    Zero,
+    /// Scale back to the remembered replicas and resume the suspended autoscaler
+    Restore,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ScalePayload {
+    pub namespace: String,
+    pub service_deployment: String,
+    /// Deployment (default) or StatefulSet
+    #[serde(default)]
+    pub kind: WorkloadKind,
+    #[serde(default)]
+    pub operation: ScaleOperation,
+    /// Wanted replicas, required by `set`
+    pub replicas: Option<i32>,
+    /// Pin the HorizontalPodAutoscaler of the workload to `replicas` until `restore`
+    #[serde(default)]
+    pub suspend_autoscaler: bool,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ScaleResponse {
+    pub status: String,
+    pub previous_replicas: i32,
+    pub replicas: i32,
+    /// HorizontalPodAutoscaler targeting the workload
+    pub autoscaler: Option<String>,
+    /// Whether the autoscaler is pinned by Officer after this call
+    pub autoscaler_suspended: bool,
+}
+
+/// Canary of a staged deploy, `phase` is `starting`, `baking` or `ready` (waiting for a manual promotion)
+/// while it is in flight, then `promoted` or `aborted`
+#[derive(Serialize, Deserialize, Apiv2Schema, Clone)]
+pub struct CanaryStatus {
+    pub namespace: String,
+    pub service_deployment: String,
+    pub canary_deployment: String,
+    pub phase: String,
+    pub message: String,
+    pub container: String,
+    pub image: String,
+    pub previous_image: String,
+    pub replicas: i32,
+    pub ready_replicas: i32,
+    /// RFC 3339 time the canary was created
+    pub started: Option<String>,
+    /// RFC 3339 time the bake ends, once the canary is ready
+    pub bake_until: Option<String>,
+    pub auto_promote: bool,
+    pub triggered_by: String,
+    pub failing_pods: Vec<FailingPod>,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct CanaryQuery {
+    pub namespace: String,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct CanaryActionPayload {
+    pub namespace: String,
+    pub service_deployment: String,
+    /// Why the canary is promoted or aborted, recorded in the event
+    pub reason: Option<String>,
+}
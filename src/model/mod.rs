@@ -1,2 +1,8 @@
 pub mod kubernetes;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod rbac;
+pub mod audit;
+pub mod error;
+pub mod falco;
+pub mod forensics;
+pub mod playbook;
@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, fmt};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use super::{falco::{FalcoEvent, FalcoPriority}, forensics::ForensicBundle, kubernetes::{DetachedPod, FieldChange, QuarantinePolicyStatus}};
+
+/// Falco response playbooks, usually loaded from the file in `PLAYBOOKS_FILE`.
+/// The first playbook matching an alert runs its actions in order.
+///
+/// Example:
+/// ```yaml
+/// - name: network-scan
+///   rules: ["network_scan_process_in_container"]
+///   actions:
+///     - action: isolate_network
+///     - action: notify
+///       url: https://hooks.example.com/security
+/// - name: crypto-miner
+///   rules: ["Detect crypto miners*"]
+///   min_priority: critical
+///   tags: ["mitre_execution"]
+///   forensics: true
+///   output_fields:
+///     k8s.ns.name: "prod-*"
+///   actions:
+///     - action: label
+///       labels:
+///         quarantine: "true"
+///     - action: cordon_node
+///     - action: delete_pod
+///       grace_period_seconds: 0
+/// ```
+#[derive(Serialize, Deserialize, Apiv2Schema, Debug, Clone)]
+pub struct Playbook {
+    pub name: String,
+    /// Falco rule names, `*` matches any characters
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// Lowest priority of the alerts the playbook applies to
+    pub min_priority: Option<FalcoPriority>,
+    /// Tags the rule must all have
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Patterns the output fields must match, e.g. `k8s.ns.name: "prod-*"`
+    #[serde(default)]
+    pub output_fields: BTreeMap<String, String>,
+    /// Capture a forensic bundle of the pod before the actions run
+    #[serde(default)]
+    pub forensics: bool,
+    /// Response actions, run in order until one fails
+    pub actions: Vec<PlaybookAction>,
+}
+
+/// Response action of a playbook, on the pod of the alert unless stated otherwise
+#[derive(Serialize, Deserialize, Apiv2Schema, Debug, Clone)]
+pub struct PlaybookAction {
+    pub action: PlaybookActionKind,
+    /// Labels set by `label`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Annotations set by `annotate`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Grace period of `delete_pod`, the pod's own when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grace_period_seconds: Option<u32>,
+    /// URL `notify` posts the alert and the outcome of the previous actions to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// With `isolate_network`, relabel the pod out of the selectors of its ReplicaSet or DaemonSet and Services
+    /// so a clean replacement starts while the pod stays for investigation
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detach: bool,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybookActionKind {
+    /// Label the pod `isolate: "true"` after ensuring the quarantine NetworkPolicy selecting it
+    IsolateNetwork,
+    Label,
+    Annotate,
+    /// Mark the node of the pod unschedulable, the caller needs `cordon-node` in every namespace
+    CordonNode,
+    /// Delete the pod, the caller needs `delete-pod`
+    DeletePod,
+    /// Scale the Deployment or StatefulSet owning the pod to zero, `/scale` can restore it. The caller needs `scale`
+    ScaleToZero,
+    Notify,
+}
+
+impl PlaybookActionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaybookActionKind::IsolateNetwork => "isolate_network",
+            PlaybookActionKind::Label => "label",
+            PlaybookActionKind::Annotate => "annotate",
+            PlaybookActionKind::CordonNode => "cordon_node",
+            PlaybookActionKind::DeletePod => "delete_pod",
+            PlaybookActionKind::ScaleToZero => "scale_to_zero",
+            PlaybookActionKind::Notify => "notify",
+        }
+    }
+}
+
+impl fmt::Display for PlaybookActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Whether `value` matches `pattern`, where `*` matches any characters
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl Playbook {
+    /// Whether the Falco event is one the playbook responds to
+    pub fn matches(&self, event: &FalcoEvent) -> bool {
+        if !self.rules.is_empty() && !self.rules.iter().any(|pattern| wildcard_match(pattern, &event.rule)) {
+            return false;
+        }
+        if self.min_priority.is_some_and(|min| event.priority < min) {
+            return false;
+        }
+        if !self.tags.iter().all(|tag| event.tags.contains(tag)) {
+            return false;
+        }
+        self.output_fields.iter().all(|(field, pattern)| {
+            event.field(field).is_some_and(|value| wildcard_match(pattern, &value))
+        })
+    }
+}
+
+/// Loaded playbooks
+#[derive(Serialize, Apiv2Schema)]
+pub struct PlaybooksResponse {
+    /// File the playbooks are loaded from, none for the built-in playbook
+    pub file: Option<String>,
+    /// Why the last change of the file was not loaded, the previous playbooks stay in use
+    pub reload_error: Option<String>,
+    pub playbooks: Vec<Playbook>,
+}
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ValidatePlaybooksPayload {
+    /// Playbook file to check before it is deployed, the loaded playbooks when not set
+    pub content: Option<String>,
+    /// Falco event to find the matching playbook for
+    pub alert: Option<FalcoEvent>,
+}
+
+#[derive(Serialize, Apiv2Schema)]
+pub struct ValidatePlaybooksResponse {
+    pub valid: bool,
+    pub error: Option<String>,
+    /// Playbook that would respond to `alert`
+    pub matched: Option<Playbook>,
+}
+
+/// Outcome of one action of a playbook
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct ActionOutcome {
+    pub action: PlaybookActionKind,
+    pub status: String,
+}
+
+/// Outcome of the response to a Falco alert
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct PlaybookResponse {
+    pub status: String,
+    /// Playbook that responded to the alert, none when no playbook matched
+    pub playbook: Option<String>,
+    pub actions: Vec<ActionOutcome>,
+    /// Quarantine NetworkPolicy, when the playbook isolated the pod
+    pub quarantine: Option<QuarantinePolicyStatus>,
+    /// Selectors the isolated pod was taken out of, when `detach` is set
+    pub detached: Option<DetachedPod>,
+    /// Forensic bundle captured before the actions ran
+    pub forensics: Option<ForensicBundle>,
+    /// Why the forensic bundle could not be captured, the actions run anyway
+    pub forensics_error: Option<String>,
+    /// Computed changes of the pod, only for `dry_run`
+    pub changes: Option<Vec<FieldChange>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_without_star_is_exact() {
+        assert!(wildcard_match("Terminal shell in container", "Terminal shell in container"));
+        assert!(!wildcard_match("Terminal shell", "Terminal shell in container"));
+        assert!(!wildcard_match("Terminal shell in container", "Terminal shell"));
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "a"));
+    }
+
+    #[test]
+    fn wildcard_star_matches_any_characters() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("Terminal*", "Terminal shell in container"));
+        assert!(wildcard_match("*container", "Terminal shell in container"));
+        assert!(wildcard_match("*shell*", "Terminal shell in container"));
+        assert!(wildcard_match("T*shell*c*r", "Terminal shell in container"));
+        assert!(!wildcard_match("*shell*", "Read sensitive file"));
+        assert!(!wildcard_match("Terminal*", "A Terminal shell"));
+    }
+
+    #[test]
+    fn wildcard_parts_do_not_overlap() {
+        assert!(!wildcard_match("a*a", "a"));
+        assert!(wildcard_match("a*a", "aa"));
+        assert!(!wildcard_match("*ab*ab", "xab"));
+        assert!(wildcard_match("*ab*ab", "abab"));
+        assert!(wildcard_match("prod-**-db", "prod-eu-db"));
+    }
+}
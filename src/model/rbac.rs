@@ -0,0 +1,225 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+use serde::Deserialize;
+
+use super::auth::Principal;
+
+/// Actions that can be granted to a role, most of them are named after the endpoint they protect
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    GetPod,
+    DeployService,
+    RestartServiceDeployment,
+    IsolatePod,
+    UnisolatePod,
+    RevokeToken,
+    ReadAudit,
+    RolloutStatus,
+    RollbackDeployment,
+    DeploymentHistory,
+    ChangeImageRepository,
+    Scale,
+    ReadPlaybooks,
+    ReadForensics,
+    DeletePod,
+    CordonNode,
+}
+
+impl Action {
+    pub const ALL: [Action; 16] = [
+        Action::GetPod,
+        Action::DeployService,
+        Action::RestartServiceDeployment,
+        Action::IsolatePod,
+        Action::UnisolatePod,
+        Action::RevokeToken,
+        Action::ReadAudit,
+        Action::RolloutStatus,
+        Action::RollbackDeployment,
+        Action::DeploymentHistory,
+        Action::ChangeImageRepository,
+        Action::Scale,
+        Action::ReadPlaybooks,
+        Action::ReadForensics,
+        Action::DeletePod,
+        Action::CordonNode,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::GetPod => "get-pod",
+            Action::DeployService => "deploy-service",
+            Action::RestartServiceDeployment => "restart-service-deployment",
+            Action::IsolatePod => "isolate-pod",
+            Action::UnisolatePod => "unisolate-pod",
+            Action::RevokeToken => "revoke-token",
+            Action::ReadAudit => "read-audit",
+            Action::RolloutStatus => "rollout-status",
+            Action::RollbackDeployment => "rollback-deployment",
+            Action::DeploymentHistory => "deployment-history",
+            Action::ChangeImageRepository => "change-image-repository",
+            Action::Scale => "scale",
+            Action::ReadPlaybooks => "read-playbooks",
+            Action::ReadForensics => "read-forensics",
+            Action::DeletePod => "delete-pod",
+            Action::CordonNode => "cordon-node",
+        }
+    }
+
+    /// Map a request path to the action it performs, `None` means the route only requires authentication
+    pub fn from_path(path: &str) -> Option<Action> {
+        match path {
+            "/get-pod" => Some(Action::GetPod),
+            "/deploy-service" | "/deploy-batch" | "/canary/promote" | "/canary/abort" => Some(Action::DeployService),
+            "/restart-service-deployment" => Some(Action::RestartServiceDeployment),
+            "/isolate-pod" => Some(Action::IsolatePod),
+            "/unisolate-pod" => Some(Action::UnisolatePod),
+            "/audit" => Some(Action::ReadAudit),
+            "/rollout-status" | "/canaries" => Some(Action::RolloutStatus),
+            "/rollback-deployment" => Some(Action::RollbackDeployment),
+            "/scale" => Some(Action::Scale),
+            "/playbooks" | "/playbooks/validate" => Some(Action::ReadPlaybooks),
+            path if path == "/forensics" || path.starts_with("/forensics/") => Some(Action::ReadForensics),
+            path if path.starts_with("/deployments/") && path.ends_with("/history") => Some(Action::DeploymentHistory),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Action::ALL
+            .into_iter()
+            .find(|action| action.as_str() == s)
+            .ok_or_else(|| format!("Unknown action: {}", s))
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// RBAC policy, usually loaded from the file in `RBAC_POLICY_FILE`
+///
+/// Example:
+/// ```yaml
+/// roles:
+///   release-manager:
+///     actions: ["get-pod", "deploy-service"]
+/// bindings:
+///   - role: deployer
+///     users: ["jane@example.com"]
+///     groups: ["platform"]
+///   - role: admin
+///     groups: ["acme/sre"]
+///     min_access_level: maintainer
+///     namespaces: ["staging", "production"]
+///   - role: security-responder
+///     users: ["api-key"]
+///     namespaces: ["*"]
+/// ```
+#[derive(Deserialize, Debug, Default)]
+pub struct Policy {
+    /// Additional roles, or overrides of the built-in ones
+    #[serde(default)]
+    pub roles: HashMap<String, Role>,
+    #[serde(default)]
+    pub bindings: Vec<RoleBinding>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Role {
+    /// Action names, `*` grants every action
+    pub actions: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoleBinding {
+    pub role: String,
+    /// User emails or API key identities, `oidc:<provider>:<subject>` for OpenID Connect logins and
+    /// `<issuer name>:<subject>` for tokens of trusted issuers
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// GitLab groups, or project paths when `OAUTH2_GITLAB_PROJECT_ACCESS` is enabled, `oidc:<provider>:<group>`
+    /// for OpenID Connect logins and `<issuer name>:<group>` for tokens of trusted issuers
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Minimum GitLab access level the user must have in one of `groups`
+    pub min_access_level: Option<GitlabAccessLevel>,
+    /// Namespaces the role applies to, `*` matches every namespace
+    #[serde(default = "all_namespaces")]
+    pub namespaces: Vec<String>,
+}
+
+/// Upper bound of what a credential may do, used for API keys
+#[derive(Deserialize, Debug, Clone)]
+pub struct Scope {
+    /// Action names, `*` allows every endpoint
+    pub endpoints: Vec<String>,
+    /// Namespaces, `*` matches every namespace
+    #[serde(default = "all_namespaces")]
+    pub namespaces: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum GitlabAccessLevel {
+    Guest = 10,
+    Reporter = 20,
+    Developer = 30,
+    Maintainer = 40,
+    Owner = 50,
+}
+
+fn all_namespaces() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+impl Role {
+    fn new(actions: &[&str]) -> Self {
+        Role { actions: actions.iter().map(|a| a.to_string()).collect() }
+    }
+
+    /// Roles available without defining them in the policy file
+    pub fn builtin() -> HashMap<String, Role> {
+        HashMap::from([
+            ("viewer".to_string(), Role::new(&["get-pod", "rollout-status", "deployment-history"])),
+            ("deployer".to_string(), Role::new(&[
+                "get-pod", "rollout-status", "deployment-history", "deploy-service", "restart-service-deployment", "rollback-deployment", "scale",
+            ])),
+            ("security-responder".to_string(), Role::new(&["get-pod", "isolate-pod", "unisolate-pod", "scale", "delete-pod", "read-playbooks", "read-forensics"])),
+            ("admin".to_string(), Role::new(&["*"])),
+        ])
+    }
+
+    pub fn allows(&self, action: Action) -> bool {
+        self.actions.iter().any(|a| a == "*" || a == action.as_str())
+    }
+}
+
+impl RoleBinding {
+    pub fn matches(&self, principal: &Principal) -> bool {
+        if self.users.contains(&principal.subject) {
+            return true;
+        }
+        let membership = &principal.membership;
+        self.groups.iter().any(|group| match self.min_access_level {
+            Some(min) => membership.access_levels.get(group).is_some_and(|level| *level >= min as u8),
+            None => membership.groups.contains(group),
+        })
+    }
+
+    pub fn applies_to_namespace(&self, namespace: Option<&str>) -> bool {
+        self.namespaces.iter().any(|ns| ns == "*" || Some(ns.as_str()) == namespace)
+    }
+}
+
+impl Scope {
+    pub fn allows(&self, action: Action, namespace: Option<&str>) -> bool {
+        self.endpoints.iter().any(|a| a == "*" || a == action.as_str())
+            && self.namespaces.iter().any(|ns| ns == "*" || Some(ns.as_str()) == namespace)
+    }
+}
@@ -0,0 +1,200 @@
+use log::info;
+use oauth2::{basic::BasicClient, http::{header::AUTHORIZATION, HeaderMap, HeaderValue, Method}, reqwest::async_http_client, AccessToken, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl};
+use serde::{de::DeserializeOwned, Deserialize};
+use url::Url;
+
+use crate::{config::{get_gitlab_project_access, get_optional_envar}, model::auth::Membership};
+use super::{AuthenticatedUser, LoginState};
+
+pub const GITLAB: &str = "gitlab";
+
+#[allow(unused)]
+#[derive(Deserialize, Debug)]
+pub struct Identity {
+    #[allow(unused)]
+    pub provider: String,
+    #[allow(unused)]
+    pub extern_uid: String
+}
+
+#[allow(unused)]
+#[derive(Deserialize, Debug)]
+pub struct UserInfo {
+    id: u64,
+    name: String,
+    username: String,
+    email: String,
+    commit_email: String,
+    is_admin: bool,
+    identities: Vec<Identity>,
+    avatar_url: String
+}
+
+#[derive(Deserialize, Debug)]
+struct GitlabGroup {
+    full_path: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GitlabProject {
+    path_with_namespace: String,
+}
+
+// GitLab access levels, highest first
+const ACCESS_LEVELS: [u8; 5] = [50, 40, 30, 20, 10];
+
+/// GitLab OAuth2 login, configured with the `OAUTH2_GITLAB_*` variables
+pub struct GitlabProvider {
+    url: String,
+    client: BasicClient,
+}
+
+impl GitlabProvider {
+    // Build the provider if `OAUTH2_GITLAB_URL` is set
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let oauth2_gitlab_url = match get_optional_envar("OAUTH2_GITLAB_URL") {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let required = |name: &str| get_optional_envar(name).ok_or_else(|| format!("{} environment variable not set", name));
+        let oauth2_gitlab_client_id = required("OAUTH2_GITLAB_CLIENT_ID")?;
+        let oauth2_gitlab_client_secret = required("OAUTH2_GITLAB_CLIENT_SECRET")?;
+        let oauth2_redirect_url = required("OAUTH2_REDIRECT_URL")?;
+        let auth_url = AuthUrl::new(
+            format!("{}/oauth/authorize", oauth2_gitlab_url),
+        ).map_err(|e| format!("Invalid authorization endpoint URL: {}", e))?;
+
+        let token_url = TokenUrl::new(
+            format!("{}/oauth/token", oauth2_gitlab_url),
+        ).map_err(|e| format!("Invalid token endpoint URL: {}", e))?;
+
+        let redirect_url = RedirectUrl::new(
+            oauth2_redirect_url,
+        ).map_err(|e| format!("Invalid redirect URL: {}", e))?;
+
+        let client_id = ClientId::new(oauth2_gitlab_client_id);
+        let client_secret = ClientSecret::new(oauth2_gitlab_client_secret);
+
+        let client = BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
+            .set_redirect_uri(redirect_url);
+        Ok(Some(GitlabProvider { url: oauth2_gitlab_url, client }))
+    }
+
+    pub fn authorize_url(&self) -> (Url, LoginState) {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (auth_url, csrf_token) = self.client
+            .authorize_url(CsrfToken::new_random)
+            .add_scope(Scope::new("read_user".to_string())) // GitLab's scope
+            .add_scope(Scope::new("read_api".to_string())) // GitLab's scope, needed to list groups
+            .add_scope(Scope::new("email".to_string())) // GitLab's scope
+            .add_scope(Scope::new("profile".to_string())) // GitLab's scope
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+        let state = LoginState {
+            provider: GITLAB.to_string(),
+            csrf_token: csrf_token.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            nonce: None,
+        };
+        (auth_url, state)
+    }
+
+    pub async fn authenticate(&self, code: String, state: &LoginState) -> Result<AuthenticatedUser, String> {
+        let token_response = self.client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(state.pkce_verifier.clone()))
+            .request_async(async_http_client).await
+            .map_err(|e| format!("Failed to exchange code for token: {}", e))?;
+        let api_base_url = format!("{}/api/v4", self.url);
+        let user_info = read_user(&api_base_url, token_response.access_token()).await?;
+        // info!("{:?}", user_info);
+        let membership = read_membership(&api_base_url, token_response.access_token()).await
+            .map_err(|e| format!("Failed to read GitLab groups of {}: {}", user_info.email, e))?;
+        Ok(AuthenticatedUser { subject: user_info.email, membership })
+    }
+}
+
+async fn read_user(api_base_url: &str, access_token: &AccessToken) -> Result<UserInfo, String> {
+    let url = Url::parse(&format!(
+        "{}/user?access_token={}",
+        api_base_url,
+        access_token.secret()
+    )).map_err(|e| format!("Invalid URL: {}", e))?;
+
+    let request = oauth2::HttpRequest {
+        url,
+        method: Method::GET,
+        headers: HeaderMap::new(),
+        body: Vec::new(),
+    };
+
+    match async_http_client(request).await {
+        Ok(resp) => {
+            serde_json::from_slice(&resp.body)
+                .map_err(|e| format!("Failed to parse response: {}", e))
+        }
+        Err(e) => {
+            let error_message = format!("Failed to retrieve user info: {}", e);
+            info!("{}", error_message);
+            Err(error_message)
+        }
+    }
+}
+
+// Fetch every page of a GitLab list endpoint
+async fn read_pages<T: DeserializeOwned>(url: &str, access_token: &AccessToken) -> Result<Vec<T>, String> {
+    let mut items = Vec::new();
+    let mut page = "1".to_string();
+    loop {
+        let url = Url::parse(&format!("{}&per_page=100&page={}", url, page))
+            .map_err(|e| format!("Invalid URL: {}", e))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", access_token.secret())).map_err(|e| e.to_string())?,
+        );
+        let request = oauth2::HttpRequest {
+            url,
+            method: Method::GET,
+            headers,
+            body: Vec::new(),
+        };
+        let resp = async_http_client(request).await
+            .map_err(|e| format!("Failed to retrieve page {}: {}", page, e))?;
+        if !resp.status_code.is_success() {
+            return Err(format!("GitLab responded with {}", resp.status_code));
+        }
+        let mut batch: Vec<T> = serde_json::from_slice(&resp.body)
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        items.append(&mut batch);
+        match resp.headers.get("x-next-page").and_then(|v| v.to_str().ok()) {
+            Some(next) if !next.is_empty() => page = next.to_string(),
+            _ => return Ok(items),
+        }
+    }
+}
+
+// Read the groups (and projects if enabled) of the user with their highest access level
+async fn read_membership(api_base_url: &str, access_token: &AccessToken) -> Result<Membership, String> {
+    let mut membership = Membership::default();
+    for level in ACCESS_LEVELS {
+        let groups: Vec<GitlabGroup> = read_pages(
+            &format!("{}/groups?min_access_level={}", api_base_url, level), access_token
+        ).await?;
+        let mut paths: Vec<String> = groups.into_iter().map(|g| g.full_path).collect();
+        if get_gitlab_project_access() {
+            let projects: Vec<GitlabProject> = read_pages(
+                &format!("{}/projects?membership=true&simple=true&min_access_level={}", api_base_url, level), access_token
+            ).await?;
+            paths.extend(projects.into_iter().map(|p| p.path_with_namespace));
+        }
+        for path in paths {
+            // Levels are visited from the highest, the first one seen is the effective one
+            if !membership.access_levels.contains_key(&path) {
+                membership.access_levels.insert(path.clone(), level);
+                membership.groups.push(path);
+            }
+        }
+    }
+    Ok(membership)
+}
@@ -0,0 +1,72 @@
+use std::{collections::BTreeMap, sync::OnceLock};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{config::get_oidc_providers, model::auth::Membership};
+use self::{gitlab::{GitlabProvider, GITLAB}, oidc::OidcProvider};
+
+pub mod gitlab;
+pub mod oidc;
+
+/// Data kept in the session between the login redirect and the callback
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginState {
+    pub provider: String,
+    pub csrf_token: String,
+    pub pkce_verifier: String,
+    pub nonce: Option<String>,
+}
+
+/// User authenticated by an identity provider
+pub struct AuthenticatedUser {
+    pub subject: String,
+    pub membership: Membership,
+}
+
+pub enum IdentityProvider {
+    Gitlab(Box<GitlabProvider>),
+    Oidc(Box<OidcProvider>),
+}
+
+impl IdentityProvider {
+    // URL to send the user to, and the state to keep until the callback
+    pub async fn authorize_url(&self) -> Result<(Url, LoginState), String> {
+        match self {
+            IdentityProvider::Gitlab(provider) => Ok(provider.authorize_url()),
+            IdentityProvider::Oidc(provider) => provider.authorize_url().await,
+        }
+    }
+
+    // Exchange the authorization code and identify the user
+    pub async fn authenticate(&self, code: String, state: &LoginState) -> Result<AuthenticatedUser, String> {
+        match self {
+            IdentityProvider::Gitlab(provider) => provider.authenticate(code, state).await,
+            IdentityProvider::Oidc(provider) => provider.authenticate(code, state).await,
+        }
+    }
+}
+
+static PROVIDERS: OnceLock<BTreeMap<String, IdentityProvider>> = OnceLock::new();
+
+// Register GitLab (when `OAUTH2_GITLAB_URL` is set) and every provider listed in `OIDC_PROVIDERS`
+pub fn init_providers() -> Result<(), String> {
+    let mut providers = BTreeMap::new();
+    if let Some(gitlab) = GitlabProvider::from_env()? {
+        providers.insert(GITLAB.to_string(), IdentityProvider::Gitlab(Box::new(gitlab)));
+    }
+    for name in get_oidc_providers() {
+        let provider = OidcProvider::from_env(&name)?;
+        if providers.insert(provider.name().to_string(), IdentityProvider::Oidc(Box::new(provider))).is_some() {
+            return Err(format!("Identity provider {} is configured twice", name));
+        }
+    }
+    PROVIDERS.set(providers).map_err(|_| "Identity providers already initialized".to_string())
+}
+
+pub fn get_provider(name: &str) -> Option<&'static IdentityProvider> {
+    PROVIDERS.get().and_then(|providers| providers.get(name))
+}
+
+pub fn provider_names() -> Vec<&'static str> {
+    PROVIDERS.get().map(|providers| providers.keys().map(String::as_str).collect()).unwrap_or_default()
+}
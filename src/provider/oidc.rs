@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use oauth2::{
+    basic::{BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenType},
+    reqwest::async_http_client, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, ExtraTokenFields,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, StandardRevocableToken, StandardTokenResponse, TokenUrl
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+use url::Url;
+
+use crate::{config::get_optional_envar, model::auth::Membership, util::{http::get_json, jwks::JwksCache}};
+use super::{AuthenticatedUser, LoginState};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IdTokenFields {
+    id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+type OidcClient = Client<
+    BasicErrorResponse,
+    StandardTokenResponse<IdTokenFields, BasicTokenType>,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+// Subset of `.well-known/openid-configuration`
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+struct Discovered {
+    issuer: String,
+    client: OidcClient,
+    jwks: JwksCache,
+}
+
+/// OpenID Connect login (Keycloak, Dex, ...) configured with `OIDC_<NAME>_*` variables
+pub struct OidcProvider {
+    name: String,
+    issuer_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: Vec<String>,
+    subject_claim: String,
+    groups_claim: String,
+    // Discovery is done on first use so Officer starts even when the provider is down
+    discovered: OnceCell<Discovered>,
+}
+
+impl OidcProvider {
+    // Build the provider `name` from `OIDC_<NAME>_ISSUER_URL`, `OIDC_<NAME>_CLIENT_ID`,
+    // `OIDC_<NAME>_REDIRECT_URL` and the optional `OIDC_<NAME>_CLIENT_SECRET`, `OIDC_<NAME>_SCOPES`,
+    // `OIDC_<NAME>_SUBJECT_CLAIM` (default email) and `OIDC_<NAME>_GROUPS_CLAIM` (default groups). Users and
+    // groups are bound in the RBAC policy as `oidc:<name>:<subject>` and `oidc:<name>:<group>`
+    pub fn from_env(name: &str) -> Result<Self, String> {
+        let prefix = format!("OIDC_{}_", name.to_uppercase().replace('-', "_"));
+        let optional = |var: &str| get_optional_envar(&format!("{}{}", prefix, var));
+        let required = |var: &str| optional(var).ok_or_else(|| format!("{}{} environment variable not set", prefix, var));
+        Ok(OidcProvider {
+            name: name.to_string(),
+            issuer_url: required("ISSUER_URL")?,
+            client_id: required("CLIENT_ID")?,
+            client_secret: optional("CLIENT_SECRET"),
+            redirect_url: required("REDIRECT_URL")?,
+            scopes: optional("SCOPES")
+                .unwrap_or_else(|| "openid email profile".to_string())
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            subject_claim: optional("SUBJECT_CLAIM").unwrap_or_else(|| "email".to_string()),
+            groups_claim: optional("GROUPS_CLAIM").unwrap_or_else(|| "groups".to_string()),
+            discovered: OnceCell::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn discovered(&self) -> Result<&Discovered, String> {
+        self.discovered.get_or_try_init(|| async {
+            let discovery_url = format!("{}/.well-known/openid-configuration", self.issuer_url.trim_end_matches('/'));
+            let metadata: ProviderMetadata = get_json(&discovery_url).await?;
+            if metadata.issuer.trim_end_matches('/') != self.issuer_url.trim_end_matches('/') {
+                return Err(format!("Discovery document of {} announces issuer {}", self.issuer_url, metadata.issuer));
+            }
+            let auth_url = AuthUrl::new(metadata.authorization_endpoint)
+                .map_err(|e| format!("Invalid authorization endpoint URL: {}", e))?;
+            let token_url = TokenUrl::new(metadata.token_endpoint)
+                .map_err(|e| format!("Invalid token endpoint URL: {}", e))?;
+            let redirect_url = RedirectUrl::new(self.redirect_url.clone())
+                .map_err(|e| format!("Invalid redirect URL: {}", e))?;
+            let client = OidcClient::new(
+                ClientId::new(self.client_id.clone()),
+                self.client_secret.clone().map(ClientSecret::new),
+                auth_url,
+                Some(token_url),
+            ).set_redirect_uri(redirect_url);
+            Ok(Discovered { issuer: metadata.issuer, client, jwks: JwksCache::new(metadata.jwks_uri) })
+        }).await
+    }
+
+    pub async fn authorize_url(&self) -> Result<(Url, LoginState), String> {
+        let discovered = self.discovered().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let nonce = CsrfToken::new_random().secret().clone();
+        let (auth_url, csrf_token) = discovered.client
+            .authorize_url(CsrfToken::new_random)
+            .add_scopes(self.scopes.iter().cloned().map(Scope::new))
+            .add_extra_param("nonce", nonce.clone())
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+        let state = LoginState {
+            provider: self.name.clone(),
+            csrf_token: csrf_token.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            nonce: Some(nonce),
+        };
+        Ok((auth_url, state))
+    }
+
+    pub async fn authenticate(&self, code: String, state: &LoginState) -> Result<AuthenticatedUser, String> {
+        let discovered = self.discovered().await?;
+        let token_response = discovered.client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(state.pkce_verifier.clone()))
+            .request_async(async_http_client).await
+            .map_err(|e| format!("Failed to exchange code for token: {}", e))?;
+        let id_token = token_response.extra_fields().id_token.as_deref()
+            .ok_or_else(|| "Token response has no id_token".to_string())?;
+        let claims = discovered.jwks
+            .verify::<IdTokenClaims>(id_token, &discovered.issuer, std::slice::from_ref(&self.client_id)).await
+            .map_err(|e| e.to_string())?
+            .claims;
+        if claims.nonce != state.nonce {
+            return Err("ID token nonce does not match the login request".to_string());
+        }
+        // An unverified address could be anyone's, a missing claim is not a verification
+        if self.subject_claim == "email" && claims.other.get("email_verified") != Some(&Value::Bool(true)) {
+            return Err("Email address is not verified".to_string());
+        }
+        let subject = match self.subject_claim.as_str() {
+            "sub" => claims.sub.clone(),
+            claim => claims.other.get(claim)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| format!("ID token has no {} claim", claim))?,
+        };
+        let groups: Vec<String> = match claims.other.get(&self.groups_claim) {
+            Some(Value::Array(groups)) => groups.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        // Kept apart from GitLab users and groups and those of other providers, which may use the same names
+        let subject = format!("oidc:{}:{}", self.name, subject);
+        let groups = groups.into_iter().map(|group| format!("oidc:{}:{}", self.name, group)).collect();
+        Ok(AuthenticatedUser {
+            subject,
+            membership: Membership { groups, ..Default::default() },
+        })
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::OnceLock;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{get_api_key, get_api_keys_file},
+    model::{auth::{ApiKeyEntry, AuthMethod, Membership, Principal}, rbac::Action},
+    util::reloadable::Reloadable,
+};
+
+/// API key as kept in memory, only the digest of the secret is known
+pub struct StoredApiKey {
+    entry: ApiKeyEntry,
+    digest: Vec<u8>,
+}
+
+static KEY_STORE: OnceLock<Option<Reloadable<Vec<StoredApiKey>>>> = OnceLock::new();
+
+// Parse and validate the content of `API_KEYS_FILE`
+pub fn parse_key_store(content: &str) -> Result<Vec<StoredApiKey>, String> {
+    let entries: Vec<ApiKeyEntry> = serde_yaml::from_str(content)
+        .map_err(|e| format!("Invalid API key store: {}", e))?;
+    entries.into_iter().map(|entry| {
+        let digest = hex::decode(entry.sha256.trim())
+            .ok()
+            .filter(|d| d.len() == 32)
+            .ok_or_else(|| format!("API key {}: sha256 must be 64 hex characters", entry.name))?;
+        for endpoint in entry.scope.endpoints.iter().filter(|e| e.as_str() != "*") {
+            endpoint.parse::<Action>().map_err(|e| format!("API key {}: {}", entry.name, e))?;
+        }
+        Ok(StoredApiKey { entry, digest })
+    }).collect()
+}
+
+// Load the key store from `API_KEYS_FILE`, must be called once at startup
+pub fn init_key_store() -> Result<(), String> {
+    let store = match get_api_keys_file() {
+        Some(path) => Some(Reloadable::load(&path, parse_key_store)?),
+        None => None,
+    };
+    KEY_STORE.set(store).map_err(|_| "API key store already initialized".to_string())
+}
+
+// Find the caller owning `key`, every stored key is compared so timing does not leak which one matched
+pub fn verify_api_key(key: &str) -> Option<Principal> {
+    let digest = Sha256::digest(key.as_bytes());
+    if let Some(Some(store)) = KEY_STORE.get() {
+        let keys = store.get();
+        let mut found = None;
+        for stored in keys.iter() {
+            if bool::from(stored.digest.ct_eq(digest.as_slice())) && found.is_none() {
+                found = Some(Principal {
+                    subject: stored.entry.name.clone(),
+                    membership: Membership::default(),
+                    auth_method: AuthMethod::ApiKey,
+                    scope: Some(stored.entry.scope.clone()),
+                });
+            }
+        }
+        if found.is_some() {
+            return found;
+        }
+    }
+    // Legacy shared key, governed by the RBAC bindings of the "api-key" user
+    let legacy = get_api_key()?;
+    if bool::from(Sha256::digest(legacy.as_bytes()).ct_eq(&digest)) {
+        return Some(Principal {
+            subject: "api-key".to_string(),
+            membership: Membership::default(),
+            auth_method: AuthMethod::ApiKey,
+            scope: None,
+        });
+    }
+    None
+}
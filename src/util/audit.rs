@@ -0,0 +1,380 @@
+use std::{collections::{HashMap, VecDeque}, fs, io::{BufRead, BufReader, Seek, SeekFrom}, sync::OnceLock};
+use actix_web::Error;
+use chrono::{DateTime, Utc};
+use log::error;
+use paperclip::actix::web::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use uuid::Uuid;
+
+use crate::{
+    config::{get_audit_file, get_audit_sinks, get_audit_webhook_url},
+    model::{audit::AuditRecord, auth::{Principal, RequestContext}, rbac::Action},
+    util::http::post_json,
+};
+
+// Records kept in memory for /audit when there is no file sink
+const RECENT_RECORDS: usize = 1000;
+
+enum Sink {
+    File(String),
+    Stdout,
+    Webhook(String),
+}
+
+struct AuditLog {
+    sinks: Vec<Sink>,
+    last_hash: String,
+    recent: VecDeque<AuditRecord>,
+    // Byte offset of every record in the first file sink, by id
+    offsets: HashMap<String, u64>,
+    file_len: u64,
+}
+
+// Just enough of a record to index it
+#[derive(Deserialize)]
+struct RecordId {
+    id: String,
+}
+
+static AUDIT: OnceLock<Mutex<AuditLog>> = OnceLock::new();
+
+// Configure the sinks from `AUDIT_SINKS`, must be called once at startup
+pub fn init_audit() -> Result<(), String> {
+    let mut sinks = Vec::new();
+    let mut last_hash = String::new();
+    let mut offsets = HashMap::new();
+    let mut file_len = 0;
+    for name in get_audit_sinks() {
+        match name.as_str() {
+            "file" => {
+                let path = get_audit_file().ok_or("AUDIT_FILE must be set for the file audit sink")?;
+                // Continue the hash chain of the existing log
+                if let Ok(content) = fs::read_to_string(&path) {
+                    if let Some(last) = content.lines().rev().find(|line| !line.trim().is_empty()) {
+                        let record: AuditRecord = serde_json::from_str(last)
+                            .map_err(|e| format!("Last record of {} is invalid: {}", path, e))?;
+                        last_hash = record.hash;
+                    }
+                    if !sinks.iter().any(|sink| matches!(sink, Sink::File(_))) {
+                        offsets = index_records(&content);
+                        file_len = content.len() as u64;
+                    }
+                }
+                sinks.push(Sink::File(path));
+            }
+            "stdout" => sinks.push(Sink::Stdout),
+            "webhook" => {
+                let url = get_audit_webhook_url().ok_or("AUDIT_WEBHOOK_URL must be set for the webhook audit sink")?;
+                sinks.push(Sink::Webhook(url));
+            }
+            other => return Err(format!("Unknown audit sink: {}", other)),
+        }
+    }
+    let log = AuditLog { sinks, last_hash, recent: VecDeque::new(), offsets, file_len };
+    AUDIT.set(Mutex::new(log)).map_err(|_| "Audit log already initialized".to_string())
+}
+
+/// Audit record being built while a handler runs
+pub struct AuditEntry {
+    record: AuditRecord,
+}
+
+impl AuditEntry {
+    pub fn new(ctx: &RequestContext, action: Action, namespace: &str, target: String, request: Value) -> Self {
+        AuditEntry {
+            record: AuditRecord {
+                id: Uuid::new_v4().to_string(),
+                time: Utc::now().to_rfc3339(),
+                correlation_id: ctx.correlation_id.clone(),
+                actor: ctx.principal.subject.clone(),
+                auth_method: ctx.principal.auth_method.as_str().to_string(),
+                source_ip: ctx.source_ip.clone(),
+                action: action.to_string(),
+                namespace: namespace.to_string(),
+                target,
+                targets: Vec::new(),
+                request,
+                before: None,
+                after: None,
+                result: String::new(),
+                response: None,
+                error: None,
+                prev_hash: String::new(),
+                hash: String::new(),
+            },
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.record.id
+    }
+
+    pub fn actor(&self) -> &str {
+        &self.record.actor
+    }
+
+    // Every object changed when the target names several of them
+    pub fn targets(&mut self, targets: Vec<String>) {
+        self.record.targets = targets;
+    }
+
+    // State of the target before the change (image, labels, ...)
+    pub fn before(&mut self, state: Value) {
+        self.record.before = Some(state);
+    }
+
+    // State of the target after the change
+    pub fn after(&mut self, state: Value) {
+        self.record.after = Some(state);
+    }
+
+    // Record the outcome of the handler in every sink
+    pub async fn finish<T: Serialize>(mut self, result: &Result<Json<T>, Error>) {
+        match result {
+            Ok(response) => {
+                self.record.result = "success".to_string();
+                self.record.response = serde_json::to_value(&**response).ok();
+            }
+            Err(e) => {
+                self.record.result = "failure".to_string();
+                self.record.error = Some(e.to_string());
+            }
+        }
+        write_record(self.record).await;
+    }
+}
+
+/// Request refused by `auth_middleware`, without principal when the caller could not be authenticated
+pub struct Denial<'a> {
+    pub principal: Option<&'a Principal>,
+    pub correlation_id: &'a str,
+    pub source_ip: Option<String>,
+    pub action: Option<Action>,
+    pub namespace: Option<String>,
+    pub path: &'a str,
+    pub error: String,
+}
+
+// Record a refused request in every sink
+pub async fn record_denial(denial: Denial<'_>) {
+    let record = AuditRecord {
+        id: Uuid::new_v4().to_string(),
+        time: Utc::now().to_rfc3339(),
+        correlation_id: denial.correlation_id.to_string(),
+        actor: denial.principal.map(|p| p.subject.clone()).unwrap_or_default(),
+        auth_method: denial.principal.map(|p| p.auth_method.as_str().to_string()).unwrap_or_default(),
+        source_ip: denial.source_ip,
+        action: denial.action.map(|action| action.to_string()).unwrap_or_default(),
+        namespace: denial.namespace.unwrap_or_default(),
+        target: denial.path.to_string(),
+        targets: Vec::new(),
+        request: Value::Null,
+        before: None,
+        after: None,
+        result: "denied".to_string(),
+        response: None,
+        error: Some(denial.error),
+        prev_hash: String::new(),
+        hash: String::new(),
+    };
+    write_record(record).await;
+}
+
+// Append the record and call the webhooks
+async fn write_record(record: AuditRecord) {
+    let (record, webhooks) = match append(record).await {
+        Some(appended) => appended,
+        None => return,
+    };
+    for url in webhooks {
+        if let Err(e) = post_json(&url, &record).await {
+            error!("Audit webhook failed: {}", e);
+        }
+    }
+}
+
+// Chain the record to the previous one and write it to the local sinks, webhooks are returned to be called without the lock.
+// The lock is held while the file is written so that records stay in the order of the chain.
+async fn append(mut record: AuditRecord) -> Option<(AuditRecord, Vec<String>)> {
+    let mut log = match AUDIT.get() {
+        Some(log) => log.lock().await,
+        None => return None,
+    };
+    record.prev_hash = log.last_hash.clone();
+    record.hash = String::new();
+    let digest = Sha256::digest(serde_json::to_vec(&record).unwrap_or_default());
+    record.hash = hex::encode(digest);
+    log.last_hash = record.hash.clone();
+
+    let line = serde_json::to_string(&record).unwrap_or_default();
+    let mut webhooks = Vec::new();
+    let mut indexed = false;
+    for (i, sink) in log.sinks.iter().enumerate() {
+        match sink {
+            Sink::File(path) => {
+                let written = match OpenOptions::new().create(true).append(true).open(path).await {
+                    Ok(mut file) => file.write_all(format!("{}\n", line).as_bytes()).await,
+                    Err(e) => Err(e),
+                };
+                match written {
+                    Ok(()) => indexed |= log.sinks[..i].iter().all(|sink| !matches!(sink, Sink::File(_))),
+                    Err(e) => error!("Could not write audit record {} to {}: {}", record.id, path, e),
+                }
+            }
+            Sink::Stdout => println!("{}", line),
+            Sink::Webhook(url) => webhooks.push(url.clone()),
+        }
+    }
+    if indexed {
+        let offset = log.file_len;
+        log.offsets.insert(record.id.clone(), offset);
+        log.file_len += line.len() as u64 + 1;
+    }
+    log.recent.push_back(record.clone());
+    if log.recent.len() > RECENT_RECORDS {
+        log.recent.pop_front();
+    }
+    Some((record, webhooks))
+}
+
+/// Filters of /audit, already parsed
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub namespace: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: usize,
+}
+
+impl AuditFilter {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        let time = DateTime::parse_from_rfc3339(&record.time).map(|t| t.with_timezone(&Utc)).ok();
+        self.actor.iter().all(|actor| &record.actor == actor)
+            && self.namespace.iter().all(|namespace| &record.namespace == namespace)
+            && self.target.iter().all(|target| &record.target == target || record.targets.contains(target))
+            && self.since.iter().all(|since| time.is_some_and(|t| t >= *since))
+            && self.until.iter().all(|until| time.is_some_and(|t| t <= *until))
+    }
+}
+
+// Most recent records matching the filter, oldest first. Read from the file sink when there is one,
+// otherwise from the records kept in memory since startup.
+pub async fn query(filter: AuditFilter) -> Result<Vec<AuditRecord>, String> {
+    let file = match AUDIT.get() {
+        Some(log) => {
+            let log = log.lock().await;
+            let file = log.sinks.iter().find_map(|sink| match sink {
+                Sink::File(path) => Some(path.clone()),
+                _ => None,
+            });
+            match file {
+                Some(path) => path,
+                None => {
+                    let records = log.recent.iter().filter(|record| filter.matches(record)).cloned().collect();
+                    return Ok(latest(records, filter.limit));
+                }
+            }
+        }
+        None => return Ok(Vec::new()),
+    };
+    let limit = filter.limit;
+    // Reading and parsing the whole log does not belong on a worker thread
+    let records = tokio::task::spawn_blocking(move || {
+        let content = fs::read_to_string(&file)
+            .map_err(|e| format!("Could not read audit log {}: {}", file, e))?;
+        Ok::<_, String>(content.lines()
+            .filter_map(|line| serde_json::from_str::<AuditRecord>(line).ok())
+            .filter(|record| filter.matches(record))
+            .collect())
+    }).await.map_err(|e| format!("Audit log query failed: {}", e))??;
+    Ok(latest(records, limit))
+}
+
+// Look up records by id through the offset index instead of reading the whole log
+pub async fn records_by_id(ids: Vec<String>) -> Result<HashMap<String, AuditRecord>, String> {
+    let (file, offsets) = match AUDIT.get() {
+        Some(log) => {
+            let log = log.lock().await;
+            let file = log.sinks.iter().find_map(|sink| match sink {
+                Sink::File(path) => Some(path.clone()),
+                _ => None,
+            });
+            match file {
+                Some(path) => {
+                    let offsets: Vec<(String, u64)> = ids.into_iter()
+                        .filter_map(|id| log.offsets.get(&id).map(|offset| (id, *offset)))
+                        .collect();
+                    (path, offsets)
+                }
+                None => {
+                    return Ok(log.recent.iter()
+                        .filter(|record| ids.contains(&record.id))
+                        .map(|record| (record.id.clone(), record.clone()))
+                        .collect());
+                }
+            }
+        }
+        None => return Ok(HashMap::new()),
+    };
+    if offsets.is_empty() {
+        return Ok(HashMap::new());
+    }
+    tokio::task::spawn_blocking(move || {
+        let file_handle = fs::File::open(&file)
+            .map_err(|e| format!("Could not read audit log {}: {}", file, e))?;
+        let mut reader = BufReader::new(file_handle);
+        let mut records = HashMap::new();
+        for (id, offset) in offsets {
+            let mut line = String::new();
+            reader.seek(SeekFrom::Start(offset))
+                .and_then(|_| reader.read_line(&mut line))
+                .map_err(|e| format!("Could not read audit log {}: {}", file, e))?;
+            // The file may have been rotated or edited since it was indexed
+            match serde_json::from_str::<AuditRecord>(&line) {
+                Ok(record) if record.id == id => { records.insert(id, record); }
+                _ => error!("Audit record {} is not at its indexed offset in {}", id, file),
+            }
+        }
+        Ok(records)
+    }).await.map_err(|e| format!("Audit log lookup failed: {}", e))?
+}
+
+// Offset of every record in the content of an audit log
+fn index_records(content: &str) -> HashMap<String, u64> {
+    let mut offsets = HashMap::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        if let Ok(record) = serde_json::from_str::<RecordId>(line) {
+            offsets.insert(record.id, offset);
+        }
+        offset += line.len() as u64;
+    }
+    offsets
+}
+
+// The `limit` most recent records
+fn latest(mut records: Vec<AuditRecord>, limit: usize) -> Vec<AuditRecord> {
+    let skip = records.len().saturating_sub(limit);
+    records.drain(..skip);
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_records_points_at_each_line() {
+        let content = "{\"id\":\"a\"}\n\n{\"id\":\"b\",\"x\":1}\nnot json\n{\"id\":\"c\"}";
+        let offsets = index_records(content);
+        assert_eq!(offsets.len(), 3);
+        for (id, offset) in offsets {
+            let line = content[offset as usize..].lines().next().unwrap();
+            assert_eq!(serde_json::from_str::<RecordId>(line).unwrap().id, id);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::apps::v1::Deployment;
+use kube::{api::{ListParams, Patch, PatchParams}, Api, Client};
+use serde_json::json;
+
+use crate::{
+    model::kubernetes::{CanaryStatus, RolloutStatus},
+    util::{quarantine::selects, rollout::{rollout_status, COMPLETE, FAILED}},
+};
+
+// Label of the canary Deployment and its pods, naming the deployment it is the canary of
+pub const CANARY_OF_LABEL: &str = "officer/canary-of";
+// Label telling canary pods from the stable ones, the primary selector must exclude `track: canary`
+const TRACK_LABEL: &str = "track";
+const CANARY_TRACK: &str = "canary";
+const PHASE_ANNOTATION: &str = "officer/canary-phase";
+const CONTAINER_ANNOTATION: &str = "officer/canary-container";
+const IMAGE_ANNOTATION: &str = "officer/canary-image";
+const PREVIOUS_IMAGE_ANNOTATION: &str = "officer/canary-previous-image";
+const BAKE_SECONDS_ANNOTATION: &str = "officer/canary-bake-seconds";
+const BAKE_UNTIL_ANNOTATION: &str = "officer/canary-bake-until";
+const AUTO_PROMOTE_ANNOTATION: &str = "officer/canary-auto-promote";
+const TRIGGERED_BY_ANNOTATION: &str = "officer/canary-triggered-by";
+
+pub const STARTING: &str = "starting";
+pub const BAKING: &str = "baking";
+pub const READY: &str = "ready";
+pub const PROMOTED: &str = "promoted";
+pub const ABORTED: &str = "aborted";
+
+// Longest bake time, a canary is not meant to take live traffic for longer than a week
+pub const MAX_BAKE_SECONDS: u64 = 7 * 24 * 3600;
+
+// The canary is checked this often while it bakes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn canary_name(name: &str) -> String {
+    format!("{}-canary", name)
+}
+
+/// What a canary runs, kept in the annotations of the canary Deployment
+pub struct CanarySpec {
+    pub container: String,
+    pub image: String,
+    pub previous_image: String,
+    pub replicas: i32,
+    pub bake_seconds: u64,
+    pub auto_promote: bool,
+    pub triggered_by: String,
+}
+
+/// Result of watching a canary
+pub enum BakeOutcome {
+    Passed(CanaryStatus),
+    Failed(CanaryStatus),
+    // The canary was promoted or aborted meanwhile
+    Gone,
+}
+
+// Canary Deployment of `primary`: the same spec with the new image and `spec.replicas`.
+// Its pods keep the labels of the primary pods, so the Services of the deployment send them a share of the traffic,
+// with `track: canary` in addition. Overlapping Deployment selectors are not supported by Kubernetes, the primary
+// selector must not match the canary pods, e.g. with `track: stable` or `officer/canary-of` DoesNotExist.
+pub fn build_canary(primary: &Deployment, spec: &CanarySpec) -> Result<Deployment, String> {
+    let name = primary.metadata.name.clone().unwrap_or_default();
+    let mut canary_spec = primary.spec.clone().ok_or_else(|| format!("Deployment {} has no spec", name))?;
+    canary_spec.replicas = Some(spec.replicas);
+    let canary_labels = [(TRACK_LABEL, CANARY_TRACK), (CANARY_OF_LABEL, name.as_str())];
+    let template = &mut canary_spec.template;
+    let pod_labels = template.metadata.get_or_insert_with(Default::default).labels.get_or_insert_with(Default::default);
+    for (key, value) in canary_labels {
+        pod_labels.insert(key.to_string(), value.to_string());
+    }
+    if selects(&canary_spec.selector, pod_labels) {
+        return Err(format!(
+            "The selector of Deployment {} would also select the canary pods, it needs a {}: stable label \
+            or a {} DoesNotExist expression for a canary deploy", name, TRACK_LABEL, CANARY_OF_LABEL
+        ));
+    }
+    let selector = &mut canary_spec.selector;
+    if let Some(expressions) = selector.match_expressions.as_mut() {
+        expressions.retain(|expression| canary_labels.iter().all(|(key, _)| expression.key != *key));
+    }
+    let match_labels = selector.match_labels.get_or_insert_with(Default::default);
+    for (key, value) in canary_labels {
+        match_labels.insert(key.to_string(), value.to_string());
+    }
+    let template = &mut canary_spec.template;
+    let pod_spec = template.spec.as_mut().ok_or_else(|| format!("Deployment {} has no pod spec", name))?;
+    let container = pod_spec.containers.iter_mut()
+        .chain(pod_spec.init_containers.iter_mut().flatten())
+        .find(|c| c.name == spec.container)
+        .ok_or_else(|| format!("Deployment {} has no container {}", name, spec.container))?;
+    container.image = Some(spec.image.clone());
+
+    let mut labels = primary.metadata.labels.clone().unwrap_or_default();
+    labels.insert(CANARY_OF_LABEL.to_string(), name.clone());
+    let annotations = BTreeMap::from([
+        (PHASE_ANNOTATION.to_string(), STARTING.to_string()),
+        (CONTAINER_ANNOTATION.to_string(), spec.container.clone()),
+        (IMAGE_ANNOTATION.to_string(), spec.image.clone()),
+        (PREVIOUS_IMAGE_ANNOTATION.to_string(), spec.previous_image.clone()),
+        (BAKE_SECONDS_ANNOTATION.to_string(), spec.bake_seconds.to_string()),
+        (AUTO_PROMOTE_ANNOTATION.to_string(), spec.auto_promote.to_string()),
+        (TRIGGERED_BY_ANNOTATION.to_string(), spec.triggered_by.clone()),
+    ]);
+    let mut canary = Deployment::default();
+    canary.metadata.name = Some(canary_name(&name));
+    canary.metadata.namespace = primary.metadata.namespace.clone();
+    canary.metadata.labels = Some(labels);
+    canary.metadata.annotations = Some(annotations);
+    canary.spec = Some(canary_spec);
+    Ok(canary)
+}
+
+fn annotation(canary: &Deployment, key: &str) -> String {
+    canary.metadata.annotations.as_ref().and_then(|a| a.get(key).cloned()).unwrap_or_default()
+}
+
+// Container and image a canary runs
+pub fn canary_image(canary: &Deployment) -> (String, String) {
+    (annotation(canary, CONTAINER_ANNOTATION), annotation(canary, IMAGE_ANNOTATION))
+}
+
+pub fn canary_phase(canary: &Deployment) -> String {
+    annotation(canary, PHASE_ANNOTATION)
+}
+
+pub fn is_auto_promoted(canary: &Deployment) -> bool {
+    annotation(canary, AUTO_PROMOTE_ANNOTATION) != "false"
+}
+
+// State of a canary from its annotations and the rollout of the canary Deployment
+pub async fn canary_status(client: Client, namespace: &str, canary: &Deployment) -> Result<CanaryStatus, String> {
+    let name = canary.metadata.name.clone().unwrap_or_default();
+    let rollout = rollout_status(client, namespace, &name).await?;
+    Ok(status_of(namespace, canary, &rollout))
+}
+
+fn status_of(namespace: &str, canary: &Deployment, rollout: &RolloutStatus) -> CanaryStatus {
+    let name = canary.metadata.name.clone().unwrap_or_default();
+    let phase = annotation(canary, PHASE_ANNOTATION);
+    let message = match phase.as_str() {
+        STARTING => format!("Waiting for the canary to be ready: {}", rollout.message),
+        BAKING => "Canary is ready, watching it until the bake time passes".to_string(),
+        READY => "Bake time passed, waiting for /canary/promote".to_string(),
+        _ => rollout.message.clone(),
+    };
+    let bake_until = Some(annotation(canary, BAKE_UNTIL_ANNOTATION)).filter(|until| !until.is_empty());
+    CanaryStatus {
+        namespace: namespace.to_string(),
+        service_deployment: canary.metadata.labels.as_ref().and_then(|l| l.get(CANARY_OF_LABEL).cloned()).unwrap_or_default(),
+        canary_deployment: name,
+        phase,
+        message,
+        container: annotation(canary, CONTAINER_ANNOTATION),
+        image: annotation(canary, IMAGE_ANNOTATION),
+        previous_image: annotation(canary, PREVIOUS_IMAGE_ANNOTATION),
+        replicas: canary.spec.as_ref().and_then(|spec| spec.replicas).unwrap_or_default(),
+        ready_replicas: rollout.ready_replicas,
+        started: canary.metadata.creation_timestamp.as_ref().map(|t| t.0.to_rfc3339()),
+        bake_until,
+        auto_promote: is_auto_promoted(canary),
+        triggered_by: annotation(canary, TRIGGERED_BY_ANNOTATION),
+        failing_pods: rollout.failing_pods.clone(),
+    }
+}
+
+// Canaries in flight in a namespace
+pub async fn list_canaries(client: Client, namespace: &str) -> Result<Vec<CanaryStatus>, String> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let canaries = deployments.list(&ListParams::default().labels(CANARY_OF_LABEL)).await
+        .map_err(|e| format!("Could not list canaries: {}", e))?;
+    let mut statuses = Vec::new();
+    for canary in &canaries.items {
+        statuses.push(canary_status(client.clone(), namespace, canary).await?);
+    }
+    Ok(statuses)
+}
+
+async fn set_phase(deployments: &Api<Deployment>, name: &str, phase: &str, bake_until: Option<String>) -> Result<(), String> {
+    let mut annotations = BTreeMap::from([(PHASE_ANNOTATION.to_string(), phase.to_string())]);
+    if let Some(until) = bake_until {
+        annotations.insert(BAKE_UNTIL_ANNOTATION.to_string(), until);
+    }
+    let patch = json!({ "metadata": { "annotations": annotations } });
+    deployments.patch(name, &PatchParams::apply("canary"), &Patch::Merge(&patch)).await
+        .map_err(|e| format!("Could not update canary {}: {}", name, e))?;
+    Ok(())
+}
+
+// Watch the canary of deployment `name` until it is ready, then for its bake time.
+// It fails when it is not ready after `timeout`, when a ready canary pod stops being ready,
+// or when a container restarts more than `max_restarts` times.
+pub async fn bake(client: Client, namespace: &str, name: &str, timeout: Duration, max_restarts: i32) -> Result<BakeOutcome, String> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let canary_name = canary_name(name);
+    let started = Instant::now();
+    loop {
+        let canary = match deployments.get_opt(&canary_name).await {
+            Ok(Some(canary)) => canary,
+            Ok(None) => return Ok(BakeOutcome::Gone),
+            Err(e) => return Err(format!("Get canary {} failed: {}", canary_name, e)),
+        };
+        let phase = annotation(&canary, PHASE_ANNOTATION);
+        if phase != STARTING && phase != BAKING {
+            return Ok(BakeOutcome::Gone);
+        }
+        let rollout = rollout_status(client.clone(), namespace, &canary_name).await?;
+        let mut status = status_of(namespace, &canary, &rollout);
+        if let Some(pod) = rollout.failing_pods.iter().find(|pod| pod.restart_count > max_restarts) {
+            status.message = format!(
+                "Container {} of canary pod {} restarted {} times ({})",
+                pod.container.as_deref().unwrap_or_default(), pod.name, pod.restart_count, pod.reason
+            );
+            return Ok(BakeOutcome::Failed(status));
+        }
+        if rollout.state == FAILED {
+            status.message = format!("Canary failed: {}", rollout.message);
+            return Ok(BakeOutcome::Failed(status));
+        }
+        if phase == STARTING {
+            if rollout.state == COMPLETE {
+                let bake_seconds: u64 = annotation(&canary, BAKE_SECONDS_ANNOTATION).parse().unwrap_or_default();
+                let until = Utc::now() + chrono::Duration::seconds(bake_seconds.min(MAX_BAKE_SECONDS) as i64);
+                set_phase(&deployments, &canary_name, BAKING, Some(until.to_rfc3339())).await?;
+            } else if started.elapsed() >= timeout {
+                status.message = format!("Canary not ready after {} seconds: {}", timeout.as_secs(), rollout.message);
+                return Ok(BakeOutcome::Failed(status));
+            }
+        } else if rollout.state != COMPLETE {
+            status.message = format!("Canary stopped being ready: {}", rollout.message);
+            return Ok(BakeOutcome::Failed(status));
+        } else {
+            let bake_until = status.bake_until.as_deref().and_then(|until| DateTime::parse_from_rfc3339(until).ok());
+            if bake_until.is_none_or(|until| until <= Utc::now()) {
+                status.message = "Canary stayed ready for the bake time".to_string();
+                return Ok(BakeOutcome::Passed(status));
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Mark a canary that passed its bake time and waits for a manual promotion
+pub async fn mark_ready(client: Client, namespace: &str, name: &str) -> Result<(), String> {
+    let deployments: Api<Deployment> = Api::namespaced(client, namespace);
+    set_phase(&deployments, &canary_name(name), READY, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::{
+        api::{apps::v1::DeploymentSpec, core::v1::{Container, PodSpec, PodTemplateSpec}},
+        apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement, ObjectMeta},
+    };
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn primary(selector: LabelSelector, pod_labels: &[(&str, &str)]) -> Deployment {
+        Deployment {
+            metadata: ObjectMeta { name: Some("web".to_string()), ..Default::default() },
+            spec: Some(DeploymentSpec {
+                selector,
+                template: PodTemplateSpec {
+                    metadata: Some(ObjectMeta { labels: Some(labels(pod_labels)), ..Default::default() }),
+                    spec: Some(PodSpec {
+                        containers: vec![Container { name: "app".to_string(), image: Some("web:1".to_string()), ..Default::default() }],
+                        ..Default::default()
+                    }),
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn spec() -> CanarySpec {
+        CanarySpec {
+            container: "app".to_string(),
+            image: "web:2".to_string(),
+            previous_image: "web:1".to_string(),
+            replicas: 1,
+            bake_seconds: 60,
+            auto_promote: true,
+            triggered_by: "alice".to_string(),
+        }
+    }
+
+    fn match_labels(pairs: &[(&str, &str)]) -> LabelSelector {
+        LabelSelector { match_labels: Some(labels(pairs)), ..Default::default() }
+    }
+
+    #[test]
+    fn canary_pods_are_not_selected_by_the_primary() {
+        let primary = primary(match_labels(&[("app", "web"), ("track", "stable")]), &[("app", "web"), ("track", "stable")]);
+        let canary = build_canary(&primary, &spec()).unwrap();
+        let canary_spec = canary.spec.unwrap();
+        let pod_labels = canary_spec.template.metadata.unwrap().labels.unwrap();
+        assert_eq!(pod_labels, labels(&[("app", "web"), ("track", "canary"), (CANARY_OF_LABEL, "web")]));
+        assert!(selects(&canary_spec.selector, &pod_labels));
+        assert!(!selects(&primary.spec.unwrap().selector, &pod_labels));
+        assert_eq!(canary_spec.template.spec.unwrap().containers[0].image.as_deref(), Some("web:2"));
+        assert_eq!(canary.metadata.name.as_deref(), Some("web-canary"));
+    }
+
+    #[test]
+    fn canary_of_expression_excludes_canary_pods() {
+        let selector = LabelSelector {
+            match_expressions: Some(vec![LabelSelectorRequirement {
+                key: CANARY_OF_LABEL.to_string(),
+                operator: "DoesNotExist".to_string(),
+                values: None,
+            }]),
+            ..match_labels(&[("app", "web")])
+        };
+        let canary = build_canary(&primary(selector, &[("app", "web")]), &spec()).unwrap();
+        let canary_spec = canary.spec.unwrap();
+        let pod_labels = canary_spec.template.metadata.unwrap().labels.unwrap();
+        assert!(selects(&canary_spec.selector, &pod_labels));
+        assert!(canary_spec.selector.match_expressions.unwrap().is_empty());
+    }
+
+    #[test]
+    fn overlapping_selectors_are_refused() {
+        let primary = primary(match_labels(&[("app", "web")]), &[("app", "web")]);
+        assert!(build_canary(&primary, &spec()).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::model::kubernetes::FieldChange;
+
+// Metadata maintained by the API server, it changes on every write
+const SERVER_FIELDS: [&str; 5] = ["managedFields", "resourceVersion", "generation", "creationTimestamp", "uid"];
+
+// Fields that differ between two versions of an object, the status and server maintained metadata left out
+pub fn object_diff<K: Serialize>(before: &K, after: &K) -> Vec<FieldChange> {
+    let mut before = serde_json::to_value(before).unwrap_or_default();
+    let mut after = serde_json::to_value(after).unwrap_or_default();
+    for object in [&mut before, &mut after] {
+        if let Some(object) = object.as_object_mut() {
+            object.remove("status");
+            if let Some(metadata) = object.get_mut("metadata").and_then(Value::as_object_mut) {
+                for field in SERVER_FIELDS {
+                    metadata.remove(field);
+                }
+            }
+        }
+    }
+    let mut changes = Vec::new();
+    diff_values(String::new(), Some(&before), Some(&after), &mut changes);
+    changes
+}
+
+// Path of a field, keys that are not plain identifiers (labels, annotations) are quoted
+fn field_path(parent: &str, key: &str) -> String {
+    if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        if parent.is_empty() { key.to_string() } else { format!("{}.{}", parent, key) }
+    } else {
+        format!("{}[{:?}]", parent, key)
+    }
+}
+
+// Elements of a list of named objects (containers, volumes, ...) are matched by name, other lists by index
+fn list_entries(list: &[Value]) -> Vec<(String, &Value)> {
+    let names: Vec<Option<&str>> = list.iter().map(|v| v.get("name").and_then(Value::as_str)).collect();
+    if names.iter().all(Option::is_some) {
+        names.into_iter().flatten().map(|name| format!("[name={}]", name)).zip(list).collect()
+    } else {
+        list.iter().enumerate().map(|(index, value)| (format!("[{}]", index), value)).collect()
+    }
+}
+
+fn entry<'a>(entries: &[(String, &'a Value)], key: &str) -> Option<&'a Value> {
+    entries.iter().find(|(k, _)| k == key).map(|(_, value)| *value)
+}
+
+fn diff_values(path: String, before: Option<&Value>, after: Option<&Value>, changes: &mut Vec<FieldChange>) {
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                diff_values(field_path(&path, key), before.get(key), after.get(key), changes);
+            }
+        }
+        (Some(Value::Array(before)), Some(Value::Array(after))) => {
+            let before = list_entries(before);
+            let after = list_entries(after);
+            let mut keys: Vec<&String> = before.iter().map(|(key, _)| key).collect();
+            keys.extend(after.iter().map(|(key, _)| key).filter(|key| !before.iter().any(|(k, _)| k == *key)));
+            for key in keys {
+                diff_values(format!("{}{}", path, key), entry(&before, key), entry(&after, key), changes);
+            }
+        }
+        (before, after) if before != after => changes.push(FieldChange {
+            path,
+            before: before.cloned(),
+            after: after.cloned(),
+        }),
+        _ => {}
+    }
+}
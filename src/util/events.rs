@@ -0,0 +1,35 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::{runtime::events::{Event, EventType, Recorder, Reporter}, Client, Resource};
+use log::error;
+
+use crate::config::get_optional_envar;
+
+// Publish an Event on `object` so the action shows up in `kubectl describe`.
+// Failures are only logged, the action itself already happened.
+// Officer's service account needs permission to create events.events.k8s.io.
+pub async fn publish_event<K>(client: Client, object: &K, type_: EventType, reason: &str, action: &str, note: String)
+where
+    K: Resource<DynamicType = ()>,
+{
+    publish_event_on(client, object.object_ref(&()), type_, reason, action, note).await
+}
+
+// Publish an Event on the object behind `reference`, for dynamic objects like CRD workloads
+pub async fn publish_event_on(client: Client, reference: ObjectReference, type_: EventType, reason: &str, action: &str, note: String) {
+    let reporter = Reporter {
+        controller: "officer".to_string(),
+        instance: get_optional_envar("POD_NAME"),
+    };
+    let name = reference.name.clone().unwrap_or_default();
+    let recorder = Recorder::new(client, reporter, reference);
+    let event = Event {
+        type_,
+        reason: reason.to_string(),
+        note: Some(note),
+        action: action.to_string(),
+        secondary: None,
+    };
+    if let Err(e) = recorder.publish(event).await {
+        error!("Could not publish {} event on {}: {}", reason, name, e);
+    }
+}
@@ -0,0 +1,41 @@
+use oauth2::{http::{header::{ACCEPT, CONTENT_TYPE}, HeaderMap, HeaderValue, Method}, reqwest::async_http_client};
+use serde::{de::DeserializeOwned, Serialize};
+use url::Url;
+
+// GET a JSON document, used for discovery documents and key sets
+pub async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    let request = oauth2::HttpRequest {
+        url: url.clone(),
+        method: Method::GET,
+        headers,
+        body: Vec::new(),
+    };
+    let resp = async_http_client(request).await
+        .map_err(|e| format!("Failed to retrieve {}: {}", url, e))?;
+    if !resp.status_code.is_success() {
+        return Err(format!("{} responded with {}", url, resp.status_code));
+    }
+    serde_json::from_slice(&resp.body).map_err(|e| format!("Failed to parse {}: {}", url, e))
+}
+
+// POST a JSON document, the response body is ignored
+pub async fn post_json<T: Serialize>(url: &str, body: &T) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let request = oauth2::HttpRequest {
+        url: url.clone(),
+        method: Method::POST,
+        headers,
+        body: serde_json::to_vec(body).map_err(|e| e.to_string())?,
+    };
+    let resp = async_http_client(request).await
+        .map_err(|e| format!("Failed to post to {}: {}", url, e))?;
+    if !resp.status_code.is_success() {
+        return Err(format!("{} responded with {}", url, resp.status_code));
+    }
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use std::fmt;
+
+/// Parsed image reference: `[registry/]repository[:tag][@digest]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub registry: Option<String>,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+// A first path component is a registry when it has a dot or a port, or is localhost
+fn is_registry(component: &str) -> bool {
+    component.contains('.') || component.contains(':') || component == "localhost"
+}
+
+fn is_lower_alnum(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit()
+}
+
+// Path component of a repository: lowercase alphanumerics separated by `.`, `_`, `__` or dashes
+fn validate_path_component(component: &str) -> Result<(), String> {
+    let invalid = || format!("Invalid repository path component: {:?}", component);
+    let starts_and_ends_alnum = component.chars().next().is_some_and(is_lower_alnum)
+        && component.chars().last().is_some_and(is_lower_alnum);
+    if !starts_and_ends_alnum {
+        return Err(invalid());
+    }
+    let mut separator = String::new();
+    for c in component.chars() {
+        if is_lower_alnum(c) {
+            let valid_separator = separator.is_empty()
+                || separator == "."
+                || separator == "_"
+                || separator == "__"
+                || separator.chars().all(|s| s == '-');
+            if !valid_separator {
+                return Err(invalid());
+            }
+            separator.clear();
+        } else if matches!(c, '.' | '_' | '-') {
+            separator.push(c);
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(())
+}
+
+fn validate_registry(registry: &str) -> Result<(), String> {
+    let (host, port) = match registry.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (registry, None),
+    };
+    let valid_host = !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        });
+    let valid_port = port.iter().all(|port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+    if valid_host && valid_port {
+        Ok(())
+    } else {
+        Err(format!("Invalid registry: {:?}", registry))
+    }
+}
+
+// Tags are up to 128 characters of letters, digits, `_`, `.` and `-`, not starting with `.` or `-`
+pub fn validate_tag(tag: &str) -> Result<(), String> {
+    let valid = tag.len() <= 128
+        && tag.chars().next().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid image tag: {:?}", tag))
+    }
+}
+
+// Digests are `algorithm:encoded`, sha256 and sha512 are checked for their length
+pub fn validate_digest(digest: &str) -> Result<(), String> {
+    let invalid = || format!("Invalid image digest: {:?}", digest);
+    let (algorithm, encoded) = digest.split_once(':').ok_or_else(invalid)?;
+    let valid_algorithm = !algorithm.is_empty()
+        && algorithm.chars().all(|c| is_lower_alnum(c) || matches!(c, '+' | '.' | '_' | '-'))
+        && algorithm.chars().next().is_some_and(is_lower_alnum);
+    let hex = |len: usize| encoded.len() == len && encoded.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    let valid_encoded = match algorithm {
+        "sha256" => hex(64),
+        "sha512" => hex(128),
+        _ => !encoded.is_empty() && encoded.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '=' | '_' | '-')),
+    };
+    if valid_algorithm && valid_encoded {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Result<Self, String> {
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => {
+                validate_digest(digest)?;
+                (name, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+        let (registry, path) = match name.split_once('/') {
+            Some((first, rest)) if is_registry(first) => (Some(first.to_string()), rest),
+            _ => (None, name),
+        };
+        // After the registry, a colon can only start the tag
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) => {
+                validate_tag(tag)?;
+                (repository, Some(tag.to_string()))
+            }
+            None => (path, None),
+        };
+        if let Some(registry) = &registry {
+            validate_registry(registry)?;
+        }
+        if repository.is_empty() {
+            return Err(format!("Image reference {:?} has no repository", reference));
+        }
+        for component in repository.split('/') {
+            validate_path_component(component)?;
+        }
+        Ok(ImageRef { registry, repository: repository.to_string(), tag, digest })
+    }
+
+    // Registry and repository, without tag and digest
+    pub fn name(&self) -> String {
+        match &self.registry {
+            Some(registry) => format!("{}/{}", registry, self.repository),
+            None => self.repository.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+// Image `current` moved to the requested tag and/or digest, and to another repository when given.
+// A tag alone drops the previous digest, a digest alone drops the previous tag.
+pub fn retarget(current: &str, repository: Option<&str>, tag: Option<&str>, digest: Option<&str>) -> Result<ImageRef, String> {
+    if tag.is_none() && digest.is_none() {
+        return Err("A version or a digest is required".to_string());
+    }
+    let mut image = ImageRef::parse(current)
+        .map_err(|e| format!("Current image {} can not be parsed: {}", current, e))?;
+    if let Some(repository) = repository {
+        let target = ImageRef::parse(repository)?;
+        if target.tag.is_some() || target.digest.is_some() {
+            return Err(format!("Repository {} must not have a tag or digest", repository));
+        }
+        image.registry = target.registry;
+        image.repository = target.repository;
+    }
+    if let Some(tag) = tag {
+        validate_tag(tag)?;
+    }
+    if let Some(digest) = digest {
+        validate_digest(digest)?;
+    }
+    image.tag = tag.map(str::to_string);
+    image.digest = digest.map(str::to_string);
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn image(registry: Option<&str>, repository: &str, tag: Option<&str>, digest: Option<&str>) -> ImageRef {
+        ImageRef {
+            registry: registry.map(str::to_string),
+            repository: repository.to_string(),
+            tag: tag.map(str::to_string),
+            digest: digest.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_docker_hub_names() {
+        assert_eq!(ImageRef::parse("nginx").unwrap(), image(None, "nginx", None, None));
+        assert_eq!(ImageRef::parse("nginx:1.27").unwrap(), image(None, "nginx", Some("1.27"), None));
+        assert_eq!(ImageRef::parse("bitnami/redis:7.2").unwrap(), image(None, "bitnami/redis", Some("7.2"), None));
+        assert_eq!(ImageRef::parse("docker.io/library/nginx:latest").unwrap(),
+            image(Some("docker.io"), "library/nginx", Some("latest"), None));
+    }
+
+    #[test]
+    fn tells_a_registry_port_from_a_tag() {
+        assert_eq!(ImageRef::parse("localhost:5000/app").unwrap(), image(Some("localhost:5000"), "app", None, None));
+        assert_eq!(ImageRef::parse("localhost:5000/app:1.0").unwrap(), image(Some("localhost:5000"), "app", Some("1.0"), None));
+        assert_eq!(ImageRef::parse("localhost/app").unwrap(), image(Some("localhost"), "app", None, None));
+        assert_eq!(ImageRef::parse("ghcr.io/org/team/app:v2").unwrap(), image(Some("ghcr.io"), "org/team/app", Some("v2"), None));
+        // Without a dot, port or localhost the first component is part of the repository
+        assert_eq!(ImageRef::parse("org/app:v2").unwrap(), image(None, "org/app", Some("v2"), None));
+    }
+
+    #[test]
+    fn parses_digests() {
+        assert_eq!(ImageRef::parse(&format!("nginx@{}", DIGEST)).unwrap(), image(None, "nginx", None, Some(DIGEST)));
+        assert_eq!(ImageRef::parse(&format!("registry.local:443/app:1.0@{}", DIGEST)).unwrap(),
+            image(Some("registry.local:443"), "app", Some("1.0"), Some(DIGEST)));
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        for reference in [
+            "", "Nginx", "nginx:", "nginx:-1", "app:a/b", "registry.local:port/app", "-registry.local/app",
+            "org//app", "app_", "nginx@sha256:abc", "nginx@md5", &format!("nginx@{}", DIGEST.to_uppercase()),
+            &format!("app:{}", "a".repeat(129)),
+        ] {
+            assert!(ImageRef::parse(reference).is_err(), "{:?} was accepted", reference);
+        }
+    }
+
+    #[test]
+    fn displays_what_it_parsed() {
+        for reference in ["nginx", "localhost:5000/app:1.0", &format!("ghcr.io/org/app:v2@{}", DIGEST)] {
+            assert_eq!(ImageRef::parse(reference).unwrap().to_string(), reference);
+        }
+    }
+
+    #[test]
+    fn retargets_the_tag_and_digest() {
+        let current = format!("registry.local:5000/app:1.0@{}", DIGEST);
+        assert_eq!(retarget(&current, None, Some("1.1"), None).unwrap().to_string(), "registry.local:5000/app:1.1");
+        assert_eq!(retarget("app:1.0", None, None, Some(DIGEST)).unwrap().to_string(), format!("app@{}", DIGEST));
+        assert_eq!(retarget("app:1.0", None, Some("1.1"), Some(DIGEST)).unwrap().to_string(), format!("app:1.1@{}", DIGEST));
+    }
+
+    #[test]
+    fn retargets_the_repository() {
+        let retargeted = retarget("registry.local:5000/app:1.0", Some("ghcr.io/org/app"), Some("2.0"), None).unwrap();
+        assert_eq!(retargeted, image(Some("ghcr.io"), "org/app", Some("2.0"), None));
+        // A Docker Hub repository drops the registry of the current image
+        assert_eq!(retarget("registry.local/app:1.0", Some("nginx"), Some("1.27"), None).unwrap().to_string(), "nginx:1.27");
+        assert!(retarget("app:1.0", Some("nginx:1.27"), Some("1.27"), None).is_err());
+        assert!(retarget("app:1.0", Some(&format!("nginx@{}", DIGEST)), Some("1.27"), None).is_err());
+    }
+
+    #[test]
+    fn retarget_needs_a_valid_version() {
+        assert!(retarget("app:1.0", None, None, None).is_err());
+        assert!(retarget("app:1.0", None, Some("-bad"), None).is_err());
+        assert!(retarget("app:1.0", None, None, Some("sha256:short")).is_err());
+        assert!(retarget("Not An Image", None, Some("1.0"), None).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+use jsonwebtoken::{decode, decode_header, jwk::{Jwk, JwkSet}, Algorithm, DecodingKey, TokenData, Validation};
+use serde::de::DeserializeOwned;
+use tokio::sync::RwLock;
+
+use crate::{model::error::AuthError, util::http::get_json};
+
+// Keys are fetched again after this long, rotated keys are picked up even without an unknown kid
+const MAX_AGE: Duration = Duration::from_secs(3600);
+// Unknown key ids must not make us hammer the issuer
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Only asymmetric algorithms, a shared secret can not come from a JWKS
+const ALLOWED_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256, Algorithm::RS384, Algorithm::RS512,
+    Algorithm::PS256, Algorithm::PS384, Algorithm::PS512,
+    Algorithm::ES256, Algorithm::ES384, Algorithm::EdDSA,
+];
+
+/// Signing keys of an issuer, fetched from its JWKS endpoint and cached
+pub struct JwksCache {
+    uri: String,
+    keys: RwLock<Option<(Instant, JwkSet)>>,
+}
+
+fn select<'a>(set: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => set.find(kid),
+        // Without a key id the choice is only unambiguous with a single key
+        None if set.keys.len() == 1 => set.keys.first(),
+        None => None,
+    }
+}
+
+impl JwksCache {
+    pub fn new(uri: String) -> Self {
+        JwksCache { uri, keys: RwLock::new(None) }
+    }
+
+    // Find the key with `kid`, the key set is refreshed when the key is unknown
+    pub async fn find(&self, kid: Option<&str>) -> Result<Jwk, AuthError> {
+        let unknown = || AuthError::UnknownKey(format!("Unknown signing key {}", kid.unwrap_or("<none>")));
+        {
+            let keys = self.keys.read().await;
+            if let Some((fetched, set)) = keys.as_ref() {
+                if fetched.elapsed() < MAX_AGE {
+                    if let Some(jwk) = select(set, kid) {
+                        return Ok(jwk.clone());
+                    }
+                    if fetched.elapsed() < MIN_REFRESH_INTERVAL {
+                        return Err(unknown());
+                    }
+                }
+            }
+        }
+        let mut keys = self.keys.write().await;
+        let set: JwkSet = get_json(&self.uri).await
+            .map_err(|e| AuthError::Unavailable(format!("Could not fetch signing keys: {}", e)))?;
+        let jwk = select(&set, kid).cloned();
+        *keys = Some((Instant::now(), set));
+        jwk.ok_or_else(unknown)
+    }
+
+    // Verify the signature, issuer, audience and lifetime of a token signed by one of the keys
+    pub async fn verify<T: DeserializeOwned>(&self, token: &str, issuer: &str, audiences: &[String]) -> Result<TokenData<T>, AuthError> {
+        let header = decode_header(token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(AuthError::InvalidToken(format!("Unsupported token algorithm {:?}", header.alg)));
+        }
+        let jwk = self.find(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| AuthError::UnknownKey(format!("Invalid signing key: {}", e)))?;
+        // A token for any audience of the issuer must never be accepted
+        if audiences.is_empty() {
+            return Err(AuthError::InvalidToken("No audience is accepted for this issuer".to_string()));
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(audiences);
+        validation.validate_nbf = true;
+        Ok(decode::<T>(token, &key, &validation)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn unreachable_key_set_is_unavailable() {
+        let cache = JwksCache::new("http://127.0.0.1:9/jwks".to_string());
+        let err = cache.find(Some("kid")).await.unwrap_err();
+        assert!(matches!(err, AuthError::Unavailable(_)), "{:?}", err);
+    }
+
+    #[actix_web::test]
+    async fn missing_key_is_unknown() {
+        let set: JwkSet = serde_json::from_str(r#"{"keys":[]}"#).unwrap();
+        let cache = JwksCache { uri: String::new(), keys: RwLock::new(Some((Instant::now(), set))) };
+        let err = cache.find(Some("kid")).await.unwrap_err();
+        assert!(matches!(err, AuthError::UnknownKey(_)), "{:?}", err);
+    }
+}
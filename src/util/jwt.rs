@@ -1,30 +1,201 @@
-use std::env;
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey, TokenData};
+use std::{collections::HashMap, env, fs, sync::{Mutex, OnceLock}};
+use chrono::Utc;
+use jsonwebtoken::{encode, decode, errors::ErrorKind, Header, Algorithm, Validation, EncodingKey, DecodingKey, TokenData};
+use log::error;
 use serde::{Deserialize, Serialize};
 use dotenv::dotenv;
+use uuid::Uuid;
+
+use crate::{config::{get_access_token_ttl, get_refresh_token_ttl, get_revocation_file, get_session_ttl}, model::auth::{Membership, TokenResponse}};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
 
 // Define the claims structure
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    pub iat: usize,
+    pub nbf: usize,
+    pub jti: String,
+    // Login session, shared by every token derived from the same login
+    pub sid: String,
+    // Login time of the session, the membership claims are as old as this
+    pub auth_time: usize,
+    pub typ: TokenType,
+    #[serde(flatten)]
+    pub membership: Membership,
 }
+
 pub fn get_jwt_secret_key() -> String {
     dotenv().ok();  // Load environment variables from .env file
     env::var("OFFICER_SECRET_KEY").expect("OFFICER_SECRET_KEY must be set")
 }
-// Create a JWT token
-pub fn create_token(sub: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let claims = Claims {
-        sub: sub.to_owned(),
-        exp: 10000000000, // Set your expiration time here
-    };
+
+fn now() -> usize {
+    Utc::now().timestamp() as usize
+}
+
+fn sign(claims: &Claims) -> Result<String, jsonwebtoken::errors::Error> {
     let encoding_key = EncodingKey::from_secret(get_jwt_secret_key().as_ref());
-    encode(&Header::new(Algorithm::HS256), &claims, &encoding_key)
+    encode(&Header::new(Algorithm::HS256), claims, &encoding_key)
 }
 
-// Validate a JWT token
-pub fn validate_token(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+// When the login session started at `auth_time` ends
+fn session_end(auth_time: usize) -> usize {
+    auth_time + get_session_ttl().max(0) as usize
+}
+
+// Claims of a new token, it expires with the session at the latest
+fn new_claims(sub: &str, membership: &Membership, sid: &str, auth_time: usize, typ: TokenType, ttl: i64) -> Claims {
+    let iat = now();
+    Claims {
+        sub: sub.to_owned(),
+        exp: (iat + ttl.max(0) as usize).min(session_end(auth_time)),
+        iat,
+        nbf: iat,
+        jti: Uuid::new_v4().to_string(),
+        sid: sid.to_owned(),
+        auth_time,
+        typ,
+        membership: membership.clone(),
+    }
+}
+
+// Create an access token and a refresh token for a new login session
+pub fn create_token(sub: &str, membership: &Membership) -> Result<TokenResponse, jsonwebtoken::errors::Error> {
+    create_token_pair(sub, membership, &Uuid::new_v4().to_string(), now())
+}
+
+fn create_token_pair(sub: &str, membership: &Membership, sid: &str, auth_time: usize) -> Result<TokenResponse, jsonwebtoken::errors::Error> {
+    let access = new_claims(sub, membership, sid, auth_time, TokenType::Access, get_access_token_ttl());
+    let refresh = new_claims(sub, membership, sid, auth_time, TokenType::Refresh, get_refresh_token_ttl());
+    Ok(TokenResponse {
+        token: sign(&access)?,
+        refresh_token: sign(&refresh)?,
+        // The session can end in the second between the refresh check and now
+        expires_in: access.exp.saturating_sub(access.iat) as i64,
+    })
+}
+
+fn decode_token(token: &str, typ: Option<TokenType>) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
     let decoding_key = DecodingKey::from_secret(get_jwt_secret_key().as_ref());
-    decode::<Claims>(token, &decoding_key, &Validation::new(Algorithm::HS256))
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_nbf = true;
+    let token = decode::<Claims>(token, &decoding_key, &validation)?;
+    if typ.is_some_and(|typ| typ != token.claims.typ) || is_revoked(&token.claims.jti) || is_revoked(&token.claims.sid) {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(token)
+}
+
+// Validate an access token
+pub fn validate_token(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+    decode_token(token, Some(TokenType::Access))
+}
+
+// Validate an access or refresh token
+pub fn validate_any_token(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+    decode_token(token, None)
+}
+
+// Exchange a refresh token for a new token pair, the refresh token can only be used once.
+// The session is not extended, once `JWT_SESSION_TTL` has passed since the login the user has to log in again
+// so that a membership removed from the provider is not carried forward.
+pub fn refresh_token(token: &str) -> Result<TokenResponse, jsonwebtoken::errors::Error> {
+    let claims = decode_token(token, Some(TokenType::Refresh))?.claims;
+    // The token may still be accepted within the leeway of its expiry, which is capped at the session end
+    if now() >= session_end(claims.auth_time) {
+        return Err(ErrorKind::ExpiredSignature.into());
+    }
+    // Checked and revoked under one lock, a concurrent refresh with the same token fails here
+    if !revoke(&claims.jti, claims.exp) {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    create_token_pair(&claims.sub, &claims.membership, &claims.sid, claims.auth_time)
+}
+
+// Revoke every token issued for the login session of `claims`
+pub fn revoke_session(claims: &Claims) {
+    // Tokens of the session are rotated on refresh, none can outlive a refresh token issued now
+    revoke(&claims.sid, now() + get_refresh_token_ttl().max(0) as usize);
+    revoke(&claims.jti, claims.exp);
+}
+
+fn revocation_list() -> &'static Mutex<HashMap<String, usize>> {
+    static REVOKED: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+    REVOKED.get_or_init(|| {
+        let revoked = get_revocation_file()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Mutex::new(revoked)
+    })
+}
+
+// Add a token or session id to the revocation list until `until` (unix timestamp),
+// false when it was already revoked
+fn revoke(id: &str, until: usize) -> bool {
+    let mut revoked = revocation_list().lock().unwrap();
+    let now = now();
+    // Expired entries are useless, the tokens are rejected anyway
+    revoked.retain(|_, exp| *exp > now);
+    if revoked.insert(id.to_owned(), until).is_some() {
+        return false;
+    }
+    if let Some(path) = get_revocation_file() {
+        let persisted = serde_json::to_string(&*revoked)
+            .map_err(|e| e.to_string())
+            .and_then(|content| fs::write(&path, content).map_err(|e| e.to_string()));
+        if let Err(e) = persisted {
+            error!("Could not persist revocation list to {}: {}", path, e);
+        }
+    }
+    true
+}
+
+fn is_revoked(id: &str) -> bool {
+    revocation_list().lock().unwrap().contains_key(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_secret_key() {
+        env::set_var("OFFICER_SECRET_KEY", "a".repeat(64));
+    }
+
+    #[test]
+    fn refresh_is_refused_once_the_session_ended() {
+        set_secret_key();
+        // The refresh token expired 10 seconds ago with its session, inside the validation leeway
+        let auth_time = now() - get_session_ttl() as usize - 10;
+        let refresh = new_claims("alice", &Membership::default(), "session", auth_time, TokenType::Refresh, 3600);
+        let token = sign(&refresh).unwrap();
+        assert!(decode_token(&token, Some(TokenType::Refresh)).is_ok());
+        let err = refresh_token(&token).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::ExpiredSignature);
+    }
+
+    #[test]
+    fn expires_in_is_not_negative_at_the_session_end() {
+        set_secret_key();
+        let auth_time = now() - get_session_ttl() as usize - 10;
+        let pair = create_token_pair("alice", &Membership::default(), "session", auth_time).unwrap();
+        assert_eq!(pair.expires_in, 0);
+    }
+
+    #[test]
+    fn a_refresh_token_is_used_once() {
+        set_secret_key();
+        let pair = create_token("alice", &Membership::default()).unwrap();
+        assert!(refresh_token(&pair.refresh_token).is_ok());
+        assert_eq!(*refresh_token(&pair.refresh_token).unwrap_err().kind(), ErrorKind::InvalidToken);
+    }
 }
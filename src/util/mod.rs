@@ -1,2 +1,20 @@
 pub mod time_helper;
-pub mod jwt;
\ No newline at end of file
+pub mod jwt;
+pub mod rbac;
+pub mod reloadable;
+pub mod api_key;
+pub mod http;
+pub mod jwks;
+pub mod trusted_issuer;
+pub mod token_review;
+pub mod audit;
+pub mod events;pub mod rollout;
+pub mod image_ref;
+pub mod registry;
+pub mod diff;
+pub mod workload;
+pub mod scale;
+pub mod canary;
+pub mod playbook;
+pub mod quarantine;
+pub mod forensics;
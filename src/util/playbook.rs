@@ -0,0 +1,100 @@
+use std::{collections::HashSet, sync::{Arc, OnceLock}};
+use url::Url;
+
+use crate::{
+    config::get_playbooks_file,
+    model::{falco::FalcoEvent, playbook::{Playbook, PlaybookAction, PlaybookActionKind}},
+    util::reloadable::Reloadable,
+};
+
+static PLAYBOOKS: OnceLock<Option<Reloadable<Vec<Playbook>>>> = OnceLock::new();
+
+// Rule Officer responded to before playbooks existed, still isolated when there is no playbook file
+const DEFAULT_RULE: &str = "network_scan_process_in_container";
+
+fn default_playbooks() -> Vec<Playbook> {
+    vec![Playbook {
+        name: "network-scan".to_string(),
+        rules: vec![DEFAULT_RULE.to_string()],
+        min_priority: None,
+        tags: Vec::new(),
+        output_fields: Default::default(),
+        forensics: false,
+        actions: vec![PlaybookAction {
+            action: PlaybookActionKind::IsolateNetwork,
+            labels: Default::default(),
+            annotations: Default::default(),
+            grace_period_seconds: None,
+            url: None,
+            detach: false,
+        }],
+    }]
+}
+
+fn validate_action(playbook: &str, action: &PlaybookAction) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("Playbook {}: {} {}", playbook, action.action, reason));
+    match action.action {
+        PlaybookActionKind::Label if action.labels.is_empty() => invalid("needs labels"),
+        PlaybookActionKind::Annotate if action.annotations.is_empty() => invalid("needs annotations"),
+        _ if action.detach && action.action != PlaybookActionKind::IsolateNetwork => invalid("can not detach, only isolate_network does"),
+        PlaybookActionKind::Notify => match action.url.as_deref().map(Url::parse) {
+            Some(Ok(_)) => Ok(()),
+            Some(Err(e)) => invalid(&format!("has an invalid url: {}", e)),
+            None => invalid("needs a url"),
+        },
+        _ => Ok(()),
+    }
+}
+
+// Parse and validate a playbook file
+pub fn parse_playbooks(content: &str) -> Result<Vec<Playbook>, String> {
+    let playbooks: Vec<Playbook> = serde_yaml::from_str(content)
+        .map_err(|e| format!("Invalid playbooks: {}", e))?;
+    let mut names = HashSet::new();
+    for playbook in &playbooks {
+        if !names.insert(playbook.name.as_str()) {
+            return Err(format!("Playbook {} is defined twice", playbook.name));
+        }
+        // A playbook without matcher would respond to every alert
+        if playbook.rules.is_empty() && playbook.tags.is_empty() && playbook.min_priority.is_none() && playbook.output_fields.is_empty() {
+            return Err(format!("Playbook {} needs rules, tags, min_priority or output_fields to match alerts", playbook.name));
+        }
+        if playbook.actions.is_empty() {
+            return Err(format!("Playbook {} has no actions", playbook.name));
+        }
+        for action in &playbook.actions {
+            validate_action(&playbook.name, action)?;
+        }
+    }
+    Ok(playbooks)
+}
+
+// Load the playbooks from `PLAYBOOKS_FILE`, must be called once at startup
+pub fn init_playbooks() -> Result<(), String> {
+    let playbooks = match get_playbooks_file() {
+        Some(path) => Some(Reloadable::load(&path, parse_playbooks)?),
+        None => None,
+    };
+    PLAYBOOKS.set(playbooks).map_err(|_| "Playbooks already initialized".to_string())
+}
+
+// Playbooks in use, the built-in network scan playbook without `PLAYBOOKS_FILE`
+pub fn playbooks() -> Arc<Vec<Playbook>> {
+    match PLAYBOOKS.get() {
+        Some(Some(playbooks)) => playbooks.get(),
+        _ => Arc::new(default_playbooks()),
+    }
+}
+
+// File the playbooks come from and the error of its last reload
+pub fn playbooks_source() -> (Option<String>, Option<String>) {
+    match PLAYBOOKS.get() {
+        Some(Some(playbooks)) => (Some(playbooks.path().to_string()), playbooks.reload_error()),
+        _ => (None, None),
+    }
+}
+
+// First playbook responding to a Falco event
+pub fn find_playbook(playbooks: &[Playbook], event: &FalcoEvent) -> Option<Playbook> {
+    playbooks.iter().find(|playbook| playbook.matches(event)).cloned()
+}
@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, BTreeSet};
+use k8s_openapi::{
+    api::{
+        apps::v1::{DaemonSet, ReplicaSet},
+        core::v1::{Pod, Service},
+        networking::v1::{IPBlock, NetworkPolicy, NetworkPolicyEgressRule, NetworkPolicyPeer, NetworkPolicyPort, NetworkPolicySpec},
+    },
+    apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+};
+use kube::{api::{ListParams, Patch, PatchParams}, Api, Client};
+
+use crate::{
+    config::{get_quarantine_allow_dns, get_quarantine_egress_allow, get_quarantine_policy_managed},
+    model::kubernetes::{DetachedPod, QuarantinePolicyStatus},
+};
+
+/// Labels to put back on a detached pod
+pub struct Reattachment {
+    pub labels: BTreeMap<String, String>,
+    /// Labels kept off the pod because a controller would adopt it again
+    pub held_back: BTreeMap<String, String>,
+    /// ReplicaSets and DaemonSets the held back labels would make adopt the pod
+    pub controllers: Vec<String>,
+}
+
+// Label of isolated pods, selected by the quarantine NetworkPolicy
+pub const ISOLATE_LABEL: &str = "isolate";
+pub const QUARANTINE_POLICY: &str = "officer-quarantine";
+// Annotation of detached pods with the JSON of the labels removed from them
+pub const DETACHED_ANNOTATION: &str = "officer/detached-labels";
+const FIELD_MANAGER: &str = "officer-quarantine";
+
+// Egress rule of one `cidr[:port]` entry of `QUARANTINE_EGRESS_ALLOW`, the port is TCP
+fn allowlist_rule(entry: &str) -> Result<NetworkPolicyEgressRule, String> {
+    let (cidr, port) = match entry.rsplit_once(':') {
+        // An IPv6 CIDR has colons but its prefix length follows the slash
+        Some((cidr, port)) if !port.contains('/') => {
+            let port: i32 = port.parse().map_err(|_| format!("Invalid port in QUARANTINE_EGRESS_ALLOW entry {}", entry))?;
+            (cidr, Some(port))
+        }
+        _ => (entry, None),
+    };
+    if !cidr.contains('/') {
+        return Err(format!("QUARANTINE_EGRESS_ALLOW entry {} must be a CIDR, e.g. 10.0.0.5/32:443", entry));
+    }
+    Ok(NetworkPolicyEgressRule {
+        to: Some(vec![NetworkPolicyPeer {
+            ip_block: Some(IPBlock { cidr: cidr.to_string(), except: None }),
+            ..Default::default()
+        }]),
+        ports: port.map(|port| vec![NetworkPolicyPort {
+            port: Some(IntOrString::Int(port)),
+            protocol: Some("TCP".to_string()),
+            end_port: None,
+        }]),
+    })
+}
+
+// Egress to the cluster DNS, which runs as `k8s-app: kube-dns` in kube-system for both kube-dns and CoreDNS
+fn dns_rule() -> NetworkPolicyEgressRule {
+    let port = |protocol: &str| NetworkPolicyPort {
+        port: Some(IntOrString::Int(53)),
+        protocol: Some(protocol.to_string()),
+        end_port: None,
+    };
+    NetworkPolicyEgressRule {
+        to: Some(vec![NetworkPolicyPeer {
+            namespace_selector: Some(LabelSelector {
+                match_labels: Some(BTreeMap::from([("kubernetes.io/metadata.name".to_string(), "kube-system".to_string())])),
+                ..Default::default()
+            }),
+            pod_selector: Some(LabelSelector {
+                match_labels: Some(BTreeMap::from([("k8s-app".to_string(), "kube-dns".to_string())])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }]),
+        ports: Some(vec![port("UDP"), port("TCP")]),
+    }
+}
+
+// Destinations isolated pods can still reach, as reported to callers
+fn egress_allowed() -> Vec<String> {
+    let mut allowed = get_quarantine_egress_allow();
+    if get_quarantine_allow_dns() {
+        allowed.push("cluster DNS".to_string());
+    }
+    allowed
+}
+
+// Quarantine policy of a namespace: deny all ingress, and all egress except the allowlist
+pub fn quarantine_policy(namespace: &str) -> Result<NetworkPolicy, String> {
+    let mut egress = get_quarantine_egress_allow().iter()
+        .map(|entry| allowlist_rule(entry))
+        .collect::<Result<Vec<_>, _>>()?;
+    if get_quarantine_allow_dns() {
+        egress.push(dns_rule());
+    }
+    let mut policy = NetworkPolicy::default();
+    policy.metadata.name = Some(QUARANTINE_POLICY.to_string());
+    policy.metadata.namespace = Some(namespace.to_string());
+    policy.metadata.labels = Some(BTreeMap::from([("app.kubernetes.io/managed-by".to_string(), "officer".to_string())]));
+    policy.spec = Some(NetworkPolicySpec {
+        pod_selector: LabelSelector {
+            match_labels: Some(BTreeMap::from([(ISOLATE_LABEL.to_string(), "true".to_string())])),
+            ..Default::default()
+        },
+        policy_types: Some(vec!["Ingress".to_string(), "Egress".to_string()]),
+        ingress: Some(Vec::new()),
+        egress: Some(egress),
+    });
+    Ok(policy)
+}
+
+// Whether the label selector selects an object with `labels`
+pub fn selects(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let match_labels = selector.match_labels.iter().flatten().all(|(key, value)| labels.get(key) == Some(value));
+    let match_expressions = selector.match_expressions.iter().flatten().all(|expression| {
+        let value = labels.get(&expression.key);
+        let values = expression.values.as_deref().unwrap_or_default();
+        match expression.operator.as_str() {
+            "In" => value.is_some_and(|value| values.contains(value)),
+            "NotIn" => value.is_none_or(|value| !values.contains(value)),
+            "Exists" => value.is_some(),
+            "DoesNotExist" => value.is_none(),
+            _ => false,
+        }
+    });
+    match_labels && match_expressions
+}
+
+// Create the quarantine policy of the namespace or put it back to the expected spec with a server-side apply.
+// When `QUARANTINE_POLICY_MANAGED` is false the policy is only looked up.
+pub async fn ensure_quarantine_policy(client: Client, namespace: &str, dry_run: bool) -> Result<(Option<NetworkPolicy>, QuarantinePolicyStatus), String> {
+    let policies: Api<NetworkPolicy> = Api::namespaced(client, namespace);
+    let current = policies.get_opt(QUARANTINE_POLICY).await
+        .map_err(|e| format!("Get NetworkPolicy {} failed: {}", QUARANTINE_POLICY, e))?;
+    let mut status = QuarantinePolicyStatus {
+        name: QUARANTINE_POLICY.to_string(),
+        state: String::new(),
+        exists: current.is_some(),
+        selects_pod: false,
+        egress_allowed: egress_allowed(),
+    };
+    if !get_quarantine_policy_managed() {
+        status.state = if current.is_some() { "unmanaged" } else { "missing" }.to_string();
+        return Ok((current, status));
+    }
+    let desired = quarantine_policy(namespace)?;
+    let mut params = PatchParams::apply(FIELD_MANAGER).force();
+    if dry_run {
+        params = params.dry_run();
+    }
+    let applied = policies.patch(QUARANTINE_POLICY, &params, &Patch::Apply(&desired)).await
+        .map_err(|e| format!("Could not apply NetworkPolicy {}: {}", QUARANTINE_POLICY, e))?;
+    status.state = match &current {
+        None => "created",
+        Some(current) if current.spec == applied.spec => "unchanged",
+        Some(_) => "reconciled",
+    }.to_string();
+    status.exists = !dry_run || current.is_some();
+    Ok((Some(applied), status))
+}
+
+// Label keys the selector needs on an object to select it
+fn selector_keys(selector: &LabelSelector) -> impl Iterator<Item = &String> {
+    let match_labels = selector.match_labels.iter().flatten().map(|(key, _)| key);
+    let match_expressions = selector.match_expressions.iter().flatten()
+        .filter(|expression| matches!(expression.operator.as_str(), "In" | "Exists"))
+        .map(|expression| &expression.key);
+    match_labels.chain(match_expressions)
+}
+
+// Labels to remove from the pod to take it out of the selectors of its ReplicaSet or DaemonSet, which then release
+// the pod and start a replacement, and of the Services of the namespace. The labels of an already detached pod are
+// merged with the ones it lost before.
+pub async fn plan_detachment(client: Client, namespace: &str, pod: &Pod) -> Result<DetachedPod, String> {
+    let labels = pod.metadata.labels.clone().unwrap_or_default();
+    let mut keys = BTreeSet::new();
+    let mut detached = DetachedPod::default();
+    if let Some(owner) = pod.metadata.owner_references.iter().flatten().find(|owner| owner.controller == Some(true)) {
+        let controller = format!("{}/{}", owner.kind, owner.name);
+        let selector = match owner.kind.as_str() {
+            "ReplicaSet" => Api::<ReplicaSet>::namespaced(client.clone(), namespace).get(&owner.name).await
+                .map_err(|e| format!("Get ReplicaSet {} failed: {}", owner.name, e))?
+                .spec.map(|spec| spec.selector),
+            "DaemonSet" => Api::<DaemonSet>::namespaced(client.clone(), namespace).get(&owner.name).await
+                .map_err(|e| format!("Get DaemonSet {} failed: {}", owner.name, e))?
+                .spec.map(|spec| spec.selector),
+            _ => None,
+        };
+        match selector {
+            Some(selector) => {
+                keys.extend(selector_keys(&selector).cloned());
+                detached.controller = Some(controller);
+            },
+            None => detached.kept_by = Some(controller),
+        }
+    }
+    let services: Api<Service> = Api::namespaced(client, namespace);
+    let services = services.list(&ListParams::default()).await
+        .map_err(|e| format!("List services failed: {}", e))?;
+    for service in services {
+        let selector = service.spec.and_then(|spec| spec.selector).unwrap_or_default();
+        // A Service without selector has its endpoints managed by hand
+        if selector.is_empty() || selector.iter().any(|(key, value)| labels.get(key) != Some(value)) {
+            continue;
+        }
+        keys.extend(selector.into_keys());
+        detached.services.push(format!("Service/{}", service.metadata.name.unwrap_or_default()));
+    }
+    keys.remove(ISOLATE_LABEL);
+    detached.labels = detached_labels(pod)?.unwrap_or_default();
+    for key in keys {
+        if let Some(value) = labels.get(&key) {
+            detached.labels.entry(key).or_insert_with(|| value.clone());
+        }
+    }
+    Ok(detached)
+}
+
+// Labels removed from a detached pod, from its annotation
+pub fn detached_labels(pod: &Pod) -> Result<Option<BTreeMap<String, String>>, String> {
+    match pod.metadata.annotations.as_ref().and_then(|annotations| annotations.get(DETACHED_ANNOTATION)) {
+        Some(value) => serde_json::from_str(value)
+            .map(Some)
+            .map_err(|e| format!("Invalid {} annotation: {}", DETACHED_ANNOTATION, e)),
+        None => Ok(None),
+    }
+}
+
+// Split the labels removed from a detached pod into the ones to put back and the ones that make a ReplicaSet or DaemonSet
+// of the namespace select it again. The controller would adopt the pod and delete a surplus one, possibly its clean
+// replacement. Labels Services select are only held back when the controller would select the pod without them.
+pub async fn plan_reattachment(client: Client, namespace: &str, pod: &Pod, removed: BTreeMap<String, String>) -> Result<Reattachment, String> {
+    let mut current = pod.metadata.labels.clone().unwrap_or_default();
+    current.remove(ISOLATE_LABEL);
+    let replica_sets = Api::<ReplicaSet>::namespaced(client.clone(), namespace).list(&ListParams::default()).await
+        .map_err(|e| format!("List ReplicaSets failed: {}", e))?;
+    let daemon_sets = Api::<DaemonSet>::namespaced(client.clone(), namespace).list(&ListParams::default()).await
+        .map_err(|e| format!("List DaemonSets failed: {}", e))?;
+    let services = Api::<Service>::namespaced(client, namespace).list(&ListParams::default()).await
+        .map_err(|e| format!("List services failed: {}", e))?;
+    let service_keys = services.into_iter()
+        .flat_map(|service| service.spec.and_then(|spec| spec.selector).unwrap_or_default().into_keys())
+        .collect();
+    let selectors = replica_sets.into_iter()
+        .filter_map(|rs| Some((format!("ReplicaSet/{}", rs.metadata.name?), rs.spec?.selector)))
+        .chain(daemon_sets.into_iter().filter_map(|ds| Some((format!("DaemonSet/{}", ds.metadata.name?), ds.spec?.selector))));
+    Ok(reattachment(&current, removed, selectors, &service_keys))
+}
+
+fn reattachment(current: &BTreeMap<String, String>, removed: BTreeMap<String, String>,
+                selectors: impl Iterator<Item = (String, LabelSelector)>, service_keys: &BTreeSet<String>) -> Reattachment {
+    let mut plan = Reattachment { labels: removed, held_back: BTreeMap::new(), controllers: Vec::new() };
+    for (controller, selector) in selectors {
+        let mut restored = current.clone();
+        restored.extend(plan.labels.clone());
+        // Only the labels being put back matter, not a selector the pod already matches
+        if selects(&selector, current) || !selects(&selector, &restored) {
+            continue;
+        }
+        for services_too in [false, true] {
+            for key in selector_keys(&selector).filter(|key| services_too || !service_keys.contains(*key)) {
+                if let Some(value) = plan.labels.remove(key) {
+                    plan.held_back.insert(key.clone(), value);
+                }
+            }
+            let mut restored = current.clone();
+            restored.extend(plan.labels.clone());
+            if !selects(&selector, &restored) {
+                break;
+            }
+        }
+        plan.controllers.push(controller);
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn selector(pairs: &[(&str, &str)]) -> LabelSelector {
+        LabelSelector { match_labels: Some(labels(pairs)), ..Default::default() }
+    }
+
+    #[test]
+    fn controller_labels_are_held_back() {
+        let removed = labels(&[("app", "web"), ("pod-template-hash", "abc")]);
+        let selectors = vec![
+            ("ReplicaSet/web-abc".to_string(), selector(&[("app", "web"), ("pod-template-hash", "abc")])),
+            ("ReplicaSet/api-def".to_string(), selector(&[("app", "api"), ("pod-template-hash", "def")])),
+        ];
+        let service_keys = BTreeSet::from(["app".to_string()]);
+        let plan = reattachment(&labels(&[("team", "a")]), removed, selectors.into_iter(), &service_keys);
+        // The Service selector label stays, without the hash the ReplicaSet no longer selects the pod
+        assert_eq!(plan.labels, labels(&[("app", "web")]));
+        assert_eq!(plan.held_back, labels(&[("pod-template-hash", "abc")]));
+        assert_eq!(plan.controllers, ["ReplicaSet/web-abc"]);
+    }
+
+    #[test]
+    fn selectors_the_pod_already_matches_are_ignored() {
+        let selectors = vec![("DaemonSet/agent".to_string(), selector(&[("team", "a")]))];
+        let plan = reattachment(&labels(&[("team", "a")]), labels(&[("app", "web")]), selectors.into_iter(), &BTreeSet::new());
+        assert_eq!(plan.labels, labels(&[("app", "web")]));
+        assert!(plan.held_back.is_empty() && plan.controllers.is_empty());
+    }
+
+    #[test]
+    fn service_labels_are_held_back_when_the_controller_selects_only_them() {
+        let selectors = vec![("DaemonSet/agent".to_string(), selector(&[("app", "agent")]))];
+        let service_keys = BTreeSet::from(["app".to_string()]);
+        let plan = reattachment(&BTreeMap::new(), labels(&[("app", "agent")]), selectors.into_iter(), &service_keys);
+        assert!(plan.labels.is_empty());
+        assert_eq!(plan.held_back, labels(&[("app", "agent")]));
+    }
+
+    fn expression(key: &str, operator: &str, values: &[&str]) -> LabelSelector {
+        LabelSelector {
+            match_expressions: Some(vec![LabelSelectorRequirement {
+                key: key.to_string(),
+                operator: operator.to_string(),
+                values: Some(values.iter().map(|value| value.to_string()).collect()),
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn selects_with_match_labels() {
+        let pod = labels(&[("app", "web"), ("isolate", "true")]);
+        assert!(selects(&selector(&[("isolate", "true")]), &pod));
+        assert!(selects(&selector(&[("app", "web"), ("isolate", "true")]), &pod));
+        assert!(!selects(&selector(&[("isolate", "false")]), &pod));
+        assert!(!selects(&selector(&[("tier", "db")]), &pod));
+        // An empty selector selects everything
+        assert!(selects(&LabelSelector::default(), &pod));
+    }
+
+    #[test]
+    fn selects_with_match_expressions() {
+        let pod = labels(&[("app", "web")]);
+        assert!(selects(&expression("app", "In", &["web", "api"]), &pod));
+        assert!(!selects(&expression("app", "In", &["api"]), &pod));
+        assert!(!selects(&expression("tier", "In", &["db"]), &pod));
+        assert!(selects(&expression("app", "NotIn", &["api"]), &pod));
+        assert!(!selects(&expression("app", "NotIn", &["web"]), &pod));
+        assert!(selects(&expression("tier", "NotIn", &["db"]), &pod));
+        assert!(selects(&expression("app", "Exists", &[]), &pod));
+        assert!(!selects(&expression("tier", "Exists", &[]), &pod));
+        assert!(selects(&expression("tier", "DoesNotExist", &[]), &pod));
+        assert!(!selects(&expression("app", "DoesNotExist", &[]), &pod));
+        assert!(!selects(&expression("app", "Gt", &["1"]), &pod));
+    }
+
+    fn rule_target(rule: &NetworkPolicyEgressRule) -> (String, Option<i32>) {
+        let cidr = rule.to.as_ref().unwrap()[0].ip_block.as_ref().unwrap().cidr.clone();
+        let port = rule.ports.as_ref().map(|ports| match ports[0].port {
+            Some(IntOrString::Int(port)) => port,
+            _ => panic!("port is not a number"),
+        });
+        (cidr, port)
+    }
+
+    #[test]
+    fn allowlist_entries_with_and_without_port() {
+        assert_eq!(rule_target(&allowlist_rule("10.0.0.5/32").unwrap()), ("10.0.0.5/32".to_string(), None));
+        assert_eq!(rule_target(&allowlist_rule("10.0.0.5/32:443").unwrap()), ("10.0.0.5/32".to_string(), Some(443)));
+        assert_eq!(rule_target(&allowlist_rule("fd00::/8").unwrap()), ("fd00::/8".to_string(), None));
+        assert_eq!(rule_target(&allowlist_rule("fd00::/8:8443").unwrap()), ("fd00::/8".to_string(), Some(8443)));
+        assert_eq!(allowlist_rule("10.0.0.5/32:443").unwrap().ports.unwrap()[0].protocol.as_deref(), Some("TCP"));
+    }
+
+    #[test]
+    fn allowlist_entries_must_be_cidrs() {
+        for entry in ["10.0.0.5", "10.0.0.5:443", "10.0.0.0/8:https", "fd00::1", "example.com:443"] {
+            assert!(allowlist_rule(entry).is_err(), "{} was accepted", entry);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fs, sync::OnceLock};
+use log::warn;
+
+use crate::{
+    config::{get_rbac_disabled, get_rbac_policy_file},
+    model::{auth::Principal, rbac::{Action, Policy, Role}},
+};
+
+static POLICY: OnceLock<Option<Policy>> = OnceLock::new();
+
+// Parse and validate a policy document
+pub fn parse_policy(content: &str) -> Result<Policy, String> {
+    let mut policy: Policy = serde_yaml::from_str(content)
+        .map_err(|e| format!("Invalid RBAC policy: {}", e))?;
+    for (name, role) in Role::builtin() {
+        policy.roles.entry(name).or_insert(role);
+    }
+    for (name, role) in &policy.roles {
+        for action in role.actions.iter().filter(|a| a.as_str() != "*") {
+            action.parse::<Action>().map_err(|e| format!("Role {}: {}", name, e))?;
+        }
+    }
+    for binding in &policy.bindings {
+        if !policy.roles.contains_key(&binding.role) {
+            return Err(format!("Binding refers to unknown role: {}", binding.role));
+        }
+    }
+    Ok(policy)
+}
+
+// Load the policy from `RBAC_POLICY_FILE`, must be called once at startup. Without policy file startup fails
+// unless `RBAC_DISABLED` opts out of authorization
+pub fn init_policy() -> Result<(), String> {
+    let policy = match get_rbac_policy_file() {
+        Some(path) => {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read RBAC policy {}: {}", path, e))?;
+            Some(parse_policy(&content)?)
+        }
+        None if get_rbac_disabled() => {
+            warn!("RBAC_DISABLED is set, every authenticated caller is allowed to perform every action");
+            None
+        }
+        None => return Err("RBAC_POLICY_FILE is required, set RBAC_DISABLED=true to allow every caller every action".to_string()),
+    };
+    POLICY.set(policy).map_err(|_| "RBAC policy already initialized".to_string())
+}
+
+// Check whether the principal may perform `action` in `namespace`, the error is the reason for refusal
+pub fn authorize(principal: &Principal, action: Action, namespace: Option<&str>) -> Result<(), String> {
+    if let Some(scope) = &principal.scope {
+        if scope.allows(action, namespace) {
+            return Ok(());
+        }
+        return Err(format!(
+            "API key {} is not allowed to {} in namespace {}",
+            principal.subject,
+            action,
+            namespace.unwrap_or("<none>")
+        ));
+    }
+    let policy = match POLICY.get() {
+        Some(Some(policy)) => policy,
+        Some(None) => return Ok(()),
+        None => return Err("RBAC policy is not loaded".to_string()),
+    };
+    let allowed = policy.bindings.iter()
+        .filter(|b| b.matches(principal))
+        .filter(|b| b.applies_to_namespace(namespace))
+        .any(|b| policy.roles.get(&b.role).is_some_and(|role| role.allows(action)));
+    if allowed {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is not allowed to {} in namespace {}",
+            principal.subject,
+            action,
+            namespace.unwrap_or("<none>")
+        ))
+    }
+}
+
+// Whether at least one role binding applies to the principal
+pub fn has_any_role(principal: &Principal) -> bool {
+    match POLICY.get() {
+        Some(Some(policy)) => policy.bindings.iter().any(|b| b.matches(principal)),
+        _ => false,
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use k8s_openapi::api::core::v1::{PodTemplateSpec, Secret};
+use kube::{Api, Client};
+use log::warn;
+use oauth2::{http::{header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE}, HeaderMap, HeaderValue, Method, StatusCode}, reqwest::async_http_client, HttpResponse};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{config::get_insecure_registries, util::image_ref::ImageRef};
+
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+// Keys Docker Hub credentials are stored under in docker config files
+const DOCKER_HUB_KEYS: [&str; 3] = ["https://index.docker.io/v1/", "index.docker.io", "docker.io"];
+
+// Manifests and indexes, a multi-arch tag resolves to the digest of its index like `docker pull` does
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+#[derive(Debug)]
+pub enum RegistryError {
+    // The registry answered, the tag or digest does not exist
+    NotFound(String),
+    // The registry or its token service refused the pull credentials
+    Unauthorized(String),
+    // The registry could not be asked
+    Unavailable(String),
+}
+
+/// Username and password for one registry
+#[derive(Clone)]
+pub struct RegistryCredential {
+    pub username: String,
+    pub password: String,
+}
+
+// Content of a `kubernetes.io/dockerconfigjson` secret
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    username: Option<String>,
+    password: Option<String>,
+    auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+impl DockerAuth {
+    fn credential(&self) -> Option<RegistryCredential> {
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Some(RegistryCredential { username: username.clone(), password: password.clone() });
+        }
+        let decoded = STANDARD.decode(self.auth.as_deref()?).ok()?;
+        let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        Some(RegistryCredential { username: username.to_string(), password: password.to_string() })
+    }
+}
+
+// Host of a docker config key, which can be a bare host or a URL
+fn config_host(key: &str) -> &str {
+    let host = key.split_once("://").map(|(_, rest)| rest).unwrap_or(key);
+    host.split('/').next().unwrap_or(host)
+}
+
+// Registry credentials from the imagePullSecrets of the pod template of a workload, by registry host
+pub async fn pull_credentials(client: Client, namespace: &str, template: &PodTemplateSpec) -> HashMap<String, RegistryCredential> {
+    let secret_names: Vec<String> = template.spec.as_ref()
+        .and_then(|spec| spec.image_pull_secrets.as_ref())
+        .map(|secrets| secrets.iter().filter_map(|s| s.name.clone()).collect())
+        .unwrap_or_default();
+    let secrets: Api<Secret> = Api::namespaced(client, namespace);
+    let mut credentials = HashMap::new();
+    for name in secret_names {
+        let secret = match secrets.get(&name).await {
+            Ok(secret) => secret,
+            Err(e) => {
+                warn!("Could not read image pull secret {}/{}: {}", namespace, name, e);
+                continue;
+            }
+        };
+        let data = secret.data.unwrap_or_default();
+        let config = match data.get(".dockerconfigjson") {
+            Some(content) => serde_json::from_slice::<DockerConfig>(&content.0).map(|config| config.auths),
+            // Legacy format, the auths are at the top level
+            None => match data.get(".dockercfg") {
+                Some(content) => serde_json::from_slice::<HashMap<String, DockerAuth>>(&content.0),
+                None => continue,
+            },
+        };
+        match config {
+            Ok(auths) => {
+                for (key, auth) in auths {
+                    if let Some(credential) = auth.credential() {
+                        credentials.entry(config_host(&key).to_string()).or_insert(credential);
+                    }
+                }
+            }
+            Err(e) => warn!("Image pull secret {}/{} is invalid: {}", namespace, name, e),
+        }
+    }
+    credentials
+}
+
+async fn send(method: Method, url: &Url, headers: HeaderMap) -> Result<HttpResponse, RegistryError> {
+    let request = oauth2::HttpRequest { url: url.clone(), method, headers, body: Vec::new() };
+    async_http_client(request).await
+        .map_err(|e| RegistryError::Unavailable(format!("Failed to reach {}: {}", url, e)))
+}
+
+fn basic_auth(credential: &RegistryCredential) -> String {
+    format!("Basic {}", STANDARD.encode(format!("{}:{}", credential.username, credential.password)))
+}
+
+// Parameters of a `Bearer realm="...",service="...",scope="..."` challenge
+fn challenge_params(challenge: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    // Skip the scheme before the first parameter
+    let mut rest = challenge.trim().split_once(' ').map(|(_, params)| params).unwrap_or_default();
+    while let Some((key, value)) = rest.split_once("=\"") {
+        let key = key.trim_start_matches([',', ' ']).trim();
+        let (value, tail) = value.split_once('"').unwrap_or((value, ""));
+        params.insert(key.to_string(), value.to_string());
+        rest = tail;
+    }
+    params
+}
+
+// Token for a `Bearer` challenge, from the token service of the registry
+async fn bearer_token(challenge: &str, repository: &str, credential: Option<&RegistryCredential>) -> Result<String, RegistryError> {
+    let params = challenge_params(challenge);
+    let realm = params.get("realm")
+        .ok_or_else(|| RegistryError::Unavailable(format!("Registry challenge without realm: {}", challenge)))?;
+    let mut url = Url::parse(realm)
+        .map_err(|e| RegistryError::Unavailable(format!("Invalid token realm {}: {}", realm, e)))?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(service) = params.get("service") {
+            query.append_pair("service", service);
+        }
+        let scope = params.get("scope").cloned().unwrap_or_else(|| format!("repository:{}:pull", repository));
+        query.append_pair("scope", &scope);
+    }
+    let mut headers = HeaderMap::new();
+    if let Some(credential) = credential {
+        let value = HeaderValue::from_str(&basic_auth(credential)).map_err(|e| RegistryError::Unavailable(e.to_string()))?;
+        headers.insert(AUTHORIZATION, value);
+    }
+    let resp = send(Method::GET, &url, headers).await?;
+    if matches!(resp.status_code, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        return Err(RegistryError::Unauthorized(format!("Token service {} refused the pull credentials for {}", realm, repository)));
+    }
+    if !resp.status_code.is_success() {
+        return Err(RegistryError::Unavailable(format!("Token service {} responded with {}", realm, resp.status_code)));
+    }
+    let token: TokenResponse = serde_json::from_slice(&resp.body)
+        .map_err(|e| RegistryError::Unavailable(format!("Invalid token response from {}: {}", realm, e)))?;
+    token.token.or(token.access_token)
+        .ok_or_else(|| RegistryError::Unavailable(format!("Token service {} returned no token", realm)))
+}
+
+// Resolve the tag (or check the digest) of `image` with the OCI distribution API of its registry
+pub async fn resolve_digest(image: &ImageRef, credentials: &HashMap<String, RegistryCredential>) -> Result<String, RegistryError> {
+    let (host, repository, credential) = match image.registry.as_deref() {
+        Some(registry) if !DOCKER_HUB_KEYS.iter().any(|key| config_host(key) == registry) => {
+            (registry.to_string(), image.repository.clone(), credentials.get(registry))
+        }
+        _ => {
+            let repository = if image.repository.contains('/') {
+                image.repository.clone()
+            } else {
+                format!("library/{}", image.repository)
+            };
+            let credential = DOCKER_HUB_KEYS.iter().find_map(|key| credentials.get(config_host(key)));
+            (DOCKER_HUB_REGISTRY.to_string(), repository, credential)
+        }
+    };
+    let scheme = if get_insecure_registries().contains(&host) { "http" } else { "https" };
+    manifest_digest(image, scheme, &host, &repository, credential).await
+}
+
+// HEAD the manifest of `image`, answering a `Bearer` or `Basic` challenge once
+async fn manifest_digest(image: &ImageRef, scheme: &str, host: &str, repository: &str, credential: Option<&RegistryCredential>) -> Result<String, RegistryError> {
+    let reference = match (&image.digest, &image.tag) {
+        (Some(digest), _) => digest.clone(),
+        (None, Some(tag)) => tag.clone(),
+        (None, None) => "latest".to_string(),
+    };
+    let url = Url::parse(&format!("{}://{}/v2/{}/manifests/{}", scheme, host, repository, reference))
+        .map_err(|e| RegistryError::Unavailable(format!("Invalid registry URL: {}", e)))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, HeaderValue::from_static(MANIFEST_TYPES));
+    let mut resp = send(Method::HEAD, &url, headers.clone()).await?;
+    if resp.status_code == StatusCode::UNAUTHORIZED {
+        let challenge = resp.headers.get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let authorization = if challenge.to_lowercase().starts_with("bearer") {
+            format!("Bearer {}", bearer_token(&challenge, repository, credential).await?)
+        } else {
+            let credential = credential
+                .ok_or_else(|| RegistryError::Unauthorized(format!("Registry {} requires credentials", host)))?;
+            basic_auth(credential)
+        };
+        let value = HeaderValue::from_str(&authorization).map_err(|e| RegistryError::Unavailable(e.to_string()))?;
+        headers.insert(AUTHORIZATION, value);
+        resp = send(Method::HEAD, &url, headers).await?;
+    }
+    match resp.status_code {
+        status if status.is_success() => {
+            let digest = resp.headers.get("docker-content-digest").and_then(|value| value.to_str().ok());
+            match (digest, &image.digest) {
+                (Some(digest), _) => Ok(digest.to_string()),
+                (None, Some(digest)) => Ok(digest.clone()),
+                (None, None) => Err(RegistryError::Unavailable(format!("Registry {} did not return the digest of {}", host, image))),
+            }
+        }
+        StatusCode::NOT_FOUND => Err(RegistryError::NotFound(format!("Image {} does not exist in {}", image, host))),
+        // Registries also answer this way for repositories that do not exist
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err(RegistryError::Unauthorized(format!("Image {} is not readable in {} with the pull secrets of the workload", image, host)))
+        }
+        status => Err(RegistryError::Unavailable(format!("Registry {} responded with {}", host, status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener, thread};
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    // Registry with a token service: `app:1.0` is readable with any token, `private` only with the token of user:secret
+    fn stub_registry() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let realm = format!("http://{}/token", host);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut authorization = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("authorization") {
+                            authorization = value.trim().to_string();
+                        }
+                    }
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let user_token = basic_auth(&RegistryCredential { username: "user".to_string(), password: "secret".to_string() });
+                let (status, headers, body) = if path.starts_with("/token") {
+                    if authorization.is_empty() {
+                        ("200 OK", String::new(), r#"{"token":"anonymous"}"#)
+                    } else if authorization == user_token {
+                        ("200 OK", String::new(), r#"{"access_token":"user"}"#)
+                    } else {
+                        ("401 Unauthorized", String::new(), "")
+                    }
+                } else if !request_line.starts_with("HEAD ") {
+                    ("405 Method Not Allowed", String::new(), "")
+                } else if !authorization.starts_with("Bearer ") {
+                    let challenge = format!("WWW-Authenticate: Bearer realm=\"{}\",service=\"stub\"\r\n", realm);
+                    ("401 Unauthorized", challenge, "")
+                } else if path == "/v2/app/manifests/1.0" || (path == "/v2/private/manifests/1.0" && authorization == "Bearer user") {
+                    ("200 OK", format!("Docker-Content-Digest: {}\r\n", DIGEST), "")
+                } else if path.starts_with("/v2/app/") {
+                    ("404 Not Found", String::new(), "")
+                } else {
+                    ("403 Forbidden", String::new(), "")
+                };
+                let response = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, headers, body.len(), body);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        host
+    }
+
+    async fn resolve(host: &str, image: &str, credential: Option<RegistryCredential>) -> Result<String, RegistryError> {
+        let image = ImageRef::parse(&format!("{}/{}", host, image)).unwrap();
+        manifest_digest(&image, "http", host, &image.repository, credential.as_ref()).await
+    }
+
+    #[actix_web::test]
+    async fn resolves_a_tag_through_a_bearer_challenge() {
+        let host = stub_registry();
+        assert_eq!(resolve(&host, "app:1.0", None).await.unwrap(), DIGEST);
+    }
+
+    #[actix_web::test]
+    async fn missing_tag_is_not_found() {
+        let host = stub_registry();
+        assert!(matches!(resolve(&host, "app:2.0", None).await, Err(RegistryError::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn pull_credentials_are_sent_to_the_token_service() {
+        let host = stub_registry();
+        let credential = RegistryCredential { username: "user".to_string(), password: "secret".to_string() };
+        assert_eq!(resolve(&host, "private:1.0", Some(credential)).await.unwrap(), DIGEST);
+    }
+
+    #[actix_web::test]
+    async fn refused_credentials_are_not_reported_as_missing() {
+        let host = stub_registry();
+        assert!(matches!(resolve(&host, "private:1.0", None).await, Err(RegistryError::Unauthorized(_))));
+        let credential = RegistryCredential { username: "user".to_string(), password: "wrong".to_string() };
+        assert!(matches!(resolve(&host, "private:1.0", Some(credential)).await, Err(RegistryError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn challenge_params_are_parsed() {
+        let params = challenge_params(r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull""#);
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/nginx:pull");
+    }
+}
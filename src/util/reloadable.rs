@@ -0,0 +1,70 @@
+use std::{fs, sync::{Arc, RwLock}, time::SystemTime};
+use log::{error, info};
+
+/// Configuration file that is parsed again whenever its modification time changes.
+/// If the new content is invalid the previous version stays in use.
+pub struct Reloadable<T> {
+    path: String,
+    parse: fn(&str) -> Result<T, String>,
+    state: RwLock<(Option<SystemTime>, Arc<T>)>,
+    // Why the current version of the file was not loaded
+    error: RwLock<Option<String>>,
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl<T> Reloadable<T> {
+    // Load the file for the first time, errors are fatal here
+    pub fn load(path: &str, parse: fn(&str) -> Result<T, String>) -> Result<Self, String> {
+        let mtime = modified(path);
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let value = parse(&content)?;
+        Ok(Reloadable {
+            path: path.to_string(),
+            parse,
+            state: RwLock::new((mtime, Arc::new(value))),
+            error: RwLock::new(None),
+        })
+    }
+
+    // Current content, reloaded first if the file changed on disk
+    pub fn get(&self) -> Arc<T> {
+        let mtime = modified(&self.path);
+        {
+            let state = self.state.read().unwrap();
+            if state.0 == mtime || mtime.is_none() {
+                return state.1.clone();
+            }
+        }
+        let mut state = self.state.write().unwrap();
+        if state.0 != mtime {
+            // Remember the mtime even on failure so a broken file is not parsed on every call
+            state.0 = mtime;
+            match fs::read_to_string(&self.path).map_err(|e| e.to_string()).and_then(|c| (self.parse)(&c)) {
+                Ok(value) => {
+                    info!("Reloaded {}", self.path);
+                    state.1 = Arc::new(value);
+                    *self.error.write().unwrap() = None;
+                }
+                Err(e) => {
+                    error!("Could not reload {}, keeping the previous version: {}", self.path, e);
+                    *self.error.write().unwrap() = Some(e);
+                }
+            }
+        }
+        state.1.clone()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Error of the last reload, none when the file on disk is the version in use
+    pub fn reload_error(&self) -> Option<String> {
+        self.get();
+        self.error.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,517 @@
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+use k8s_openapi::{
+    api::{apps::v1::{ControllerRevision, DaemonSet, Deployment, ReplicaSet, StatefulSet}, core::v1::{ContainerStatus, Pod, PodTemplateSpec}},
+    apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta},
+};
+use kube::{api::{DynamicObject, ListParams}, core::dynamic::ParseDynamicObjectError, runtime::wait::await_condition, Api, Client};
+use serde_json::Value;
+
+use crate::{
+    model::kubernetes::{FailingPod, RolloutCondition, RolloutStatus, WorkloadKind},
+    util::workload::WorkloadApi,
+};
+
+pub const PROGRESSING: &str = "progressing";
+pub const COMPLETE: &str = "complete";
+pub const FAILED: &str = "failed";
+pub const TIMEOUT: &str = "timeout";
+
+pub const REVISION_ANNOTATION: &str = "deployment.kubernetes.io/revision";
+pub const POD_TEMPLATE_HASH_LABEL: &str = "pod-template-hash";
+// Label of StatefulSet and DaemonSet pods with the ControllerRevision they were created from
+const REVISION_HASH_LABEL: &str = "controller-revision-hash";
+pub const CHANGE_CAUSE_ANNOTATION: &str = "kubernetes.io/change-cause";
+// Id of the audit record of the change, copied with the change cause to the new ReplicaSet
+pub const AUDIT_ID_ANNOTATION: &str = "officer/audit-id";
+
+// Longest wait accepted from a caller
+const MAX_TIMEOUT: Duration = Duration::from_secs(1800);
+// Pods are checked this often while the Deployment is watched
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Waiting reasons that are part of a normal container start
+const STARTING_REASONS: [&str; 2] = ["ContainerCreating", "PodInitializing"];
+
+// State and message of a rollout, following the rules of `kubectl rollout status`
+fn rollout_state(deployment: &Deployment) -> (&'static str, String) {
+    let name = deployment.metadata.name.as_deref().unwrap_or_default();
+    let status = deployment.status.clone().unwrap_or_default();
+    if status.observed_generation.unwrap_or_default() < deployment.metadata.generation.unwrap_or_default() {
+        return (PROGRESSING, "Waiting for the deployment spec update to be observed".to_string());
+    }
+    let deadline_exceeded = status.conditions.iter().flatten()
+        .any(|c| c.type_ == "Progressing" && c.reason.as_deref() == Some("ProgressDeadlineExceeded"));
+    if deadline_exceeded {
+        return (FAILED, format!("Deployment {} exceeded its progress deadline", name));
+    }
+    let desired = deployment.spec.as_ref().and_then(|spec| spec.replicas).unwrap_or(1);
+    let replicas = status.replicas.unwrap_or_default();
+    let updated = status.updated_replicas.unwrap_or_default();
+    let available = status.available_replicas.unwrap_or_default();
+    if updated < desired {
+        (PROGRESSING, format!("{} of {} new replicas have been updated", updated, desired))
+    } else if replicas > updated {
+        (PROGRESSING, format!("{} old replicas are pending termination", replicas - updated))
+    } else if available < updated {
+        (PROGRESSING, format!("{} of {} updated replicas are available", available, updated))
+    } else {
+        (COMPLETE, format!("Deployment {} successfully rolled out", name))
+    }
+}
+
+fn is_finished(deployment: Option<&Deployment>) -> bool {
+    match deployment {
+        Some(deployment) => rollout_state(deployment).0 != PROGRESSING,
+        None => true,
+    }
+}
+
+// Current status of the rollout of Deployment `name`
+pub async fn rollout_status(client: Client, namespace: &str, name: &str) -> Result<RolloutStatus, String> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let deployment = deployments.get(name).await
+        .map_err(|e| format!("Get deployment failed: {}", e))?;
+    let (state, message) = rollout_state(&deployment);
+    let failing_pods = if state == COMPLETE {
+        Vec::new()
+    } else {
+        failing_pods(client, namespace, &deployment).await?
+    };
+    let status = deployment.status.unwrap_or_default();
+    Ok(RolloutStatus {
+        namespace: namespace.to_string(),
+        service_deployment: name.to_string(),
+        state: state.to_string(),
+        message,
+        generation: deployment.metadata.generation,
+        observed_generation: status.observed_generation,
+        replicas: status.replicas.unwrap_or_default(),
+        updated_replicas: status.updated_replicas.unwrap_or_default(),
+        ready_replicas: status.ready_replicas.unwrap_or_default(),
+        available_replicas: status.available_replicas.unwrap_or_default(),
+        conditions: status.conditions.unwrap_or_default().into_iter().map(|c| RolloutCondition {
+            type_: c.type_,
+            status: c.status,
+            reason: c.reason,
+            message: c.message,
+        }).collect(),
+        failing_pods,
+    })
+}
+
+// Hash label of the newest ControllerRevision owned by the workload, carried by the pods of that revision
+async fn latest_revision_hash(client: Client, namespace: &str, owner: &ObjectMeta) -> Result<Option<String>, String> {
+    let revisions: Api<ControllerRevision> = Api::namespaced(client, namespace);
+    let revisions = revisions.list(&ListParams::default()).await
+        .map_err(|e| format!("Could not list controller revisions: {}", e))?;
+    Ok(revisions.items.into_iter()
+        .filter(|revision| revision.metadata.owner_references.iter().flatten().any(|o| Some(&o.uid) == owner.uid.as_ref()))
+        .max_by_key(|revision| revision.revision)
+        .and_then(|revision| revision.metadata.labels?.remove(REVISION_HASH_LABEL)))
+}
+
+// Replica counts of a rollout of another kind than Deployment, with the pods of its new revision
+struct Progress {
+    state: &'static str,
+    message: String,
+    observed_generation: Option<i64>,
+    replicas: i32,
+    updated_replicas: i32,
+    ready_replicas: i32,
+    available_replicas: i32,
+    pod_selector: String,
+}
+
+fn selector_labels(selector: &LabelSelector) -> String {
+    selector.match_labels.iter().flatten().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(",")
+}
+
+// Pods of the update revision are labelled with its `controller-revision-hash`
+fn stateful_set_progress(stateful_set: &StatefulSet) -> Progress {
+    let name = stateful_set.metadata.name.as_deref().unwrap_or_default();
+    let spec = stateful_set.spec.clone().unwrap_or_default();
+    let status = stateful_set.status.clone().unwrap_or_default();
+    let desired = spec.replicas.unwrap_or(1);
+    let updated = status.updated_replicas.unwrap_or_default();
+    let ready = status.ready_replicas.unwrap_or_default();
+    let partition = spec.update_strategy.as_ref()
+        .and_then(|strategy| strategy.rolling_update.as_ref())
+        .and_then(|rolling_update| rolling_update.partition)
+        .unwrap_or_default();
+    let update_revision = status.update_revision.clone().unwrap_or_default();
+    let (state, message) = if status.observed_generation.unwrap_or_default() < stateful_set.metadata.generation.unwrap_or_default() {
+        (PROGRESSING, "Waiting for the statefulset spec update to be observed".to_string())
+    } else if ready < desired {
+        (PROGRESSING, format!("{} of {} replicas are ready", ready, desired))
+    } else if partition > 0 && updated < desired - partition {
+        (PROGRESSING, format!("{} of {} new replicas have been updated, partitioned at {}", updated, desired - partition, partition))
+    } else if partition == 0 && status.current_revision.as_deref() != Some(update_revision.as_str()) {
+        (PROGRESSING, format!("{} of {} new replicas have been updated", updated, desired))
+    } else {
+        (COMPLETE, format!("StatefulSet {} successfully rolled out", name))
+    };
+    // Pods are only looked at once the update revision is known
+    let pod_selector = match (selector_labels(&spec.selector), update_revision.is_empty()) {
+        (selector, false) if !selector.is_empty() => format!("{},{}={}", selector, REVISION_HASH_LABEL, update_revision),
+        _ => String::new(),
+    };
+    Progress {
+        state,
+        message,
+        observed_generation: status.observed_generation,
+        replicas: status.replicas,
+        updated_replicas: updated,
+        ready_replicas: ready,
+        available_replicas: status.available_replicas.unwrap_or_default(),
+        pod_selector,
+    }
+}
+
+fn daemon_set_progress(daemon_set: &DaemonSet) -> Progress {
+    let name = daemon_set.metadata.name.as_deref().unwrap_or_default();
+    let status = daemon_set.status.clone().unwrap_or_default();
+    let desired = status.desired_number_scheduled;
+    let updated = status.updated_number_scheduled.unwrap_or_default();
+    let available = status.number_available.unwrap_or_default();
+    let (state, message) = if status.observed_generation.unwrap_or_default() < daemon_set.metadata.generation.unwrap_or_default() {
+        (PROGRESSING, "Waiting for the daemonset spec update to be observed".to_string())
+    } else if updated < desired {
+        (PROGRESSING, format!("{} of {} updated pods have been scheduled", updated, desired))
+    } else if available < desired {
+        (PROGRESSING, format!("{} of {} updated pods are available", available, desired))
+    } else {
+        (COMPLETE, format!("DaemonSet {} successfully rolled out", name))
+    };
+    Progress {
+        state,
+        message,
+        observed_generation: status.observed_generation,
+        replicas: desired,
+        updated_replicas: updated,
+        ready_replicas: status.number_ready,
+        available_replicas: available,
+        // Completed with the hash of the newest ControllerRevision, the status does not name it
+        pod_selector: daemon_set.spec.as_ref().map(|spec| selector_labels(&spec.selector)).unwrap_or_default(),
+    }
+}
+
+// Argo Rollouts sums the rollout up in `status.phase`, pods of the new revision carry `rollouts-pod-template-hash`
+fn argo_rollout_progress(rollout: &DynamicObject) -> Progress {
+    let name = rollout.metadata.name.as_deref().unwrap_or_default();
+    let status = rollout.data.get("status").cloned().unwrap_or_default();
+    let count = |field: &str| status.get(field).and_then(Value::as_i64).unwrap_or_default() as i32;
+    // Older Argo Rollouts versions report the generation as a string
+    let observed_generation = match status.get("observedGeneration") {
+        Some(Value::String(generation)) => generation.parse().ok(),
+        Some(generation) => generation.as_i64(),
+        None => None,
+    };
+    let phase = status.get("phase").and_then(Value::as_str).unwrap_or_default();
+    let detail = status.get("message").and_then(Value::as_str).unwrap_or_default();
+    let (state, message) = if observed_generation.unwrap_or_default() < rollout.metadata.generation.unwrap_or_default() {
+        (PROGRESSING, "Waiting for the rollout spec update to be observed".to_string())
+    } else {
+        match phase {
+            "Healthy" => (COMPLETE, format!("Rollout {} successfully rolled out", name)),
+            "Degraded" => (FAILED, format!("Rollout {} is degraded: {}", name, detail)),
+            "" => (PROGRESSING, "Waiting for the rollout controller".to_string()),
+            phase => (PROGRESSING, format!("Rollout {} is {}{}", name, phase, if detail.is_empty() { String::new() } else { format!(": {}", detail) })),
+        }
+    };
+    let selector = rollout.data.get("spec")
+        .and_then(|spec| spec.get("selector"))
+        .and_then(|selector| serde_json::from_value::<LabelSelector>(selector.clone()).ok())
+        .map(|selector| selector_labels(&selector))
+        .unwrap_or_default();
+    let pod_selector = match status.get("currentPodHash").and_then(Value::as_str) {
+        Some(hash) if !selector.is_empty() => format!("{},rollouts-pod-template-hash={}", selector, hash),
+        _ => String::new(),
+    };
+    Progress {
+        state,
+        message,
+        observed_generation,
+        replicas: count("replicas"),
+        updated_replicas: count("updatedReplicas"),
+        ready_replicas: count("readyReplicas"),
+        available_replicas: count("availableReplicas"),
+        pod_selector,
+    }
+}
+
+// Current status of the rollout of a workload of any kind, following `kubectl rollout status` for the built-in kinds
+// and the phase of Argo Rollouts
+pub async fn workload_rollout_status(client: Client, namespace: &str, kind: WorkloadKind, name: &str) -> Result<RolloutStatus, String> {
+    if kind == WorkloadKind::Deployment {
+        return rollout_status(client, namespace, name).await;
+    }
+    let workload = WorkloadApi::new(client.clone(), namespace, kind);
+    let object = workload.api.get(name).await
+        .map_err(|e| format!("Get {} failed: {}", kind, e))?;
+    let generation = object.metadata.generation;
+    let metadata = object.metadata.clone();
+    let invalid = |e: ParseDynamicObjectError| format!("{} {} is invalid: {}", kind, name, e);
+    let mut progress = match kind {
+        WorkloadKind::Rollout => argo_rollout_progress(&object),
+        WorkloadKind::DaemonSet => daemon_set_progress(&object.try_parse().map_err(invalid)?),
+        _ => stateful_set_progress(&object.try_parse().map_err(invalid)?),
+    };
+    if kind == WorkloadKind::DaemonSet && progress.state != COMPLETE && !progress.pod_selector.is_empty() {
+        progress.pod_selector = match latest_revision_hash(client.clone(), namespace, &metadata).await? {
+            Some(hash) => format!("{},{}={}", progress.pod_selector, REVISION_HASH_LABEL, hash),
+            None => String::new(),
+        };
+    }
+    let failing_pods = if progress.state == COMPLETE || progress.pod_selector.is_empty() {
+        Vec::new()
+    } else {
+        let pods: Api<Pod> = Api::namespaced(client, namespace);
+        let pods = pods.list(&ListParams::default().labels(&progress.pod_selector)).await
+            .map_err(|e| format!("Could not list pods: {}", e))?;
+        pods.items.iter().flat_map(pod_failures).collect()
+    };
+    Ok(RolloutStatus {
+        namespace: namespace.to_string(),
+        service_deployment: name.to_string(),
+        state: progress.state.to_string(),
+        message: progress.message,
+        generation,
+        observed_generation: progress.observed_generation,
+        replicas: progress.replicas,
+        updated_replicas: progress.updated_replicas,
+        ready_replicas: progress.ready_replicas,
+        available_replicas: progress.available_replicas,
+        conditions: Vec::new(),
+        failing_pods,
+    })
+}
+
+// Pod of the new revision with a container that restarted more than `max_restarts` times
+fn restarting_pod(status: &RolloutStatus, max_restarts: Option<i32>) -> Option<FailingPod> {
+    // Until the update is observed the pods are those of the previous revision, their restarts are not ours
+    if status.observed_generation.unwrap_or_default() < status.generation.unwrap_or_default() {
+        return None;
+    }
+    let max = max_restarts?;
+    status.failing_pods.iter().find(|pod| pod.restart_count > max).cloned()
+}
+
+// Watch workload `name` until its rollout completes, fails or `timeout` elapses.
+// The rollout also fails when a container of the new revision restarts more than `max_restarts` times.
+pub async fn wait_for_rollout(
+    client: Client,
+    namespace: &str,
+    kind: WorkloadKind,
+    name: &str,
+    timeout: Duration,
+    max_restarts: Option<i32>,
+) -> Result<RolloutStatus, String> {
+    let timeout = timeout.min(MAX_TIMEOUT);
+    let started = Instant::now();
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    loop {
+        let remaining = timeout.saturating_sub(started.elapsed());
+        // Deployments are watched, the other kinds polled
+        if kind == WorkloadKind::Deployment {
+            let watched = tokio::time::timeout(remaining.min(POLL_INTERVAL), await_condition(deployments.clone(), name, is_finished)).await;
+            if let Ok(Err(e)) = watched {
+                return Err(format!("Watching deployment {} failed: {}", name, e));
+            }
+        } else {
+            tokio::time::sleep(remaining.min(POLL_INTERVAL)).await;
+        }
+        let mut status = workload_rollout_status(client.clone(), namespace, kind, name).await?;
+        if status.state != PROGRESSING {
+            return Ok(status);
+        }
+        if let Some(pod) = restarting_pod(&status, max_restarts) {
+            status.message = format!(
+                "Container {} of pod {} restarted {} times ({})",
+                pod.container.as_deref().unwrap_or_default(), pod.name, pod.restart_count, pod.reason
+            );
+            status.state = FAILED.to_string();
+            return Ok(status);
+        }
+        if started.elapsed() >= timeout {
+            status.state = TIMEOUT.to_string();
+            status.message = format!("Rollout not finished after {} seconds: {}", timeout.as_secs(), status.message);
+            return Ok(status);
+        }
+    }
+}
+
+// Revision of a Deployment or ReplicaSet
+pub fn revision(metadata: &ObjectMeta) -> Option<i64> {
+    metadata.annotations.as_ref()
+        .and_then(|annotations| annotations.get(REVISION_ANNOTATION))
+        .and_then(|revision| revision.parse().ok())
+}
+
+// Image of every container of a pod template, init containers included
+pub fn container_images(template: &PodTemplateSpec) -> BTreeMap<String, String> {
+    template.spec.iter()
+        .flat_map(|spec| spec.init_containers.iter().flatten().chain(spec.containers.iter()))
+        .map(|container| (container.name.clone(), container.image.clone().unwrap_or_default()))
+        .collect()
+}
+
+fn label_selector(deployment: &Deployment) -> String {
+    deployment.spec.as_ref()
+        .and_then(|spec| spec.selector.match_labels.as_ref())
+        .map(|labels| labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(","))
+        .unwrap_or_default()
+}
+
+// ReplicaSets owned by the Deployment, oldest revision first
+pub async fn replica_sets(client: Client, namespace: &str, deployment: &Deployment) -> Result<Vec<ReplicaSet>, String> {
+    let selector = label_selector(deployment);
+    if selector.is_empty() {
+        return Ok(Vec::new());
+    }
+    let replica_sets: Api<ReplicaSet> = Api::namespaced(client, namespace);
+    let list = replica_sets.list(&ListParams::default().labels(&selector)).await
+        .map_err(|e| format!("Could not list replica sets: {}", e))?;
+    let mut owned: Vec<ReplicaSet> = list.items.into_iter()
+        .filter(|rs| rs.metadata.owner_references.iter().flatten().any(|owner| Some(&owner.uid) == deployment.metadata.uid.as_ref()))
+        .collect();
+    owned.sort_by_key(|rs| revision(&rs.metadata));
+    Ok(owned)
+}
+
+// Pods of the current revision that are stuck, restarting or can not be scheduled
+async fn failing_pods(client: Client, namespace: &str, deployment: &Deployment) -> Result<Vec<FailingPod>, String> {
+    let mut selector = label_selector(deployment);
+    if selector.is_empty() {
+        return Ok(Vec::new());
+    }
+    let current_revision = revision(&deployment.metadata);
+    let current_hash = replica_sets(client.clone(), namespace, deployment).await?.into_iter()
+        .find(|rs| current_revision.is_some() && revision(&rs.metadata) == current_revision)
+        .and_then(|rs| rs.metadata.labels.and_then(|labels| labels.get(POD_TEMPLATE_HASH_LABEL).cloned()));
+    // Without the ReplicaSet of the current revision the pods of older ones would be blamed
+    match current_hash {
+        Some(hash) => selector = format!("{},{}={}", selector, POD_TEMPLATE_HASH_LABEL, hash),
+        None => return Ok(Vec::new()),
+    }
+    let pods: Api<Pod> = Api::namespaced(client, namespace);
+    let pods = pods.list(&ListParams::default().labels(&selector)).await
+        .map_err(|e| format!("Could not list pods: {}", e))?;
+    Ok(pods.items.iter().flat_map(pod_failures).collect())
+}
+
+fn container_failure(pod_name: &str, container: &ContainerStatus) -> Option<FailingPod> {
+    let state = container.state.as_ref()?;
+    let (reason, mut message) = if let Some(waiting) = state.waiting.as_ref() {
+        let reason = waiting.reason.clone()?;
+        if STARTING_REASONS.contains(&reason.as_str()) {
+            return None;
+        }
+        (reason, waiting.message.clone())
+    } else if let Some(terminated) = state.terminated.as_ref().filter(|t| t.exit_code != 0) {
+        let reason = terminated.reason.clone().unwrap_or_else(|| format!("Exited with code {}", terminated.exit_code));
+        (reason, terminated.message.clone())
+    } else if container.restart_count > 0 {
+        // Running again, but it already crashed
+        ("Restarted".to_string(), None)
+    } else {
+        return None;
+    };
+    // A crash-looping container only says it is backing off, the previous exit tells why
+    if let Some(last) = container.last_state.as_ref().and_then(|s| s.terminated.as_ref()) {
+        message = Some(format!(
+            "Last exit code {} ({}){}",
+            last.exit_code,
+            last.reason.as_deref().unwrap_or("Unknown"),
+            message.map(|m| format!(": {}", m)).unwrap_or_default()
+        ));
+    }
+    Some(FailingPod {
+        name: pod_name.to_string(),
+        container: Some(container.name.clone()),
+        reason,
+        message,
+        restart_count: container.restart_count,
+    })
+}
+
+fn pod_failures(pod: &Pod) -> Vec<FailingPod> {
+    let name = pod.metadata.name.as_deref().unwrap_or_default();
+    let status = match pod.status.as_ref() {
+        Some(status) => status,
+        None => return Vec::new(),
+    };
+    let mut failures: Vec<FailingPod> = status.init_container_statuses.iter().flatten()
+        .chain(status.container_statuses.iter().flatten())
+        .filter_map(|container| container_failure(name, container))
+        .collect();
+    if failures.is_empty() {
+        let unscheduled = status.conditions.iter().flatten()
+            .find(|c| c.type_ == "PodScheduled" && c.status == "False");
+        if let Some(condition) = unscheduled {
+            failures.push(FailingPod {
+                name: name.to_string(),
+                container: None,
+                reason: condition.reason.clone().unwrap_or_else(|| "Unschedulable".to_string()),
+                message: condition.message.clone(),
+                restart_count: 0,
+            });
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::apps::v1::{StatefulSetSpec, StatefulSetStatus};
+
+    fn status(generation: i64, observed_generation: i64, restart_count: i32) -> RolloutStatus {
+        RolloutStatus {
+            namespace: "prod".to_string(),
+            service_deployment: "web".to_string(),
+            state: PROGRESSING.to_string(),
+            message: String::new(),
+            generation: Some(generation),
+            observed_generation: Some(observed_generation),
+            replicas: 2,
+            updated_replicas: 1,
+            ready_replicas: 1,
+            available_replicas: 1,
+            conditions: Vec::new(),
+            failing_pods: vec![FailingPod {
+                name: "web-abc".to_string(),
+                container: Some("app".to_string()),
+                reason: "Restarted".to_string(),
+                message: None,
+                restart_count,
+            }],
+        }
+    }
+
+    #[test]
+    fn restarts_count_once_the_update_is_observed() {
+        assert_eq!(restarting_pod(&status(3, 3, 4), Some(3)).map(|pod| pod.name), Some("web-abc".to_string()));
+        assert!(restarting_pod(&status(3, 3, 3), Some(3)).is_none());
+        assert!(restarting_pod(&status(3, 3, 4), None).is_none());
+    }
+
+    #[test]
+    fn restarts_before_the_update_is_observed_are_ignored() {
+        assert!(restarting_pod(&status(4, 3, 10), Some(0)).is_none());
+    }
+
+    #[test]
+    fn stateful_set_pods_wait_for_the_update_revision() {
+        let mut stateful_set = StatefulSet {
+            spec: Some(StatefulSetSpec {
+                selector: LabelSelector { match_labels: Some(BTreeMap::from([("app".to_string(), "db".to_string())])), ..Default::default() },
+                ..Default::default()
+            }),
+            status: Some(StatefulSetStatus::default()),
+            ..Default::default()
+        };
+        assert_eq!(stateful_set_progress(&stateful_set).pod_selector, "");
+        stateful_set.status.as_mut().unwrap().update_revision = Some("db-7f9".to_string());
+        assert_eq!(stateful_set_progress(&stateful_set).pod_selector, "app=db,controller-revision-hash=db-7f9");
+    }
+}
@@ -0,0 +1,206 @@
+use std::sync::OnceLock;
+
+use k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscaler;
+use kube::{api::{ListParams, Patch, PatchParams}, Api, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{config::get_scale_bounds, model::kubernetes::WorkloadKind};
+
+// Replicas of the workload before Officer scaled it to zero or pinned its autoscaler
+pub const PREVIOUS_REPLICAS_ANNOTATION: &str = "officer/previous-replicas";
+// Bounds of an autoscaler pinned by Officer, put back on restore
+pub const SUSPENDED_AUTOSCALER_ANNOTATION: &str = "officer/suspended-autoscaler";
+
+/// Replica bounds of a namespace
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct ScaleBounds {
+    pub min: i32,
+    pub max: Option<i32>,
+}
+
+impl ScaleBounds {
+    pub fn check(&self, replicas: i32) -> Result<(), String> {
+        if replicas < self.min {
+            return Err(format!("{} replicas is below the minimum of {}", replicas, self.min));
+        }
+        match self.max {
+            Some(max) if replicas > max => Err(format!("{} replicas is above the maximum of {}", replicas, max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+static SCALE_BOUNDS: OnceLock<Vec<(String, ScaleBounds)>> = OnceLock::new();
+
+// Parse and validate every `SCALE_BOUNDS` entry, must be called once at startup
+pub fn init_scale_bounds() -> Result<(), String> {
+    let bounds = parse_bounds(&get_scale_bounds())?;
+    SCALE_BOUNDS.set(bounds).map_err(|_| "Scale bounds are already initialized".to_string())
+}
+
+// Bounds for `namespace` from `SCALE_BOUNDS`, a namespace without entry and without `*` only needs non-negative replicas
+pub fn scale_bounds(namespace: &str) -> ScaleBounds {
+    bounds_of(SCALE_BOUNDS.get().map(Vec::as_slice).unwrap_or_default(), namespace)
+}
+
+fn parse_bounds(entries: &[String]) -> Result<Vec<(String, ScaleBounds)>, String> {
+    let mut parsed: Vec<(String, ScaleBounds)> = Vec::new();
+    for entry in entries {
+        let invalid = || format!("Invalid SCALE_BOUNDS entry {:?}, expected namespace=min:max", entry);
+        let (scope, range) = entry.split_once('=').ok_or_else(invalid)?;
+        let (min, max) = range.split_once(':').ok_or_else(invalid)?;
+        let scope = scope.trim();
+        let bounds = ScaleBounds {
+            min: min.trim().parse().map_err(|_| invalid())?,
+            max: match max.trim() {
+                "" => None,
+                max => Some(max.parse().map_err(|_| invalid())?),
+            },
+        };
+        if scope.is_empty() {
+            return Err(invalid());
+        }
+        if bounds.min < 0 {
+            return Err(format!("Invalid SCALE_BOUNDS entry {:?}, the minimum can not be negative", entry));
+        }
+        if bounds.max.is_some_and(|max| max < bounds.min) {
+            return Err(format!("Invalid SCALE_BOUNDS entry {:?}, the maximum is below the minimum", entry));
+        }
+        if parsed.iter().any(|(other, _)| other == scope) {
+            return Err(format!("SCALE_BOUNDS has more than one entry for {}", scope));
+        }
+        parsed.push((scope.to_string(), bounds));
+    }
+    Ok(parsed)
+}
+
+fn bounds_of(bounds: &[(String, ScaleBounds)], namespace: &str) -> ScaleBounds {
+    let find = |scope: &str| bounds.iter().find(|(other, _)| other == scope).map(|(_, bounds)| bounds.clone());
+    find(namespace).or_else(|| find("*")).unwrap_or(ScaleBounds { min: 0, max: None })
+}
+
+// Bounds of the autoscaler saved while it is pinned
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AutoscalerBounds {
+    min_replicas: Option<i32>,
+    max_replicas: i32,
+}
+
+// HorizontalPodAutoscaler whose scale target is the workload
+pub async fn find_autoscaler(client: Client, namespace: &str, kind: WorkloadKind, name: &str) -> Result<Option<HorizontalPodAutoscaler>, String> {
+    let autoscalers: Api<HorizontalPodAutoscaler> = Api::namespaced(client, namespace);
+    let list = autoscalers.list(&ListParams::default()).await
+        .map_err(|e| format!("Could not list autoscalers: {}", e))?;
+    Ok(list.items.into_iter().find(|hpa| {
+        hpa.spec.as_ref().is_some_and(|spec| spec.scale_target_ref.kind == kind.as_str() && spec.scale_target_ref.name == name)
+    }))
+}
+
+pub fn is_suspended(autoscaler: &HorizontalPodAutoscaler) -> bool {
+    autoscaler.metadata.annotations.as_ref().is_some_and(|annotations| annotations.contains_key(SUSPENDED_AUTOSCALER_ANNOTATION))
+}
+
+// Pin the autoscaler to `replicas` by setting both bounds, the original bounds are kept in an annotation.
+// An autoscaler already pinned keeps the bounds saved the first time.
+pub async fn suspend_autoscaler(client: Client, namespace: &str, autoscaler: &HorizontalPodAutoscaler, replicas: i32) -> Result<(), String> {
+    let name = autoscaler.metadata.name.as_deref().unwrap_or_default();
+    let mut annotations = serde_json::Map::new();
+    if !is_suspended(autoscaler) {
+        let spec = autoscaler.spec.as_ref().ok_or_else(|| format!("Autoscaler {} has no spec", name))?;
+        let saved = AutoscalerBounds { min_replicas: spec.min_replicas, max_replicas: spec.max_replicas };
+        let saved = serde_json::to_string(&saved).map_err(|e| e.to_string())?;
+        annotations.insert(SUSPENDED_AUTOSCALER_ANNOTATION.to_string(), json!(saved));
+    }
+    let patch = json!({
+        "metadata": {
+            "annotations": annotations
+        },
+        "spec": {
+            "minReplicas": replicas,
+            "maxReplicas": replicas
+        }
+    });
+    let autoscalers: Api<HorizontalPodAutoscaler> = Api::namespaced(client, namespace);
+    autoscalers.patch(name, &PatchParams::apply("scale"), &Patch::Merge(&patch)).await
+        .map_err(|e| format!("Could not suspend autoscaler {}: {}", name, e))?;
+    Ok(())
+}
+
+// Put the saved bounds back on an autoscaler pinned by `suspend_autoscaler`
+pub async fn resume_autoscaler(client: Client, namespace: &str, autoscaler: &HorizontalPodAutoscaler) -> Result<(), String> {
+    let name = autoscaler.metadata.name.as_deref().unwrap_or_default();
+    let saved = autoscaler.metadata.annotations.as_ref()
+        .and_then(|annotations| annotations.get(SUSPENDED_AUTOSCALER_ANNOTATION))
+        .ok_or_else(|| format!("Autoscaler {} is not suspended", name))?;
+    let saved: AutoscalerBounds = serde_json::from_str(saved)
+        .map_err(|e| format!("Saved bounds of autoscaler {} are invalid: {}", name, e))?;
+    let patch = json!({
+        "metadata": {
+            "annotations": {
+                SUSPENDED_AUTOSCALER_ANNOTATION: null
+            }
+        },
+        "spec": {
+            "minReplicas": saved.min_replicas,
+            "maxReplicas": saved.max_replicas
+        }
+    });
+    let autoscalers: Api<HorizontalPodAutoscaler> = Api::namespaced(client, namespace);
+    autoscalers.patch(name, &PatchParams::apply("scale"), &Patch::Merge(&patch)).await
+        .map_err(|e| format!("Could not resume autoscaler {}: {}", name, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(entries: &[&str]) -> Result<Vec<(String, ScaleBounds)>, String> {
+        let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        parse_bounds(&entries)
+    }
+
+    fn bounds(entries: &[&str], namespace: &str) -> ScaleBounds {
+        bounds_of(&parse(entries).unwrap(), namespace)
+    }
+
+    #[test]
+    fn namespace_entry_wins_over_the_wildcard() {
+        let entries = ["*=1:10", "prod=2:", "dev = 0 : 3"];
+        assert_eq!(bounds(&entries, "prod"), ScaleBounds { min: 2, max: None });
+        assert_eq!(bounds(&entries, "dev"), ScaleBounds { min: 0, max: Some(3) });
+        assert_eq!(bounds(&entries, "staging"), ScaleBounds { min: 1, max: Some(10) });
+        // The wildcard also applies when it comes after the namespace entries
+        assert_eq!(bounds(&["prod=2:", "*=1:10"], "staging"), ScaleBounds { min: 1, max: Some(10) });
+    }
+
+    #[test]
+    fn without_entry_only_negative_replicas_are_refused() {
+        assert_eq!(bounds(&[], "prod"), ScaleBounds { min: 0, max: None });
+        assert_eq!(bounds(&["dev=0:3"], "prod"), ScaleBounds { min: 0, max: None });
+    }
+
+    #[test]
+    fn invalid_entries_are_errors() {
+        for entry in ["prod", "prod=2", "prod=a:3", "prod=1:b", "prod=:3", "=1:3", "prod=-1:3", "prod=3:2"] {
+            assert!(parse(&[entry]).is_err(), "{} was accepted", entry);
+        }
+        // Wherever they are in the list
+        assert!(parse(&["prod=1:2", "dev=x:1"]).is_err());
+        assert!(parse(&["prod=1:2", "dev=0:1", "prod=2:3"]).is_err());
+        assert!(parse(&["prod=2:2", "dev=0:"]).is_ok());
+    }
+
+    #[test]
+    fn check_enforces_both_bounds() {
+        let bounds = ScaleBounds { min: 1, max: Some(3) };
+        assert!(bounds.check(0).is_err());
+        assert!(bounds.check(1).is_ok());
+        assert!(bounds.check(3).is_ok());
+        assert!(bounds.check(4).is_err());
+        assert!(ScaleBounds { min: 0, max: None }.check(1000).is_ok());
+    }
+}
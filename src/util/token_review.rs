@@ -0,0 +1,84 @@
+use std::{collections::HashMap, sync::{Mutex, OnceLock}, time::{Duration, Instant}};
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use kube::{api::PostParams, Api, Client};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{get_token_review_audiences, get_token_review_enabled},
+    model::{auth::{AuthMethod, Membership, Principal}, error::AuthError},
+};
+
+// Reviews are cached briefly so a busy caller does not cost an API call per request
+const CACHE_TTL: Duration = Duration::from_secs(30);
+const SERVICE_ACCOUNT_PREFIX: &str = "system:serviceaccount:";
+
+// Token digest to review time and reviewed caller
+type ReviewCache = Mutex<HashMap<Vec<u8>, (Instant, Principal)>>;
+
+fn review_cache() -> &'static ReviewCache {
+    static CACHE: OnceLock<ReviewCache> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Check the TokenReview settings, must be called once at startup. Without audiences the default token of any pod
+// would be accepted
+pub fn init_token_review() -> Result<(), String> {
+    if get_token_review_enabled() && get_token_review_audiences().is_empty() {
+        return Err("TOKEN_REVIEW_AUDIENCES is required when TOKEN_REVIEW_ENABLED is set".to_string());
+    }
+    Ok(())
+}
+
+// Validate a service account token with the Kubernetes API server, it must be issued for one of
+// `TOKEN_REVIEW_AUDIENCES`. A review that can not be made is not a rejection of the token.
+pub async fn review_token(token: &str) -> Result<Principal, AuthError> {
+    let digest = Sha256::digest(token.as_bytes()).to_vec();
+    if let Some((reviewed, principal)) = review_cache().lock().unwrap().get(&digest) {
+        if reviewed.elapsed() < CACHE_TTL {
+            return Ok(principal.clone());
+        }
+    }
+
+    let audiences = get_token_review_audiences();
+    if audiences.is_empty() {
+        return Err(AuthError::Unavailable("TOKEN_REVIEW_AUDIENCES is not set".to_string()));
+    }
+    let client = Client::try_default().await
+        .map_err(|e| AuthError::Unavailable(format!("Kubernetes connection failed: {}", e)))?;
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token.to_string()),
+            audiences: Some(audiences.clone()),
+        },
+        ..Default::default()
+    };
+    let reviews: Api<TokenReview> = Api::all(client);
+    let status = reviews.create(&PostParams::default(), &review).await
+        .map_err(|e| AuthError::Unavailable(format!("TokenReview failed: {}", e)))?
+        .status
+        .ok_or_else(|| AuthError::Unavailable("TokenReview has no status".to_string()))?;
+    if status.authenticated != Some(true) {
+        return Err(AuthError::InvalidToken(status.error.unwrap_or_else(|| "Token is not authenticated".to_string())));
+    }
+    // An API server that ignores the requested audiences would otherwise accept any token
+    if !status.audiences.iter().flatten().any(|audience| audiences.contains(audience)) {
+        return Err(AuthError::InvalidToken("Token is not issued for an accepted audience".to_string()));
+    }
+    let user = status.user.unwrap_or_default();
+    let username = user.username.unwrap_or_default();
+    // Only workloads are expected here, humans log in through an identity provider
+    if !username.starts_with(SERVICE_ACCOUNT_PREFIX) {
+        return Err(AuthError::InvalidToken(format!("{} is not a service account", username)));
+    }
+    let principal = Principal {
+        subject: username,
+        membership: Membership { groups: user.groups.unwrap_or_default(), ..Default::default() },
+        auth_method: AuthMethod::ServiceAccount,
+        scope: None,
+    };
+
+    let mut cache = review_cache().lock().unwrap();
+    cache.retain(|_, (reviewed, _)| reviewed.elapsed() < CACHE_TTL);
+    cache.insert(digest, (Instant::now(), principal.clone()));
+    Ok(principal)
+}
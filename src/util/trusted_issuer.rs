@@ -0,0 +1,126 @@
+use std::{collections::{HashMap, HashSet}, fs, sync::OnceLock};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+use crate::{
+    config::get_trusted_issuers_file,
+    model::{auth::{AuthMethod, Membership, Principal, TrustedIssuerConfig}, error::AuthError},
+    util::{http::get_json, jwks::JwksCache},
+};
+
+/// Issuer from `TRUSTED_ISSUERS_FILE` with its key set
+pub struct TrustedIssuer {
+    config: TrustedIssuerConfig,
+    jwks: OnceCell<JwksCache>,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct UnverifiedClaims {
+    iss: Option<String>,
+}
+
+static ISSUERS: OnceLock<Vec<TrustedIssuer>> = OnceLock::new();
+
+// Load `TRUSTED_ISSUERS_FILE`, must be called once at startup
+pub fn init_trusted_issuers() -> Result<(), String> {
+    let issuers = match get_trusted_issuers_file() {
+        Some(path) => {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read trusted issuers {}: {}", path, e))?;
+            let configs: Vec<TrustedIssuerConfig> = serde_yaml::from_str(&content)
+                .map_err(|e| format!("Invalid trusted issuers: {}", e))?;
+            let mut names = HashSet::new();
+            for config in &configs {
+                if config.audiences.is_empty() {
+                    return Err(format!("Trusted issuer {} needs audiences", config.name));
+                }
+                if config.name.is_empty() || !names.insert(config.name.as_str()) {
+                    return Err(format!("Trusted issuer {} needs a unique, non-empty name", config.issuer));
+                }
+            }
+            configs.into_iter().map(|config| TrustedIssuer { config, jwks: OnceCell::new() }).collect()
+        }
+        None => Vec::new(),
+    };
+    ISSUERS.set(issuers).map_err(|_| "Trusted issuers already initialized".to_string())
+}
+
+// Whether the token is not one of ours and has to be checked against a trusted issuer
+pub fn is_external_token(token: &str) -> bool {
+    decode_header(token).is_ok_and(|header| header.alg != Algorithm::HS256)
+}
+
+// Read `iss` before the signature is checked, only used to pick the key set
+fn unverified_issuer(token: &str) -> Option<String> {
+    let header = decode_header(token).ok()?;
+    let mut validation = Validation::new(header.alg);
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    validation.validate_aud = false;
+    decode::<UnverifiedClaims>(token, &DecodingKey::from_secret(&[]), &validation).ok()?.claims.iss
+}
+
+// Whether the (unverified) issuer of the token is in `TRUSTED_ISSUERS_FILE`
+pub fn is_trusted_issuer(token: &str) -> bool {
+    unverified_issuer(token).is_some_and(|iss| {
+        ISSUERS.get().is_some_and(|issuers| issuers.iter().any(|i| i.config.issuer == iss))
+    })
+}
+
+fn claim_values(claims: &HashMap<String, Value>, name: &str) -> Vec<String> {
+    match claims.get(name) {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl TrustedIssuer {
+    async fn jwks(&self) -> Result<&JwksCache, String> {
+        self.jwks.get_or_try_init(|| async {
+            let uri = match &self.config.jwks_uri {
+                Some(uri) => uri.clone(),
+                None => {
+                    let discovery_url = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+                    get_json::<DiscoveryDocument>(&discovery_url).await?.jwks_uri
+                }
+            };
+            Ok(JwksCache::new(uri))
+        }).await
+    }
+}
+
+// Validate a token signed by a trusted issuer and build the caller from its claims
+pub async fn validate_external_token(token: &str) -> Result<Principal, AuthError> {
+    let iss = unverified_issuer(token).ok_or_else(|| AuthError::InvalidToken("Token has no issuer".to_string()))?;
+    let issuer = ISSUERS.get()
+        .and_then(|issuers| issuers.iter().find(|i| i.config.issuer == iss))
+        .ok_or_else(|| AuthError::InvalidToken(format!("Issuer {} is not trusted", iss)))?;
+    let claims = issuer.jwks().await
+        .map_err(|e| AuthError::Unavailable(format!("Could not discover the signing keys of {}: {}", iss, e)))?
+        .verify::<HashMap<String, Value>>(token, &issuer.config.issuer, &issuer.config.audiences).await?
+        .claims;
+    let name = &issuer.config.name;
+    let subject = claims.get(&issuer.config.subject_claim)
+        .and_then(Value::as_str)
+        .map(|subject| format!("{}:{}", name, subject))
+        .ok_or_else(|| AuthError::InvalidToken(format!("Token has no {} claim", issuer.config.subject_claim)))?;
+    let groups = issuer.config.groups_claims.iter()
+        .flat_map(|claim| claim_values(&claims, claim))
+        .map(|group| format!("{}:{}", name, group))
+        .collect();
+    Ok(Principal {
+        subject,
+        membership: Membership { groups, ..Default::default() },
+        auth_method: AuthMethod::ExternalJwt,
+        scope: None,
+    })
+}
@@ -0,0 +1,69 @@
+use k8s_openapi::api::{apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet}, core::v1::{Pod, PodTemplateSpec}};
+use kube::{api::{ApiResource, DynamicObject, GroupVersionKind}, Api, Client};
+
+use crate::model::kubernetes::WorkloadKind;
+
+/// Api of one workload kind, CRDs like Argo Rollouts have no typed API so every kind goes through `DynamicObject`
+pub struct WorkloadApi {
+    pub kind: WorkloadKind,
+    pub resource: ApiResource,
+    pub api: Api<DynamicObject>,
+}
+
+impl WorkloadApi {
+    pub fn new(client: Client, namespace: &str, kind: WorkloadKind) -> Self {
+        let resource = match kind {
+            WorkloadKind::Deployment => ApiResource::erase::<Deployment>(&()),
+            WorkloadKind::StatefulSet => ApiResource::erase::<StatefulSet>(&()),
+            WorkloadKind::DaemonSet => ApiResource::erase::<DaemonSet>(&()),
+            WorkloadKind::Rollout => {
+                ApiResource::from_gvk_with_plural(&GroupVersionKind::gvk("argoproj.io", "v1alpha1", "Rollout"), "rollouts")
+            }
+        };
+        let api = Api::namespaced_with(client, namespace, &resource);
+        WorkloadApi { kind, resource, api }
+    }
+
+    // Built-in kinds accept strategic merge patches, CRDs only JSON merge patches
+    pub fn strategic_merge(&self) -> bool {
+        self.kind != WorkloadKind::Rollout
+    }
+}
+
+// Pod template of a workload, every supported kind keeps it in `spec.template`.
+// A Rollout referencing a Deployment through `workloadRef` has none.
+pub fn pod_template(workload: &DynamicObject) -> Result<PodTemplateSpec, String> {
+    let name = workload.metadata.name.as_deref().unwrap_or_default();
+    let template = workload.data.get("spec")
+        .and_then(|spec| spec.get("template"))
+        .ok_or_else(|| format!("Workload {} has no pod template", name))?;
+    serde_json::from_value(template.clone())
+        .map_err(|e| format!("Pod template of {} is invalid: {}", name, e))
+}
+
+// Workload controlling a pod, a Deployment or Rollout is found through the ReplicaSet between them
+pub async fn pod_owner(client: Client, namespace: &str, pod: &Pod) -> Result<Option<(WorkloadKind, String)>, String> {
+    let owner = match pod.metadata.owner_references.iter().flatten().find(|owner| owner.controller == Some(true)) {
+        Some(owner) => owner,
+        None => return Ok(None),
+    };
+    match owner.kind.as_str() {
+        "StatefulSet" => Ok(Some((WorkloadKind::StatefulSet, owner.name.clone()))),
+        "DaemonSet" => Ok(Some((WorkloadKind::DaemonSet, owner.name.clone()))),
+        "ReplicaSet" => {
+            let replica_sets: Api<ReplicaSet> = Api::namespaced(client, namespace);
+            let replica_set = replica_sets.get(&owner.name).await
+                .map_err(|e| format!("Get ReplicaSet {} failed: {}", owner.name, e))?;
+            // Argo Rollouts manage ReplicaSets like Deployments do
+            let workload = replica_set.metadata.owner_references.iter().flatten()
+                .find(|owner| owner.controller == Some(true))
+                .and_then(|owner| match owner.kind.as_str() {
+                    "Deployment" => Some((WorkloadKind::Deployment, owner.name.clone())),
+                    "Rollout" => Some((WorkloadKind::Rollout, owner.name.clone())),
+                    _ => None,
+                });
+            Ok(workload)
+        }
+        _ => Ok(None),
+    }
+}